@@ -1,33 +1,14 @@
 use clap::{App, Arg};
 use desmos_lang::{
-    compiler::{
-        compiler::{compile_stmt, Context},
-        error::CompileError,
-    },
+    compiler::compiler::{compile_stmt, Context},
     core::latex::latex_to_str,
-    parser::parser::{parse, ParseError},
+    parser::parser::parse,
+    source::EvalError,
 };
 use std::fs::File;
 use std::io::prelude::*;
 
-#[derive(Debug)]
-pub enum EvalError<'a> {
-    ParseError(ParseError),
-    CompileError(CompileError<'a>),
-}
-
-impl From<ParseError> for EvalError<'_> {
-    fn from(err: ParseError) -> Self {
-        Self::ParseError(err)
-    }
-}
-
-impl<'a> From<CompileError<'a>> for EvalError<'a> {
-    fn from(err: CompileError<'a>) -> Self {
-        Self::CompileError(err)
-    }
-}
-
+#[allow(clippy::result_large_err)]
 fn try_eval(inp: &str, debug: bool) -> Result<String, EvalError<'_>> {
     let ast = parse(inp)?;
     if debug {
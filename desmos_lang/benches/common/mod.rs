@@ -0,0 +1,16 @@
+// Shared by the benches. This is a small timing loop instead of a bench harness so it
+//  works on stable without extra dependencies.
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 100;
+
+pub fn bench<F: FnMut()>(name: &str, mut f: F) {
+    // Warm up once so the first iteration doesn't skew the average
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let avg: Duration = start.elapsed() / ITERATIONS;
+    println!("{:<24} {:>12?} / iter", name, avg);
+}
@@ -0,0 +1,29 @@
+// Run with `cargo bench -p desmos_lang`
+mod common;
+
+use common::bench;
+use desmos_lang::{
+    compiler::compiler::{compile_expr, Context},
+    core::ast::{CallModifier, Expression},
+};
+use pest::Span;
+use std::hint::black_box;
+
+fn main() {
+    let spn = Span::new("", 0, 0).unwrap();
+    let call = (
+        spn.clone(),
+        Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func: "sin",
+            args: vec![(spn.clone(), Expression::Num("1"))],
+        },
+    );
+    // [sin(1), sin(1), ...]
+    let expr = (spn, Expression::List(vec![call; 10_000]));
+
+    bench("compile_sin_calls_10k", || {
+        let mut ctx = Context::new();
+        black_box(compile_expr(&mut ctx, expr.clone()).unwrap());
+    });
+}
@@ -0,0 +1,14 @@
+// Run with `cargo bench -p desmos_lang`
+mod common;
+
+use common::bench;
+use desmos_lang::core::latex::Latex;
+use std::hint::black_box;
+
+fn main() {
+    let list = Latex::List((0..10_000).map(|i| Latex::Num(i.to_string())).collect());
+
+    bench("emit_list_10k", || {
+        black_box(black_box(&list).to_string());
+    });
+}
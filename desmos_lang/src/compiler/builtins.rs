@@ -1,6 +1,6 @@
 use crate::core::runtime::{
     Function,
-    ValType::{List, Number as Num},
+    ValType::{List, Number as Num, PointList, Polygon},
 };
 use phf::{phf_map, Map};
 
@@ -9,6 +9,28 @@ macro_rules! f {
         Function {
             args: $args,
             ret: $ret,
+            deprecated: None,
+            latex_command: false,
+        }
+    };
+}
+
+// Still compiled as usual, but using it warns and suggests the replacement
+macro_rules! deprecated {
+    ($replacement:expr, $func:expr) => {
+        Function {
+            deprecated: Some($replacement),
+            ..$func
+        }
+    };
+}
+
+// Emitted as a latex command, \sin rather than \operatorname{sin}
+macro_rules! command {
+    ($func:expr) => {
+        Function {
+            latex_command: true,
+            ..$func
         }
     };
 }
@@ -41,35 +63,36 @@ macro_rules! nn {
 // Source: https://support.desmos.com/hc/en-us/articles/212235786-Supported-Functions
 pub static BUILTIN_FUNCTIONS: Map<&'static str, Function> = phf_map! {
     // Trigonometry
-    "sin" => n!(),
-    "cos" => n!(),
-    "tan" => n!(),
-    "csc" => n!(),
-    "sec" => n!(),
-    "cot" => n!(),
-
-    "arcsin" => n!(),
-    "arccos" => n!(),
-    "arctan" => n!(),
+    "sin" => command!(n!()),
+    "cos" => command!(n!()),
+    "tan" => command!(n!()),
+    "csc" => command!(n!()),
+    "sec" => command!(n!()),
+    "cot" => command!(n!()),
+
+    "arcsin" => command!(n!()),
+    "arccos" => command!(n!()),
+    "arctan" => command!(n!()),
     "arccsc" => n!(),
     "arcsec" => n!(),
     "arccot" => n!(),
 
-    "sinh" => n!(),
-    "cosh" => n!(),
-    "tanh" => n!(),
+    "sinh" => command!(n!()),
+    "cosh" => command!(n!()),
+    "tanh" => command!(n!()),
     "csch" => n!(),
     "sech" => n!(),
-    "coth" => n!(),
+    "coth" => command!(n!()),
 
     // Statistics
     "total" => l!(),
-    "min" => l!(),
-    "max" => l!(),
+    "min" => command!(l!()),
+    "max" => command!(l!()),
     "length" => l!(),
     "mean" => l!(),
     "median" => l!(),
     "stdev" => l!(),
+    "stddev" => deprecated!("stdev", l!()),
     "stdevp" => l!(),
     "mad" => l!(),
     "var" => l!(),
@@ -83,33 +106,57 @@ pub static BUILTIN_FUNCTIONS: Map<&'static str, Function> = phf_map! {
     "nPr" => nn!(),
 
     // Miscellaneous
-    "join" => ll!(),
+    "join" => f!(&[List, List], List),
 
-    "sort" => l!(),
-    "shuffle" => l!(),
+    "sort" => f!(&[List], List),
+    "shuffle" => f!(&[List], List),
 
     // TODO: Support variadic functions
     // "lcm"
     // "gcd"
 
     "mod" => nn!(),
+    // Floored division, the compiler emits floor(a/b) instead of a call
+    "div" => nn!(),
+    // repeat(value, count) is a list of count copies of value
+    "repeat" => f!(&[Num, Num], List),
+
+    // Desmos also accepts the points as separate arguments, but that needs variadic
+    //  functions
+    "polygon" => f!(&[PointList], Polygon),
 
     "floor" => n!(),
     "abs" => n!(),
     "sign" => n!(), // returns 1, -1, or 0 based on sign
-    "exp" => n!(), // e^x
-    "ln" => n!(),
-    "log" => n!(),
+    "exp" => command!(n!()), // e^x
+    "ln" => command!(n!()),
+    "log" => command!(n!()),
 
     // TODO: Support log_{a} where a is an arbitrary integer
 
     // TODO: Support for sqrt and nthroot
     // TODO: Support integral
-    // TODO: Support sum
-    // TODO: Support prod
+    // TODO: Support prod like sum. Latex::Product is only emitted for double factorials.
 
     // TODO: Support for optional arguments.
     // Round takes either one or two arguments
     "round" => n!(),
 
 };
+
+// Other names for builtins, mapped to the name in BUILTIN_FUNCTIONS. Calls through an
+//  alias are emitted with the canonical name.
+pub static BUILTIN_ALIASES: Map<&'static str, &'static str> = phf_map! {
+    "asin" => "arcsin",
+    "acos" => "arccos",
+    "atan" => "arctan",
+    "acsc" => "arccsc",
+    "asec" => "arcsec",
+    "acot" => "arccot",
+    "absoluteValue" => "abs",
+};
+
+// The name in BUILTIN_FUNCTIONS for name, which is name itself unless it's an alias
+pub fn canonical_name(name: &str) -> &str {
+    BUILTIN_ALIASES.get(name).copied().unwrap_or(name)
+}
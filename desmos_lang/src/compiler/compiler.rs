@@ -1,42 +1,267 @@
 use super::{
     builtins,
-    error::{CompileError, CompileErrorKind},
+    error::{CompileError, CompileErrorKind, CompileWarning, CompileWarningKind},
+    fold::{fold_binary, literal_value},
+    lint::{literal_int, node_count},
+    transform::{free_variables, fresh_variable, substitute},
 };
 use crate::core::{
     ast::{
         BinaryOperator, Branch, CallModifier, Expression, LocatedExpression, LocatedStatement,
-        Statement, UnaryOperator,
+        LogicalOperator, Statement, UnaryOperator,
     },
     latex::{
-        BinaryOperator as LatexBinaryOperator, Cond, Latex, UnaryOperator as LatexUnaryOperator,
+        is_atomic, latex_to_str, BinaryOperator as LatexBinaryOperator, CallKind, CompareOperator,
+        Cond, Latex, UnaryOperator as LatexUnaryOperator,
     },
     runtime::ValType,
 };
 use pest::Span;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 pub struct FunctionSignature {
     pub args: Vec<ValType>,
     pub ret: ValType,
+    // Set for deprecated builtins to the name of their replacement
+    pub deprecated: Option<&'static str>,
+}
+
+// A function defined in the program. The compiled body is kept so that later passes like
+//  inlining can use it without compiling the definition again.
+pub struct UserFunction {
+    pub signature: Rc<FunctionSignature>,
+    pub params: Vec<String>,
+    pub body: Latex,
+    // Compiled defaults of the trailing params, filled in when a call leaves them out
+    pub defaults: Vec<Latex>,
+    // Declared with inline, so it is only ever inlined and isn't part of the output
+    pub inline: bool,
 }
 
 pub struct Context<'a> {
     pub variables: HashMap<&'a str, ValType>,
     pub locals: HashMap<&'a str, ValType>,
-    pub defined_functions: HashMap<&'a str, Rc<FunctionSignature>>,
+    pub defined_functions: HashMap<&'a str, Rc<UserFunction>>,
     pub inside_map_macro: bool,
+    // Compiled values of the let bindings in scope, which are substituted wherever
+    //  they are used since desmos has no local variables. Their types are in locals.
+    pub let_bindings: HashMap<&'a str, Latex>,
+    // Compile calls to unknown functions as \operatorname{f}(...) instead of erroring,
+    //  assuming that they take and return numbers
+    pub allow_unknown_functions: bool,
+    // When set, only the builtins named here (by their canonical names) can be called.
+    //  The others are unknown functions, even with allow_unknown_functions.
+    pub allowed_builtins: Option<HashSet<&'a str>>,
+    // Treat undefined variables as numbers instead of erroring, like desmos does by
+    //  creating a slider for them. Their names are collected in implicit_sliders.
+    pub implicit_variables: bool,
+    pub implicit_sliders: HashSet<&'a str>,
+    // Warn when a parameter or local is named after one of RESERVED_IDENTIFIERS
+    pub warn_reserved_identifiers: bool,
+    // Reject names longer than this, since desmos writes every letter after the first
+    //  as a subscript
+    pub max_identifier_length: Option<usize>,
+    // Evaluate arithmetic on number literals at compile time, so 2*3 is emitted as 6
+    pub fold_constants: bool,
+    // Warn about likely mistakes with literals, like 2.5! or sin(1e20)
+    pub beginner_lints: bool,
+    // Replace calls to user functions whose bodies have at most this many nodes with the
+    //  body, substituting the arguments for the parameters
+    pub inline_limit: Option<usize>,
+    // Non-fatal problems found while compiling, in the order they were found
+    pub warnings: Vec<CompileWarning<'a>>,
+    // Names with a fixed value, and the latex that is emitted for them. Variables and
+    //  locals with the same name take priority.
+    pub constants: HashMap<&'a str, (ValType, Latex)>,
+    // Consulted for names that aren't defined in the context itself, before builtins and
+    //  constants. Lets a host like a language server resolve names from its own tables.
+    pub env: Option<&'a dyn TypeEnv>,
+    // When set, every compiled expression is pushed here along with its span, children
+    //  before their parents. source_map::build matches them up with the output.
+    pub source_spans: Option<Vec<(Span<'a>, Latex)>>,
+    // Errors that were recovered from, while compile_expr_all_errors is collecting them
+    collected_errors: Option<Vec<CompileError<'a>>>,
+}
+
+// Read-only lookup of the types of names the compiler doesn't know about. Functions found
+//  here are treated as user functions.
+pub trait TypeEnv {
+    fn variable_type(&self, name: &str) -> Option<ValType>;
+    fn function_signature(&self, name: &str) -> Option<Rc<FunctionSignature>>;
+}
+
+impl TypeEnv for Context<'_> {
+    fn variable_type(&self, name: &str) -> Option<ValType> {
+        resolve_variable(self, name)
+    }
+
+    fn function_signature(&self, name: &str) -> Option<Rc<FunctionSignature>> {
+        resolve_function(self, name).map(|(f, _)| f)
+    }
 }
 
-impl Context<'_> {
+impl<'a> Context<'a> {
     pub fn new() -> Self {
         Self {
             variables: HashMap::new(),
             locals: HashMap::new(),
             defined_functions: HashMap::new(),
             inside_map_macro: false,
+            let_bindings: HashMap::new(),
+            allow_unknown_functions: false,
+            allowed_builtins: None,
+            implicit_variables: false,
+            implicit_sliders: HashSet::new(),
+            warn_reserved_identifiers: false,
+            max_identifier_length: None,
+            fold_constants: false,
+            beginner_lints: false,
+            inline_limit: None,
+            warnings: Vec::new(),
+            constants: DEFAULT_CONSTANTS
+                .iter()
+                .map(|(name, latex)| (*name, (ValType::Number, Latex::Constant(latex.to_string()))))
+                .collect(),
+            env: None,
+            source_spans: None,
+            collected_errors: None,
+        }
+    }
+
+    // Clears per-compilation state so the context can be reused. Pass
+    //  keep_definitions to hold on to user functions and variables across the reset.
+    pub fn reset(&mut self, keep_definitions: bool) {
+        self.locals.clear();
+        self.let_bindings.clear();
+        self.warnings.clear();
+        self.inside_map_macro = false;
+        if !keep_definitions {
+            self.defined_functions.clear();
+            self.variables.clear();
+            self.implicit_sliders.clear();
+        }
+    }
+
+    // Passes r through, unless errors are being collected, in which case the error is
+    //  recorded and compilation carries on as if the types had matched
+    fn recover(&mut self, r: Result<(), CompileError<'a>>) -> Result<(), CompileError<'a>> {
+        match (r, &mut self.collected_errors) {
+            (Err(e), Some(errors)) => {
+                errors.push(e);
+                Ok(())
+            }
+            (r, _) => r,
         }
     }
+
+    // Saves everything that compiling can change, so an embedder can try a statement
+    //  and restore the snapshot if it fails. Signatures are behind an Rc, so this is
+    //  just a copy of the maps. Options like allow_unknown_functions aren't included.
+    pub fn snapshot(&self) -> ContextSnapshot<'a> {
+        ContextSnapshot {
+            variables: self.variables.clone(),
+            locals: self.locals.clone(),
+            defined_functions: self.defined_functions.clone(),
+            inside_map_macro: self.inside_map_macro,
+            let_bindings: self.let_bindings.clone(),
+            implicit_sliders: self.implicit_sliders.clone(),
+            warnings: self.warnings.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: ContextSnapshot<'a>) {
+        self.variables = snapshot.variables;
+        self.locals = snapshot.locals;
+        self.defined_functions = snapshot.defined_functions;
+        self.inside_map_macro = snapshot.inside_map_macro;
+        self.let_bindings = snapshot.let_bindings;
+        self.implicit_sliders = snapshot.implicit_sliders;
+        self.warnings = snapshot.warnings;
+    }
+
+    // Copies the user functions and variables of other into this context, so a prelude
+    //  can be compiled once and shared between snippets. A name counts as conflicting
+    //  when this context already defines it as either one. With ImportConflict::Error
+    //  nothing is imported and the first conflicting name is returned.
+    pub fn import(
+        &mut self,
+        other: &Context<'a>,
+        on_conflict: ImportConflict,
+    ) -> Result<(), &'a str> {
+        let defines = |ctx: &Self, name: &str| {
+            ctx.defined_functions.contains_key(name) || ctx.variables.contains_key(name)
+        };
+        if on_conflict == ImportConflict::Error {
+            if let Some(name) = other
+                .defined_functions
+                .keys()
+                .chain(other.variables.keys())
+                .find(|name| defines(self, name))
+                .copied()
+            {
+                return Err(name);
+            }
+        }
+
+        for (name, f) in &other.defined_functions {
+            if on_conflict == ImportConflict::KeepExisting && defines(self, name) {
+                continue;
+            }
+            self.variables.remove(name);
+            self.defined_functions.insert(name, Rc::clone(f));
+        }
+        for (name, t) in &other.variables {
+            if on_conflict == ImportConflict::KeepExisting && defines(self, name) {
+                continue;
+            }
+            self.defined_functions.remove(name);
+            self.variables.insert(name, *t);
+        }
+        Ok(())
+    }
+
+    // Definitions like a=1 for the implicit sliders, so the compiled graph doesn't rely on
+    //  desmos offering to add them. Reserved names are left out, since defining x graphs a
+    //  line instead of making a slider. Sorted so the output is stable.
+    pub fn slider_definitions(&self) -> Vec<Latex> {
+        let mut names: Vec<&str> = self
+            .implicit_sliders
+            .iter()
+            .copied()
+            .filter(|name| !RESERVED_IDENTIFIERS.contains(name))
+            .collect();
+        names.sort_unstable();
+        names
+            .into_iter()
+            .map(|name| {
+                Latex::Assignment(
+                    Box::new(Latex::Variable(name.to_string())),
+                    Box::new(Latex::Num(DEFAULT_SLIDER_VALUE.to_string())),
+                )
+            })
+            .collect()
+    }
+}
+
+// What Context::import does with a name that is defined in both contexts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportConflict {
+    KeepExisting,
+    // Replace the existing definition, like defining the name again would
+    Overwrite,
+    Error,
+}
+
+// The compilation state of a Context at some point, from Context::snapshot
+pub struct ContextSnapshot<'a> {
+    variables: HashMap<&'a str, ValType>,
+    locals: HashMap<&'a str, ValType>,
+    defined_functions: HashMap<&'a str, Rc<UserFunction>>,
+    inside_map_macro: bool,
+    let_bindings: HashMap<&'a str, Latex>,
+    implicit_sliders: HashSet<&'a str>,
+    warnings: Vec<CompileWarning<'a>>,
 }
 
 impl Default for Context<'_> {
@@ -45,92 +270,390 @@ impl Default for Context<'_> {
     }
 }
 
+thread_local! {
+    // Builtin signatures are wrapped in an Rc once (per thread, since Rc isn't Sync) so
+    //  resolving a builtin is just a refcount bump instead of a fresh allocation.
+    static BUILTIN_SIGNATURES: HashMap<&'static str, Rc<FunctionSignature>> =
+        builtins::BUILTIN_FUNCTIONS
+            .entries()
+            .map(|(name, f)| {
+                (
+                    *name,
+                    Rc::new(FunctionSignature {
+                        args: f.args.to_vec(),
+                        ret: f.ret,
+                        deprecated: f.deprecated,
+                    }),
+                )
+            })
+            .collect();
+}
+
+// The constants every context starts out with, and the latex desmos uses for them
+pub const DEFAULT_CONSTANTS: &[(&str, &str)] = &[("pi", "\\pi"), ("tau", "\\tau"), ("e", "e")];
+
+const TRIG_FUNCTIONS: &[&str] = &["sin", "cos", "tan", "csc", "sec", "cot"];
+// Past this, consecutive doubles are more than a period apart, so the result of a trig
+//  function has nothing to do with the number that was written
+const LARGE_TRIG_ARGUMENT: f64 = 1e17;
+
+// What desmos sets a slider to when it adds one
+const DEFAULT_SLIDER_VALUE: &str = "1";
+
+// Names that desmos gives a meaning of their own, like x and y for the coordinates of
+//  an implicit equation. theta isn't here since it is emitted as t_{heta}, not \theta.
+pub const RESERVED_IDENTIFIERS: &[&str] = &["x", "y", "r"];
+
+// Checks a name that the program defines, where span is the definition. Records a
+//  warning if the name is reserved and errors if it is too long, when those checks are on.
+fn check_name<'a>(
+    ctx: &mut Context<'a>,
+    name: &'a str,
+    span: &Span<'a>,
+) -> Result<(), CompileError<'a>> {
+    if matches!(ctx.max_identifier_length, Some(max) if name.len() > max) {
+        return Err(CompileError {
+            kind: CompileErrorKind::InvalidIdentifier(name),
+            span: span.clone(),
+        });
+    }
+    if ctx.warn_reserved_identifiers && RESERVED_IDENTIFIERS.contains(&name) {
+        ctx.warnings.push(CompileWarning {
+            kind: CompileWarningKind::ReservedIdentifier(name),
+            span: span.clone(),
+        });
+    }
+    Ok(())
+}
+
+// Names that users might expect to work, but that can't be compiled to desmos. They can
+//  still be defined, this only applies when nothing else gives them a meaning.
+pub const UNSUPPORTED_NAMES: &[&str] = &["i", "infinity"];
+
 // Returns function and whether it is builtin
-pub fn resolve_function<'a>(
-    ctx: &'a mut Context,
-    func: &str,
-) -> Option<(Rc<FunctionSignature>, bool)> {
+pub fn resolve_function(ctx: &Context, func: &str) -> Option<(Rc<FunctionSignature>, bool)> {
     match ctx.defined_functions.get(func) {
-        None => match builtins::BUILTIN_FUNCTIONS.get(func) {
-            None => None,
-            Some(f) => Some((
-                Rc::new(FunctionSignature {
-                    args: f.args.to_vec(),
-                    ret: f.ret,
-                }),
-                true,
-            )),
+        Some(f) => Some((f.signature.clone(), false)),
+        None => match ctx.env.and_then(|env| env.function_signature(func)) {
+            Some(f) => Some((f, false)),
+            None if !builtin_allowed(ctx, func) => None,
+            None => BUILTIN_SIGNATURES.with(|b| {
+                b.get(builtins::canonical_name(func))
+                    .map(|f| (f.clone(), true))
+            }),
         },
-        Some(f) => Some((f.clone(), false)),
     }
 }
 
-pub fn resolve_variable<'a>(ctx: &'a mut Context, var: &str) -> Option<&'a ValType> {
-    match ctx.variables.get(var) {
-        Some(r) => Some(r),
-        None => ctx.locals.get(var),
+// Whether func may be called if it is a builtin, according to ctx.allowed_builtins
+fn builtin_allowed(ctx: &Context, func: &str) -> bool {
+    match &ctx.allowed_builtins {
+        Some(allowed) => allowed.contains(builtins::canonical_name(func)),
+        None => true,
     }
 }
 
+pub fn resolve_variable(ctx: &Context, var: &str) -> Option<ValType> {
+    ctx.variables
+        .get(var)
+        .or_else(|| ctx.locals.get(var))
+        .copied()
+        .or_else(|| ctx.env.and_then(|env| env.variable_type(var)))
+        .or_else(|| ctx.constants.get(var).map(|(t, _)| *t))
+}
+
+// Whether var refers to one of ctx.constants, rather than a variable that shadows it
+fn is_constant(ctx: &Context, var: &str) -> bool {
+    ctx.constants.contains_key(var)
+        && !ctx.variables.contains_key(var)
+        && !ctx.locals.contains_key(var)
+        && ctx.env.and_then(|env| env.variable_type(var)).is_none()
+}
+
 pub fn compile_call<'a>(
-    ctx: &mut Context,
+    ctx: &mut Context<'a>,
     span: Span<'a>,
     fname: &'a str,
     args: Vec<(Span<'a>, Latex, ValType)>,
 ) -> Result<(Latex, ValType), CompileError<'a>> {
-    match resolve_function(ctx, fname) {
-        None => Err(CompileError {
-            kind: CompileErrorKind::UnknownFunction(fname),
+    let (func, kind) = match resolve_function(ctx, fname) {
+        Some((func, true)) => (func, CallKind::Builtin),
+        Some((func, false)) => (func, CallKind::User),
+        // Desmos would read a(1) as a times 1, but that is more likely to be a mistake
+        None if resolve_variable(ctx, fname).is_some() => {
+            return Err(CompileError {
+                kind: CompileErrorKind::NotAFunction(fname),
+                span,
+            })
+        }
+        // Assume that functions we don't know about take and return numbers. Builtins
+        //  that aren't allowed are left out, since desmos would still evaluate them.
+        None if ctx.allow_unknown_functions
+            && !builtins::BUILTIN_FUNCTIONS.contains_key(builtins::canonical_name(fname)) =>
+        {
+            (
+                Rc::new(FunctionSignature {
+                    args: vec![ValType::Number; args.len()],
+                    ret: ValType::Number,
+                    deprecated: None,
+                }),
+                CallKind::OperatorName,
+            )
+        }
+        None => {
+            return Err(CompileError {
+                kind: CompileErrorKind::UnknownFunction(fname),
+                span,
+            })
+        }
+    };
+
+    if let Some(replacement) = func.deprecated {
+        ctx.warnings.push(CompileWarning {
+            kind: CompileWarningKind::DeprecatedFunction {
+                name: fname,
+                replacement,
+            },
+            span: span.clone(),
+        });
+    }
+
+    // Warnings use the name as written, but aliases are emitted as the builtin they stand for
+    let emitted_name = match kind {
+        CallKind::Builtin => builtins::canonical_name(fname),
+        _ => fname,
+    };
+
+    if ctx.beginner_lints && kind == CallKind::Builtin && TRIG_FUNCTIONS.contains(&emitted_name) {
+        for (aspan, arg, _) in &args {
+            if matches!(literal_value(arg), Some(v) if v.abs() > LARGE_TRIG_ARGUMENT) {
+                ctx.warnings.push(CompileWarning {
+                    kind: CompileWarningKind::LargeTrigArgument(fname),
+                    span: aspan.clone(),
+                });
+            }
+        }
+    }
+
+    // Fill in trailing args that were left out from the defaults, if there are enough.
+    //  They were compiled where the function was defined, so a global they use can't be
+    //  shadowed here.
+    let mut args = args;
+    if let (CallKind::User, Some(f)) = (kind, ctx.defined_functions.get(fname)) {
+        let missing = func.args.len().saturating_sub(args.len());
+        if missing <= f.defaults.len() {
+            let types = &func.args[func.args.len() - missing..];
+            for (d, t) in f.defaults[f.defaults.len() - missing..].iter().zip(types) {
+                if let Some(var) = captured_variable(ctx, d, &[]) {
+                    return Err(CompileError {
+                        kind: CompileErrorKind::CapturedVariable(var),
+                        span,
+                    });
+                }
+                args.push((span.clone(), d.clone(), *t));
+            }
+        }
+    }
+
+    // Validate arg count
+    let got = args.len();
+    let expect = func.args.len();
+
+    if got != expect {
+        return Err(CompileError {
+            kind: CompileErrorKind::WrongArgCount {
+                got,
+                expected: expect,
+            },
             span,
-        }),
-        Some((func, is_builtin)) => {
-            // Validate arg count
-            let got = args.len();
-            let expect = func.args.len();
-
-            if got != expect {
-                Err(CompileError {
-                    kind: CompileErrorKind::WrongArgCount {
-                        got,
-                        expected: expect,
-                    },
-                    span,
-                })
-            } else {
-                let mut aiter = args.into_iter();
-                let args_latex = func
-                    .args
-                    .iter()
-                    .map(|expect_type| -> Result<Latex, _> {
-                        // Already checked that they are the same length, so unwrap is safe
-                        let (aspan, arg_latex, got_type) = aiter.next().unwrap();
-                        let type_errors_ok = ctx.inside_map_macro
-                            && got_type == ValType::List
-                            && *expect_type == ValType::Number;
-                        if !type_errors_ok && got_type != *expect_type {
-                            return Err(CompileError {
-                                kind: CompileErrorKind::TypeMismatch {
-                                    got: got_type,
-                                    expected: *expect_type,
-                                },
-                                span: aspan,
-                            });
-                        }
-                        Ok(arg_latex)
-                    })
-                    .collect::<Result<Vec<Latex>, _>>()?;
+        });
+    }
 
-                Ok((
-                    Latex::Call {
-                        func: fname.to_string(),
-                        is_builtin,
-                        args: args_latex,
+    // Whether any list was passed where a number is expected, making this a map over it
+    let mut broadcast = false;
+    let mut aiter = args.into_iter();
+    let args_latex = func
+        .args
+        .iter()
+        .map(|expect_type| -> Result<Latex, _> {
+            // Already checked that they are the same length, so unwrap is safe
+            let (aspan, arg_latex, got_type) = aiter.next().unwrap();
+            let type_errors_ok = ctx.inside_map_macro
+                && got_type == ValType::List
+                && *expect_type == ValType::Number;
+            // Broadcasting a function that returns a list would produce a
+            //  list of lists, which desmos doesn't support
+            if type_errors_ok && func.ret == ValType::List {
+                return Err(CompileError {
+                    kind: CompileErrorKind::NoNestedList,
+                    span: aspan,
+                });
+            }
+            if !type_errors_ok
+                && got_type != *expect_type
+                && !empty_list_as(&arg_latex, got_type, *expect_type)
+            {
+                ctx.recover(Err(CompileError {
+                    kind: CompileErrorKind::TypeMismatch {
+                        got: got_type,
+                        expected: *expect_type,
                     },
-                    func.ret,
-                ))
+                    span: aspan,
+                }))?;
+            }
+            broadcast |= type_errors_ok;
+            Ok(arg_latex)
+        })
+        .collect::<Result<Vec<Latex>, _>>()?;
+
+    // Mapping gives a list of whatever the function returns for a single item
+    let ret = match (broadcast, func.ret) {
+        (true, ValType::Number) => ValType::List,
+        (true, ValType::Point) => ValType::PointList,
+        (_, ret) => ret,
+    };
+
+    Ok((
+        match kind {
+            CallKind::Builtin => lower_builtin_call(emitted_name, args_latex),
+            CallKind::User if should_inline(ctx, fname) => {
+                let f = &ctx.defined_functions[fname];
+                // Only inline functions get here with a captured variable, and their
+                //  definitions aren't emitted so there is no call to fall back to
+                if let Some(var) = captured_variable(ctx, &f.body, &f.params) {
+                    return Err(CompileError {
+                        kind: CompileErrorKind::CapturedVariable(var),
+                        span,
+                    });
+                }
+                let substitutions = f.params.iter().cloned().zip(args_latex).collect();
+                substitute(f.body.clone(), &substitutions)
+            }
+            _ => Latex::Call {
+                func: fname.to_string(),
+                kind,
+                args: args_latex,
+            },
+        },
+        ret,
+    ))
+}
+
+// A variable that l uses and doesn't bind, other than params, which a param, local or
+//  comprehension variable shadows where l is being compiled. Pasting l there would make
+//  it refer to that instead. Let bindings are substituted away before anything is
+//  emitted, so they don't shadow anything in the output.
+fn captured_variable<'a>(ctx: &Context<'a>, l: &Latex, params: &[String]) -> Option<&'a str> {
+    free_variables(l)
+        .into_iter()
+        .filter(|v| !params.contains(v))
+        .find_map(|v| {
+            ctx.locals
+                .get_key_value(v.as_str())
+                .map(|(name, _)| *name)
+                .filter(|name| !ctx.let_bindings.contains_key(name))
+        })
+}
+
+// Whether calls to the user function fname should be replaced with its body. Bodies that
+//  call fname are never inlined, which can happen when a function is redefined in terms of
+//  its old definition, since the call would refer to the new definition in desmos. Neither
+//  are bodies that use a global which is shadowed at the call.
+fn should_inline(ctx: &Context, fname: &str) -> bool {
+    match (ctx.inline_limit, ctx.defined_functions.get(fname)) {
+        // Its definition isn't emitted, so there would be nothing for a call to refer to.
+        //  A call inside the body can only be to an earlier definition, which is fine.
+        (_, Some(f)) if f.inline => true,
+        (Some(limit), Some(f))
+            if node_count(&f.body) <= limit
+                && captured_variable(ctx, &f.body, &f.params).is_none() =>
+        {
+            let mut recursive = false;
+            f.body.walk(&mut |node| {
+                recursive |=
+                    matches!(node, Latex::Call { func, kind: CallKind::User, .. } if func == fname)
+            });
+            !recursive
+        }
+        _ => false,
+    }
+}
+
+// Builtins with a latex command of their own are emitted as \sin, the rest as
+//  \operatorname{mod}
+fn builtin_call_kind(fname: &str) -> CallKind {
+    if builtins::BUILTIN_FUNCTIONS[fname].latex_command {
+        CallKind::Builtin
+    } else {
+        CallKind::OperatorName
+    }
+}
+
+// Most builtins are emitted as calls, but some don't exist in desmos and are written in
+//  terms of other expressions. The args have already been checked against the signature.
+fn lower_builtin_call(fname: &str, args: Vec<Latex>) -> Latex {
+    let mut aiter = args.into_iter();
+    match fname {
+        // \abs isn't a command, so use the bar syntax
+        "abs" => Latex::Abs(Box::new(aiter.next().unwrap())),
+        // div(a, b) = floor(a/b)
+        "div" => Latex::Floor(Box::new(Latex::BinaryExpression {
+            left: Box::new(aiter.next().unwrap()),
+            operator: LatexBinaryOperator::Divide,
+            right: Box::new(aiter.next().unwrap()),
+        })),
+        // repeat(v, n) = v*[1...n]^0, since raising the range to the 0th power gives a
+        //  list of n ones
+        "repeat" => {
+            let value = aiter.next().unwrap();
+            let count = aiter.next().unwrap();
+            Latex::BinaryExpression {
+                left: Box::new(value),
+                operator: LatexBinaryOperator::Multiply,
+                right: Box::new(Latex::BinaryExpression {
+                    left: Box::new(Latex::Range {
+                        start: Box::new(Latex::Num("1".to_string())),
+                        end: Box::new(count),
+                    }),
+                    operator: LatexBinaryOperator::Exponent,
+                    right: Box::new(Latex::Num("0".to_string())),
+                }),
             }
         }
+        _ => Latex::Call {
+            func: fname.to_string(),
+            kind: builtin_call_kind(fname),
+            args: aiter.collect(),
+        },
+    }
+}
+
+fn skip_digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> usize {
+    let mut count = 0;
+    while chars.next_if(|c| c.is_ascii_digit()).is_some() {
+        count += 1;
+    }
+    count
+}
+
+// Checks that n is a decimal number, optionally signed and with an exponent, such as
+//  -1.5e3. The parser only produces valid numbers, but hand-built ASTs might not.
+pub fn is_valid_number(n: &str) -> bool {
+    let mut chars = n.chars().peekable();
+    chars.next_if(|c| *c == '+' || *c == '-');
+    if skip_digits(&mut chars) == 0 {
+        return false;
+    }
+    if chars.next_if_eq(&'.').is_some() && skip_digits(&mut chars) == 0 {
+        return false;
+    }
+    if chars.next_if(|c| *c == 'e' || *c == 'E').is_some() {
+        chars.next_if(|c| *c == '+' || *c == '-');
+        if skip_digits(&mut chars) == 0 {
+            return false;
+        }
     }
+    chars.next().is_none()
 }
 
 pub fn check_type(span: Span, got: ValType, expect: ValType) -> Result<(), CompileError> {
@@ -147,20 +670,272 @@ pub fn check_type(span: Span, got: ValType, expect: ValType) -> Result<(), Compi
     }
 }
 
+// The smallest span covering both a and b, which must be spans of the same input
+pub fn merge_spans<'a>(a: &Span<'a>, b: &Span<'a>) -> Span<'a> {
+    let start = if a.start() <= b.start() { a } else { b };
+    let end = if a.end() >= b.end() { a } else { b };
+    start.start_pos().span(&end.end_pos())
+}
+
+// Operands of arithmetic have to be numbers, except that lists are broadcast over inside
+//  of a map like they are for function arguments
+fn check_operand_type<'a>(
+    ctx: &Context,
+    span: Span<'a>,
+    got: ValType,
+) -> Result<(), CompileError<'a>> {
+    if ctx.inside_map_macro && got == ValType::List {
+        Ok(())
+    } else {
+        check_type(span, got, ValType::Number)
+    }
+}
+
+// Desmos adds and subtracts points coordinate-wise and scales them by numbers, but has no
+//  other arithmetic on them. At least one of ltype and rtype is a point.
+fn check_point_operands<'a>(
+    span: Span<'a>,
+    operator: BinaryOperator,
+    ltype: ValType,
+    rtype: ValType,
+) -> Result<(), CompileError<'a>> {
+    let other = if ltype == ValType::Point {
+        rtype
+    } else {
+        ltype
+    };
+    let mismatch = match operator {
+        // Both sides have to be points, so (1, 2) + 1 is an error
+        BinaryOperator::Add | BinaryOperator::Subtract => {
+            (ltype != rtype).then_some((rtype, ltype))
+        }
+        BinaryOperator::Multiply => (other != ValType::Number).then_some((other, ValType::Number)),
+        BinaryOperator::Divide => (rtype != ValType::Number).then_some((rtype, ValType::Number)),
+        BinaryOperator::Exponent | BinaryOperator::Mod => Some((ValType::Point, ValType::Number)),
+    };
+    match mismatch {
+        Some((got, expected)) => Err(CompileError {
+            kind: CompileErrorKind::TypeMismatch { got, expected },
+            span,
+        }),
+        None => Ok(()),
+    }
+}
+
 // Combination of compile_expr and check_type
 pub fn compile_expect<'a>(
-    ctx: &mut Context,
+    ctx: &mut Context<'a>,
     span: Span<'a>,
     expr: LocatedExpression<'a>,
     expect: ValType,
 ) -> Result<Latex, CompileError<'a>> {
     let (s, t) = compile_expr(ctx, expr)?;
-    check_type(span, t, expect)?;
+    if !empty_list_as(&s, t, expect) {
+        ctx.recover(check_type(span, t, expect))?;
+    }
     Ok(s)
 }
 
+// An empty list literal has no items to tell its type from, so it is a list of numbers
+//  unless it is used where a list of points is expected
+fn empty_list_as(l: &Latex, got: ValType, expect: ValType) -> bool {
+    got == ValType::List
+        && expect == ValType::PointList
+        && matches!(l, Latex::List(items) if items.is_empty())
+}
+
+// Binds var as a local of type t for compiling the body of a comprehension, sum or lambda,
+//  and returns the name to emit it as. It shadows any let binding with the same name. The
+//  values of the other let bindings are pasted into the body, so if one of them uses a
+//  variable named var it would be captured. Then var is emitted as an unused name
+//  instead, and let bound to that name so the body refers to it. The caller restores
+//  locals and let_bindings afterwards.
+fn bind_local<'a>(ctx: &mut Context<'a>, var: &'a str, t: ValType) -> String {
+    ctx.let_bindings.remove(var);
+    ctx.locals.insert(var, t);
+    let captures = |ctx: &Context, name: &str| {
+        ctx.let_bindings
+            .values()
+            .any(|v| free_variables(v).iter().any(|v| v == name))
+    };
+    if !captures(ctx, var) {
+        return var.to_string();
+    }
+    let fresh = (1..)
+        .map(|n| format!("{}{}", var, n))
+        .find(|name| !captures(ctx, name) && resolve_variable(ctx, name).is_none())
+        .unwrap();
+    ctx.let_bindings.insert(var, Latex::Variable(fresh.clone()));
+    fresh
+}
+
+// Compiles the body of a lambda with its parameter bound as a local of type param_type.
+//  The body must evaluate to a Number. Returns the name the parameter is emitted as,
+//  see bind_local, and the body.
+pub fn compile_lambda<'a>(
+    ctx: &mut Context<'a>,
+    param: &'a str,
+    param_type: ValType,
+    body: LocatedExpression<'a>,
+) -> Result<(String, Latex), CompileError<'a>> {
+    let old_locals = ctx.locals.clone();
+    let old_bindings = ctx.let_bindings.clone();
+    let name = bind_local(ctx, param, param_type);
+    let bspan = body.0.clone();
+    let r = compile_expect(ctx, bspan, body, ValType::Number);
+    ctx.locals = old_locals;
+    ctx.let_bindings = old_bindings;
+    Ok((name, r?))
+}
+
+// Compiles [body for var=list] and its two variable form. Each variable iterates over a
+//  list of numbers, and the body has to be a number or a point, giving a list of the
+//  same.
+pub fn compile_comprehension<'a>(
+    ctx: &mut Context<'a>,
+    body: LocatedExpression<'a>,
+    var: &'a str,
+    list: LocatedExpression<'a>,
+    second: Option<(&'a str, LocatedExpression<'a>)>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    let lspan = list.0.clone();
+    let list = compile_expect(ctx, lspan, list, ValType::List)?;
+    let second = match second {
+        Some((var2, list2)) => {
+            let lspan2 = list2.0.clone();
+            Some((var2, compile_expect(ctx, lspan2, list2, ValType::List)?))
+        }
+        None => None,
+    };
+
+    let old_locals = ctx.locals.clone();
+    let old_bindings = ctx.let_bindings.clone();
+    let var = bind_local(ctx, var, ValType::Number);
+    let second = second.map(|(var2, list2)| (bind_local(ctx, var2, ValType::Number), list2));
+    let bspan = body.0.clone();
+    let r = compile_expr(ctx, body);
+    ctx.locals = old_locals;
+    ctx.let_bindings = old_bindings;
+
+    let (body, btype) = r?;
+    let ret = match btype {
+        ValType::Number => ValType::List,
+        ValType::Point => ValType::PointList,
+        _ => {
+            return Err(CompileError {
+                kind: CompileErrorKind::TypeMismatch {
+                    got: btype,
+                    expected: ValType::Number,
+                },
+                span: bspan,
+            })
+        }
+    };
+    Ok((
+        Latex::Comprehension {
+            body: Box::new(body),
+            var,
+            list: Box::new(list),
+            second: second.map(|(var2, list2)| (var2, Box::new(list2))),
+        },
+        ret,
+    ))
+}
+
+// Compiles body with name bound to value. Both locals and let_bindings are restored
+//  afterwards, even if compiling the body fails.
+pub fn compile_let<'a>(
+    ctx: &mut Context<'a>,
+    name: &'a str,
+    value: LocatedExpression<'a>,
+    body: LocatedExpression<'a>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    let (value, vtype) = compile_expr(ctx, value)?;
+    let value = if is_atomic(&value) {
+        value
+    } else {
+        Latex::Paren(Box::new(value))
+    };
+
+    let old_locals = ctx.locals.clone();
+    let old_bindings = ctx.let_bindings.clone();
+    ctx.locals.insert(name, vtype);
+    ctx.let_bindings.insert(name, value);
+    let r = compile_expr(ctx, body);
+    ctx.locals = old_locals;
+    ctx.let_bindings = old_bindings;
+    r
+}
+
+// A map inside the arguments of map!(f, ...) or f@(...) is rejected, so map!(f, map!(g, xs))
+//  is an error pointing at the inner map. Desmos broadcasts a call over its list arguments
+//  all at once, so the nesting would have no effect and the output would be the same as
+//  for map!(f, g(xs)). The list of map!(x => body, xs) isn't inside the map and may be one.
+fn check_not_nested_map<'a>(ctx: &Context<'a>, span: &Span<'a>) -> Result<(), CompileError<'a>> {
+    if ctx.inside_map_macro {
+        Err(CompileError {
+            span: span.clone(),
+            kind: CompileErrorKind::NestedMap,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// f@(args) and map!(f, args). With require_list, at least one of args has to be a list,
+//  since otherwise there is nothing to map over and it is probably a mistake.
+pub fn compile_map_call<'a>(
+    ctx: &mut Context<'a>,
+    span: Span<'a>,
+    fname: &'a str,
+    args: Vec<LocatedExpression<'a>>,
+    require_list: bool,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    check_not_nested_map(ctx, &span)?;
+    // The arguments are compiled inside the map too, so map!(f, xs + ys) maps over the
+    //  element-wise sum. The old state is restored rather than cleared so that this stays
+    //  correct if nesting is ever allowed.
+    let was_inside_map_macro = ctx.inside_map_macro;
+    ctx.inside_map_macro = true;
+    let r = args
+        .into_iter()
+        .map(
+            |(aspan, aexpr)| -> Result<(Span, Latex, ValType), CompileError> {
+                let (latex, t) = compile_expr(ctx, (aspan.clone(), aexpr))?;
+                Ok((aspan, latex, t))
+            },
+        )
+        .collect::<Result<Vec<(Span, Latex, ValType)>, CompileError>>()
+        .and_then(|call_args| {
+            if require_list && call_args.iter().all(|(_, _, t)| *t != ValType::List) {
+                return Err(CompileError {
+                    span: span.clone(),
+                    kind: CompileErrorKind::BadMapMacro,
+                });
+            }
+            compile_call(ctx, span, fname, call_args)
+        });
+    ctx.inside_map_macro = was_inside_map_macro;
+    r
+}
+
+// @(expr) compiles expr with lists broadcast over, so @([1, 2] * 2) is [1, 2] * 2. Desmos
+//  already applies arithmetic element-wise, so the map only changes the type checking.
+fn compile_map_expression<'a>(
+    ctx: &mut Context<'a>,
+    span: Span<'a>,
+    inner: LocatedExpression<'a>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    check_not_nested_map(ctx, &span)?;
+    let was_inside_map_macro = ctx.inside_map_macro;
+    ctx.inside_map_macro = true;
+    let r = compile_expr(ctx, inner);
+    ctx.inside_map_macro = was_inside_map_macro;
+    r
+}
+
 pub fn handle_map_macro<'a>(
-    ctx: &mut Context,
+    ctx: &mut Context<'a>,
     span: Span<'a>,
     args: Vec<LocatedExpression<'a>>,
 ) -> Result<(Latex, ValType), CompileError<'a>> {
@@ -174,23 +949,30 @@ pub fn handle_map_macro<'a>(
     let mut argsiter = args.into_iter();
     let (fspan, fexpr) = argsiter.next().unwrap();
     match fexpr {
-        Expression::Variable(fname) => {
-            let call_args = argsiter
-                .map(
-                    |(aspan, aexpr)| -> Result<(Span, Latex, ValType), CompileError> {
-                        let (latex, t) = compile_expr(ctx, (aspan.clone(), aexpr))?;
-                        Ok((aspan, latex, t))
-                    },
-                )
-                .collect::<Result<Vec<(Span, Latex, ValType)>, CompileError>>()?;
-            //compile_expect(ctx, lspan.clone(), (lspan, lexpr), ValType::List)?;
-            // There should be no situtation in which ctx.inside_map_macro is currently
-            //  true, but save it's old state anyway.
-            let was_inside_map_macro = ctx.inside_map_macro;
-            ctx.inside_map_macro = true;
-            let r = compile_call(ctx, span, fname, call_args);
-            ctx.inside_map_macro = was_inside_map_macro;
-            r
+        Expression::Variable(fname) => compile_map_call(ctx, span, fname, argsiter.collect(), true),
+        // map!(x => body, xs) becomes [body for x=xs]
+        Expression::Lambda { param, body } => {
+            check_not_nested_map(ctx, &span)?;
+            let got = argsiter.len();
+            if got != 1 {
+                return Err(CompileError {
+                    span,
+                    kind: CompileErrorKind::WrongArgCount { got, expected: 1 },
+                });
+            }
+            check_name(ctx, param, &fspan)?;
+            let (lspan, lexpr) = argsiter.next().unwrap();
+            let list = compile_expect(ctx, lspan.clone(), (lspan, lexpr), ValType::List)?;
+            let (var, body) = compile_lambda(ctx, param, ValType::Number, *body)?;
+            Ok((
+                Latex::Comprehension {
+                    body: Box::new(body),
+                    var,
+                    list: Box::new(list),
+                    second: None,
+                },
+                ValType::List,
+            ))
         }
         _ => Err(CompileError {
             span: fspan,
@@ -199,103 +981,722 @@ pub fn handle_map_macro<'a>(
     }
 }
 
-pub fn handle_macro<'a>(
-    ctx: &mut Context,
+// sum!(i in xs, body) adds up body for each i in xs, where i has the type of the items.
+//  Over a range between integer literals, like sum!(i in [1...10], i^2), this is a
+//  summation. Other lists become the total of a comprehension, since desmos can only sum
+//  over bounds, and a range like [1...n] counts down when n is less than 1.
+pub fn handle_sum_macro<'a>(
+    ctx: &mut Context<'a>,
     span: Span<'a>,
-    name: &'a str,
     args: Vec<LocatedExpression<'a>>,
 ) -> Result<(Latex, ValType), CompileError<'a>> {
-    match name {
-        "map" => handle_map_macro(ctx, span, args),
-        _ => Err(CompileError {
-            span,
-            kind: CompileErrorKind::UndefinedMacro(name),
-        }),
+    let mut argsiter = args.into_iter();
+    let (binding, body) = match (argsiter.next(), argsiter.next(), argsiter.next()) {
+        (Some((_, Expression::Binding { var, list })), Some(body), None) => ((var, *list), body),
+        _ => {
+            return Err(CompileError {
+                span,
+                kind: CompileErrorKind::BadSumMacro,
+            })
+        }
+    };
+    let (var, list) = binding;
+    let lspan = list.0.clone();
+    let (list, ltype) = compile_expr(ctx, list)?;
+    let item_type = match ltype {
+        ValType::List => ValType::Number,
+        ValType::PointList => ValType::Point,
+        got => {
+            return Err(CompileError {
+                kind: CompileErrorKind::TypeMismatch {
+                    got,
+                    expected: ValType::List,
+                },
+                span: lspan,
+            })
+        }
+    };
+
+    let old_locals = ctx.locals.clone();
+    let old_bindings = ctx.let_bindings.clone();
+    let var = bind_local(ctx, var, item_type);
+    let bspan = body.0.clone();
+    let r = compile_expr(ctx, body);
+    ctx.locals = old_locals;
+    ctx.let_bindings = old_bindings;
+    let (body, btype) = r?;
+    if btype != ValType::Number && btype != ValType::Point {
+        return Err(CompileError {
+            kind: CompileErrorKind::TypeMismatch {
+                got: btype,
+                expected: ValType::Number,
+            },
+            span: bspan,
+        });
     }
+
+    let latex = match list {
+        Latex::Range { start, end }
+            if literal_int(&start).is_some() && literal_int(&end).is_some() =>
+        {
+            let (lower, upper) = ascending_bounds(*start, *end);
+            Latex::Sum {
+                var,
+                lower: Box::new(lower),
+                upper: Box::new(upper),
+                body: Box::new(body),
+            }
+        }
+        list => Latex::Call {
+            func: "total".to_string(),
+            kind: CallKind::OperatorName,
+            args: vec![Latex::Comprehension {
+                body: Box::new(body),
+                var,
+                list: Box::new(list),
+                second: None,
+            }],
+        },
+    };
+    Ok((latex, btype))
 }
 
-pub fn binop_to_latex(op: BinaryOperator) -> LatexBinaryOperator {
-    match op {
-        BinaryOperator::Add => LatexBinaryOperator::Add,
-        BinaryOperator::Subtract => LatexBinaryOperator::Subtract,
-        BinaryOperator::Multiply => LatexBinaryOperator::Multiply,
-        BinaryOperator::Divide => LatexBinaryOperator::Divide,
-        BinaryOperator::Mod => unreachable!(),
+// Desmos sums to 0 when the upper bound is below the lower one, but the order of a sum
+//  doesn't matter, so bounds that are integer literals counting down are swapped
+fn ascending_bounds(lower: Latex, upper: Latex) -> (Latex, Latex) {
+    match (literal_int(&lower), literal_int(&upper)) {
+        (Some(l), Some(u)) if l > u => (upper, lower),
+        _ => (lower, upper),
     }
 }
 
-pub fn unop_to_latex(op: UnaryOperator) -> LatexUnaryOperator {
+// sum(lo, hi, i => body) is the function form of sum!(i in [lo...hi], body), for when
+//  a lambda reads better. The bounds are given separately, so it is always a summation.
+pub fn compile_sum_call<'a>(
+    ctx: &mut Context<'a>,
+    span: Span<'a>,
+    args: Vec<LocatedExpression<'a>>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    let got = args.len();
+    if got != 3 {
+        return Err(CompileError {
+            span,
+            kind: CompileErrorKind::WrongArgCount { got, expected: 3 },
+        });
+    }
+    let mut argsiter = args.into_iter();
+    let (lspan, lower) = argsiter.next().unwrap();
+    let (uspan, upper) = argsiter.next().unwrap();
+    let lower = compile_expect(ctx, lspan.clone(), (lspan, lower), ValType::Number)?;
+    let upper = compile_expect(ctx, uspan.clone(), (uspan, upper), ValType::Number)?;
+    let (lower, upper) = ascending_bounds(lower, upper);
+    match argsiter.next().unwrap() {
+        (fspan, Expression::Lambda { param, body }) => {
+            check_name(ctx, param, &fspan)?;
+            let (var, body) = compile_lambda(ctx, param, ValType::Number, *body)?;
+            Ok((
+                Latex::Sum {
+                    var,
+                    lower: Box::new(lower),
+                    upper: Box::new(upper),
+                    body: Box::new(body),
+                },
+                ValType::Number,
+            ))
+        }
+        (fspan, _) => Err(CompileError {
+            span: fspan,
+            kind: CompileErrorKind::ExpectedFunction,
+        }),
+    }
+}
+
+pub fn handle_macro<'a>(
+    ctx: &mut Context<'a>,
+    span: Span<'a>,
+    name: &'a str,
+    args: Vec<LocatedExpression<'a>>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    match name {
+        "map" => handle_map_macro(ctx, span, args),
+        "sum" => handle_sum_macro(ctx, span, args),
+        _ => Err(CompileError {
+            span,
+            kind: CompileErrorKind::UndefinedMacro(name),
+        }),
+    }
+}
+
+pub fn binop_to_latex(op: BinaryOperator) -> LatexBinaryOperator {
+    match op {
+        BinaryOperator::Add => LatexBinaryOperator::Add,
+        BinaryOperator::Subtract => LatexBinaryOperator::Subtract,
+        BinaryOperator::Multiply => LatexBinaryOperator::Multiply,
+        BinaryOperator::Divide => LatexBinaryOperator::Divide,
+        BinaryOperator::Exponent => LatexBinaryOperator::Exponent,
+        BinaryOperator::Mod => unreachable!(),
+    }
+}
+
+// None for operators that desmos doesn't have, which are written in terms of others
+pub fn unop_to_latex(op: UnaryOperator) -> Option<LatexUnaryOperator> {
     match op {
-        UnaryOperator::Factorial => LatexUnaryOperator::Factorial,
+        UnaryOperator::Factorial => Some(LatexUnaryOperator::Factorial),
+        UnaryOperator::Negate => Some(LatexUnaryOperator::Negate),
+        UnaryOperator::DoubleFactorial | UnaryOperator::Plus | UnaryOperator::Percent => None,
+    }
+}
+
+// n!! as the product of n-2k for k from 0 to ceil(n/2)-1, which is n(n-2)(n-4)... down
+//  to 1 or 2. For n=0 the product is empty, so it is 1 as it should be.
+fn double_factorial(n: Latex) -> Latex {
+    let num = |n: &str| Box::new(Latex::Num(n.to_string()));
+    let k = fresh_variable(&n, "k");
+    Latex::Product {
+        var: k.clone(),
+        lower: num("0"),
+        upper: Box::new(Latex::BinaryExpression {
+            left: Box::new(Latex::Call {
+                func: "ceil".to_string(),
+                kind: CallKind::OperatorName,
+                args: vec![Latex::BinaryExpression {
+                    left: Box::new(n.clone()),
+                    operator: LatexBinaryOperator::Divide,
+                    right: num("2"),
+                }],
+            }),
+            operator: LatexBinaryOperator::Subtract,
+            right: num("1"),
+        }),
+        body: Box::new(Latex::BinaryExpression {
+            left: Box::new(n),
+            operator: LatexBinaryOperator::Subtract,
+            right: Box::new(Latex::BinaryExpression {
+                left: num("2"),
+                operator: LatexBinaryOperator::Multiply,
+                right: Box::new(Latex::Variable(k)),
+            }),
+        }),
+    }
+}
+
+// Builds a piecewise out of a list of branches, for front-ends that construct the AST
+//  themselves. The AST always has a first branch, so an empty list is an error.
+pub fn build_piecewise<'a>(
+    span: Span<'a>,
+    branches: Vec<Branch<'a>>,
+    default: Option<LocatedExpression<'a>>,
+) -> Result<LocatedExpression<'a>, CompileError<'a>> {
+    let mut biter = branches.into_iter();
+    match biter.next() {
+        Some(first) => Ok((
+            span,
+            Expression::Piecewise {
+                first: Box::new(first),
+                rest: biter.collect(),
+                default: default.map(Box::new),
+            },
+        )),
+        None => Err(CompileError {
+            kind: CompileErrorKind::EmptyPiecewise,
+            span,
+        }),
+    }
+}
+
+// A compiled condition as a list of alternatives, each of which holds when all of its
+//  comparisons do. Desmos conditions are single comparisons, so and/or are lowered from this.
+type Alternatives = Vec<Vec<(Latex, CompareOperator, Latex)>>;
+
+fn compile_condition<'a>(
+    ctx: &mut Context<'a>,
+    cond: LocatedExpression<'a>,
+) -> Result<Alternatives, CompileError<'a>> {
+    let span = cond.0;
+    match cond.1 {
+        Expression::Comparison { left, op, right } => {
+            // Desmos can only compare numbers
+            let lspan = left.0.clone();
+            let rspan = right.0.clone();
+            let left = compile_expect(ctx, lspan, *left, ValType::Number)?;
+            let right = compile_expect(ctx, rspan, *right, ValType::Number)?;
+            Ok(vec![vec![(left, op, right)]])
+        }
+        Expression::Logical {
+            left,
+            op: LogicalOperator::Or,
+            right,
+        } => {
+            let mut alternatives = compile_condition(ctx, *left)?;
+            alternatives.extend(compile_condition(ctx, *right)?);
+            Ok(alternatives)
+        }
+        Expression::Logical {
+            left,
+            op: LogicalOperator::And,
+            right,
+        } => {
+            let left = compile_condition(ctx, *left)?;
+            let right = compile_condition(ctx, *right)?;
+            // (a or b) and c is (a and c) or (b and c)
+            Ok(left
+                .iter()
+                .flat_map(|l| {
+                    right
+                        .iter()
+                        .map(move |r| l.iter().chain(r).cloned().collect())
+                })
+                .collect())
+        }
+        Expression::Paren(inner) => compile_condition(ctx, *inner),
+        e => {
+            let (_, t) = compile_expr(ctx, (span.clone(), e))?;
+            check_type(span.clone(), t, ValType::Bool)?;
+            // Comparisons are handled above, but a host can give a variable type Bool and
+            //  desmos has no way to write that as a condition
+            Err(CompileError {
+                kind: CompileErrorKind::ExpectedComparison,
+                span,
+            })
+        }
     }
 }
 
-pub fn branch_to_cond<'a>(ctx: &mut Context, branch: Branch<'a>) -> Result<Cond, CompileError<'a>> {
-    let leftcondspan = branch.cond_left.0.clone();
-    Ok(Cond {
-        left: compile_expect(ctx, leftcondspan, branch.cond_left, ValType::Number)?,
-        op: branch.cond,
-        right: compile_expr(ctx, branch.cond_right)?.0,
-        result: compile_expr(ctx, branch.val)?.0,
+// The comparisons of an and all have to hold, so their indicators multiply to 1 exactly
+//  where they do and are undefined elsewhere
+fn conjunction(comparisons: Vec<(Latex, CompareOperator, Latex)>) -> Latex {
+    comparisons
+        .into_iter()
+        .map(|(left, op, right)| {
+            Latex::Indicator(Box::new(Latex::Comparison {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            }))
+        })
+        .reduce(|left, right| Latex::BinaryExpression {
+            left: Box::new(left),
+            operator: LatexBinaryOperator::Multiply,
+            right: Box::new(right),
+        })
+        .expect("alternatives aren't empty")
+}
+
+// {cond} as a number, which is 1 where cond holds and undefined elsewhere
+fn compile_indicator<'a>(
+    ctx: &mut Context<'a>,
+    cond: LocatedExpression<'a>,
+) -> Result<Latex, CompileError<'a>> {
+    let mut alternatives = compile_condition(ctx, cond)?;
+    Ok(if alternatives.len() == 1 {
+        conjunction(alternatives.remove(0))
+    } else {
+        // An indicator can't hold an or, so it is a piecewise that is 1 in each case
+        lower_piecewise(vec![(alternatives, Latex::Num("1".to_string()))], None)
     })
 }
 
+// Returns the alternatives of the branch's condition, its compiled result and the type of
+//  the result. Every branch of a piecewise has the same type, so the later branches pass
+//  the first one's type as expect.
+fn compile_branch<'a>(
+    ctx: &mut Context<'a>,
+    branch: Branch<'a>,
+    expect: Option<ValType>,
+) -> Result<(Alternatives, Latex, ValType), CompileError<'a>> {
+    let alternatives = compile_condition(ctx, branch.cond)?;
+    let valspan = branch.val.0.clone();
+    let (result, t) = compile_expr(ctx, branch.val)?;
+    if let Some(expect) = expect {
+        check_type(valspan, t, expect)?;
+    }
+    Ok((alternatives, result, t))
+}
+
+fn piecewise(mut conds: Vec<Cond>, default: Option<Latex>) -> Latex {
+    let first = conds.remove(0);
+    Latex::Piecewise {
+        first: Box::new(first),
+        rest: conds,
+        default: default.map(Box::new),
+    }
+}
+
+// Lowers compiled branches to a desmos piecewise. Each alternative becomes its own branch
+//  with the same result. An alternative with several comparisons becomes the single
+//  condition that their indicators multiply to 1, which is false wherever one of them
+//  fails, so the later branches are still tried without being repeated.
+fn lower_piecewise(branches: Vec<(Alternatives, Latex)>, default: Option<Latex>) -> Latex {
+    let conds = branches
+        .into_iter()
+        .flat_map(|(alts, result)| alts.into_iter().map(move |a| (a, result.clone())))
+        .map(|(mut comparisons, result)| {
+            if comparisons.len() == 1 {
+                let (left, op, right) = comparisons.remove(0);
+                Cond {
+                    left,
+                    op,
+                    right,
+                    result,
+                }
+            } else {
+                Cond {
+                    left: conjunction(comparisons),
+                    op: CompareOperator::Equal,
+                    right: Latex::Num("1".to_string()),
+                    result,
+                }
+            }
+        })
+        .collect();
+    piecewise(conds, default)
+}
+
+pub fn compile_expr<'a>(
+    ctx: &mut Context<'a>,
+    expr: LocatedExpression<'a>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    let span = ctx.source_spans.as_ref().map(|_| expr.0.clone());
+    let r = compile_expr_node(ctx, expr)?;
+    if let (Some(spans), Some(span)) = (&mut ctx.source_spans, span) {
+        spans.push((span, r.0.clone()));
+    }
+    Ok(r)
+}
+
+// Like compile_expr, but carries on past type errors and undefined variables so that all
+//  of them are reported, in the order they appear. Other errors still stop compilation,
+//  and are reported after the ones found before them.
+pub fn compile_expr_all_errors<'a>(
+    ctx: &mut Context<'a>,
+    expr: LocatedExpression<'a>,
+) -> Result<(Latex, ValType), Vec<CompileError<'a>>> {
+    let outer = ctx.collected_errors.replace(vec![]);
+    let r = compile_expr(ctx, expr);
+    let mut errors = std::mem::replace(&mut ctx.collected_errors, outer).unwrap_or_default();
+    match r {
+        Ok(compiled) if errors.is_empty() => Ok(compiled),
+        Ok(_) => Err(errors),
+        Err(e) => {
+            errors.push(e);
+            Err(errors)
+        }
+    }
+}
+
 // Ideally this would be functional and ctx would not need to be mutable, but rust
 //  support for immutable hashmaps isn't built in and mutation is much simpler.
-pub fn compile_expr<'a>(
-    ctx: &mut Context,
+fn compile_expr_node<'a>(
+    ctx: &mut Context<'a>,
     expr: LocatedExpression<'a>,
 ) -> Result<(Latex, ValType), CompileError<'a>> {
     let span = expr.0;
 
     match expr.1 {
-        Expression::Num(val) => Ok((Latex::Num(val.to_string()), ValType::Number)),
+        Expression::Num(val) if !is_valid_number(val) => Err(CompileError {
+            kind: CompileErrorKind::InvalidNumber(val),
+            span,
+        }),
+        Expression::Num(val) => {
+            // A leading + would be emitted as a stray sign
+            let val = val.strip_prefix('+').unwrap_or(val);
+            Ok((
+                match val.find(['e', 'E']) {
+                    // Desmos doesn't understand 1e5, so it becomes 1*10^5
+                    Some(i) => Latex::Scientific {
+                        mantissa: val[..i].to_string(),
+                        exponent: val[i + 1..].trim_start_matches('+').to_string(),
+                    },
+                    None => Latex::Num(val.to_string()),
+                },
+                ValType::Number,
+            ))
+        }
+        Expression::Variable(val) if ctx.let_bindings.contains_key(val) => Ok((
+            ctx.let_bindings[val].clone(),
+            // Let bindings always have their type stored in locals
+            ctx.locals[val],
+        )),
+        Expression::Variable(val) if is_constant(ctx, val) => {
+            let (ctype, latex) = &ctx.constants[val];
+            Ok((latex.clone(), *ctype))
+        }
         Expression::Variable(val) => match resolve_variable(ctx, val) {
-            Some(var_type) => Ok((Latex::Variable(val.to_string()), *var_type)),
-            None => Err(CompileError {
-                kind: CompileErrorKind::UndefinedVariable(val),
+            Some(var_type) => Ok((Latex::Variable(val.to_string()), var_type)),
+            None if UNSUPPORTED_NAMES.contains(&val) => Err(CompileError {
+                kind: CompileErrorKind::UnsupportedFeature(val),
                 span,
             }),
+            None if ctx.implicit_variables => {
+                check_name(ctx, val, &span)?;
+                ctx.implicit_sliders.insert(val);
+                Ok((Latex::Variable(val.to_string()), ValType::Number))
+            }
+            None => {
+                ctx.recover(Err(CompileError {
+                    kind: CompileErrorKind::UndefinedVariable(val),
+                    span,
+                }))?;
+                Ok((Latex::Variable(val.to_string()), ValType::Number))
+            }
         },
         Expression::BinaryExpr {
             left,
             operator,
             right,
         } => {
-            let span2 = span.clone();
-            let lv = compile_expect(ctx, span, *left, ValType::Number)?;
-            let rv = compile_expect(ctx, span2, *right, ValType::Number)?;
+            if let (BinaryOperator::Divide, (rspan, Expression::Num(n))) = (operator, &*right) {
+                if n.parse::<f64>() == Ok(0.0) {
+                    return Err(CompileError {
+                        kind: CompileErrorKind::DivisionByZero,
+                        span: rspan.clone(),
+                    });
+                }
+            }
+            // Operand type errors are about the whole expression. Its span already covers
+            //  both operands when it comes from the parser, but hand-built ASTs might not.
+            let whole = merge_spans(&span, &merge_spans(&left.0, &right.0));
+            let (lv, ltype) = compile_expr(ctx, *left)?;
+            // Check the left operand before compiling the right one so that errors are
+            //  reported in source order. Points and lists that might be joined can only be
+            //  checked once the type of the right operand is known.
+            let left_checked = ltype != ValType::Point
+                && !(operator == BinaryOperator::Add
+                    && ltype == ValType::List
+                    && !ctx.inside_map_macro);
+            if left_checked {
+                ctx.recover(check_operand_type(ctx, whole.clone(), ltype))?;
+            }
+            let rspan = right.0.clone();
+            let (rv, rtype) = compile_expr(ctx, *right)?;
+            // Adding two lists joins them. Desmos would add them item by item instead, which
+            //  is still what happens inside a map since the lists are broadcast over there.
+            if operator == BinaryOperator::Add
+                && ltype == ValType::List
+                && rtype == ValType::List
+                && !ctx.inside_map_macro
+            {
+                // join takes any number of lists, so a + b + c is a single call
+                let mut lists = match lv {
+                    Latex::Call { func, kind, args }
+                        if func == "join" && kind != CallKind::User =>
+                    {
+                        args
+                    }
+                    lv => vec![lv],
+                };
+                lists.push(rv);
+                return Ok((lower_builtin_call("join", lists), ValType::List));
+            }
+            let ret = if ltype == ValType::Point || rtype == ValType::Point {
+                ctx.recover(check_point_operands(whole, operator, ltype, rtype))?;
+                ValType::Point
+            } else {
+                if !left_checked {
+                    ctx.recover(check_operand_type(ctx, whole.clone(), ltype))?;
+                }
+                if operator == BinaryOperator::Exponent {
+                    // Only the base is broadcast over, so xs^2 squares each item of xs
+                    ctx.recover(check_type(rspan, rtype, ValType::Number))?;
+                } else {
+                    ctx.recover(check_operand_type(ctx, whole, rtype))?;
+                }
+                // Desmos applies arithmetic on lists element-wise
+                if ltype == ValType::List || rtype == ValType::List {
+                    ValType::List
+                } else {
+                    ValType::Number
+                }
+            };
             Ok((
                 match operator {
                     BinaryOperator::Mod => Latex::Call {
                         func: "mod".to_string(),
-                        is_builtin: true,
+                        kind: builtin_call_kind("mod"),
                         args: vec![lv, rv],
                     },
-                    _ => Latex::BinaryExpression {
-                        left: Box::new(lv),
-                        operator: binop_to_latex(operator),
-                        right: Box::new(rv),
-                    },
+                    _ => {
+                        let operator = binop_to_latex(operator);
+                        let folded = if ctx.fold_constants {
+                            fold_binary(&lv, operator, &rv)
+                                .map_err(|kind| CompileError { kind, span })?
+                        } else {
+                            None
+                        };
+                        folded.unwrap_or_else(|| Latex::BinaryExpression {
+                            left: Box::new(lv),
+                            operator,
+                            right: Box::new(rv),
+                        })
+                    }
+                },
+                ret,
+            ))
+        }
+        Expression::Let { name, value, body } => {
+            check_name(ctx, name, &span)?;
+            compile_let(ctx, name, *value, *body)
+        }
+        Expression::Range { start, end } => {
+            let sspan = start.0.clone();
+            let espan = end.0.clone();
+            Ok((
+                Latex::Range {
+                    start: Box::new(compile_expect(ctx, sspan, *start, ValType::Number)?),
+                    end: Box::new(compile_expect(ctx, espan, *end, ValType::Number)?),
+                },
+                ValType::List,
+            ))
+        }
+        Expression::Comprehension {
+            body,
+            var,
+            list,
+            second,
+        } => {
+            check_name(ctx, var, &span)?;
+            if let Some((var2, _)) = &second {
+                check_name(ctx, var2, &span)?;
+            }
+            compile_comprehension(ctx, *body, var, *list, second.map(|(v, l)| (v, *l)))
+        }
+        Expression::Point(x, y) => {
+            let xspan = x.0.clone();
+            let yspan = y.0.clone();
+            Ok((
+                Latex::Point(
+                    Box::new(compile_expect(ctx, xspan, *x, ValType::Number)?),
+                    Box::new(compile_expect(ctx, yspan, *y, ValType::Number)?),
+                ),
+                ValType::Point,
+            ))
+        }
+        Expression::Paren(inner) => {
+            let (latex, t) = compile_expr(ctx, *inner)?;
+            Ok((Latex::Paren(Box::new(latex)), t))
+        }
+        Expression::Index { list, index } => {
+            let lspan = list.0.clone();
+            let (list, ltype) = compile_expr(ctx, *list)?;
+            let item_type = match ltype {
+                ValType::List => ValType::Number,
+                ValType::PointList => ValType::Point,
+                got => {
+                    return Err(CompileError {
+                        kind: CompileErrorKind::TypeMismatch {
+                            got,
+                            expected: ValType::List,
+                        },
+                        span: lspan,
+                    })
+                }
+            };
+            let ispan = index.0.clone();
+            let (index, itype) = compile_expr(ctx, *index)?;
+            // Indexing with a list of indices picks out several items, as in a[[1, 3]]
+            let t = match itype {
+                ValType::List => ltype,
+                _ => {
+                    check_type(ispan, itype, ValType::Number)?;
+                    item_type
+                }
+            };
+            Ok((
+                Latex::Index {
+                    list: Box::new(list),
+                    index: Box::new(index),
                 },
+                t,
+            ))
+        }
+        Expression::Pipe { value, funcs } => {
+            let (vspan, value) = *value;
+            let (mut latex, mut t) = compile_expr(ctx, (vspan.clone(), value))?;
+            let mut arg_span = vspan.clone();
+            for (fspan, fname) in funcs {
+                // compile_call checks that each function takes a single argument
+                let (l, ret) = compile_call(ctx, fspan.clone(), fname, vec![(arg_span, latex, t)])?;
+                latex = l;
+                t = ret;
+                // The next function's argument is everything up to and including this call
+                arg_span = merge_spans(&vspan, &fspan);
+            }
+            Ok((latex, t))
+        }
+        Expression::Abs(inner) => {
+            let ispan = inner.0.clone();
+            Ok((
+                Latex::Abs(Box::new(compile_expect(
+                    ctx,
+                    ispan,
+                    *inner,
+                    ValType::Number,
+                )?)),
                 ValType::Number,
             ))
         }
         Expression::UnaryExpr {
             val: v,
             operator: op,
-        } => Ok((
-            Latex::UnaryExpression {
-                left: Box::new(compile_expect(ctx, span, *v, ValType::Number)?),
-                operator: unop_to_latex(op),
-            },
-            ValType::Number,
-        )),
+        } => {
+            let vspan = v.0.clone();
+            let (left, t) = compile_expr(ctx, *v)?;
+            // Like binary operators, these are broadcast over lists inside a map. The double
+            //  factorial is a product up to a bound that depends on its operand, and desmos
+            //  doesn't allow a list as the bound.
+            if op == UnaryOperator::DoubleFactorial {
+                ctx.recover(check_type(span, t, ValType::Number))?;
+            } else {
+                ctx.recover(check_operand_type(ctx, span, t))?;
+            }
+            if ctx.beginner_lints && op == UnaryOperator::Factorial {
+                if let Some(n) = literal_value(&left) {
+                    if n.fract() != 0.0 {
+                        ctx.warnings.push(CompileWarning {
+                            kind: CompileWarningKind::NonIntegerFactorial,
+                            span: vspan,
+                        });
+                    }
+                }
+            }
+            let latex = match unop_to_latex(op) {
+                Some(operator) => Latex::UnaryExpression {
+                    left: Box::new(left),
+                    operator,
+                },
+                None if op == UnaryOperator::DoubleFactorial => double_factorial(left),
+                // \frac{x}{100}, where the fraction already groups x
+                None if op == UnaryOperator::Percent => Latex::BinaryExpression {
+                    left: Box::new(match left {
+                        Latex::Paren(inner) => *inner,
+                        left => left,
+                    }),
+                    operator: LatexBinaryOperator::Divide,
+                    right: Box::new(Latex::Num("100".to_string())),
+                },
+                // The parentheses in +(a+b) only group the operand of the plus. Precedence
+                //  adds them back where they are needed, as in 2*+(a+b).
+                None => match left {
+                    Latex::Paren(inner) => *inner,
+                    left => left,
+                },
+            };
+            let ret = if t == ValType::List {
+                ValType::List
+            } else {
+                ValType::Number
+            };
+            Ok((latex, ret))
+        }
         Expression::Call {
             modifier,
             func,
             args,
         } => match modifier {
+            // Unless a function named sum is defined, sum(lo, hi, i => body) is a summation
+            CallModifier::NormalCall if func == "sum" && resolve_function(ctx, func).is_none() => {
+                compile_sum_call(ctx, span, args)
+            }
             CallModifier::NormalCall => {
                 let compiled_args = args
                     .into_iter()
@@ -306,46 +1707,144 @@ pub fn compile_expr<'a>(
                     .collect::<Result<Vec<(Span, Latex, ValType)>, CompileError>>()?;
                 compile_call(ctx, span, func, compiled_args)
             }
-            CallModifier::MapCall => unimplemented!(),
+            CallModifier::MapCall => compile_map_call(ctx, span, func, args, false),
+            CallModifier::MacroCall => handle_macro(ctx, span, func, args),
         },
         Expression::List(values) => {
+            // The first item decides whether this is a list of numbers or points
+            let mut item_type = None;
             let items = values
                 .into_iter()
                 .map(|(s, e)| -> Result<Latex, CompileError> {
                     let (latex, vtype) = compile_expr(ctx, (s.clone(), e))?;
-                    if vtype != ValType::Number {
-                        Err(CompileError {
+                    if let ValType::List | ValType::PointList = vtype {
+                        return Err(CompileError {
                             span: s,
                             kind: CompileErrorKind::NoNestedList,
-                        })
-                    } else {
-                        Ok(latex)
+                        });
+                    }
+                    match item_type {
+                        None if vtype == ValType::Point => item_type = Some(vtype),
+                        None => {
+                            check_type(s, vtype, ValType::Number)?;
+                            item_type = Some(vtype);
+                        }
+                        Some(expected) => check_type(s, vtype, expected)?,
                     }
+                    Ok(latex)
                 })
                 .collect::<Result<Vec<Latex>, CompileError>>()?;
 
-            Ok((Latex::List(items), ValType::List))
+            let list_type = match item_type {
+                Some(ValType::Point) => ValType::PointList,
+                _ => ValType::List,
+            };
+            Ok((Latex::List(items), list_type))
         }
         Expression::Piecewise {
             first,
             rest,
             default,
         } => {
-            let def = *default;
-            let dspan = def.0.clone();
+            // The first branch decides the type of the whole piecewise
+            let (alternatives, result, ret) = compile_branch(ctx, *first, None)?;
+            let mut branches = vec![(alternatives, result)];
+            for b in rest {
+                let (alternatives, result, _) = compile_branch(ctx, b, Some(ret))?;
+                branches.push((alternatives, result));
+            }
+            let default = match default {
+                Some(def) => {
+                    let dspan = def.0.clone();
+                    Some(compile_expect(ctx, dspan, *def, ret)?)
+                }
+                None => None,
+            };
+            Ok((lower_piecewise(branches, default), ret))
+        }
+        Expression::MapExpression(inner) => compile_map_expression(ctx, span, *inner),
+        Expression::Comparison { left, op, right } => {
+            let lspan = left.0.clone();
+            let rspan = right.0.clone();
             Ok((
-                Latex::Piecewise {
-                    first: Box::new(branch_to_cond(ctx, *first)?),
-                    rest: rest
-                        .into_iter()
-                        .map(|b| branch_to_cond(ctx, b))
-                        .collect::<Result<Vec<_>, _>>()?,
-                    default: Box::new(compile_expect(ctx, dspan, def, ValType::Number)?),
+                Latex::Comparison {
+                    left: Box::new(compile_expect(ctx, lspan, *left, ValType::Number)?),
+                    op,
+                    right: Box::new(compile_expect(ctx, rspan, *right, ValType::Number)?),
+                },
+                ValType::Bool,
+            ))
+        }
+        Expression::Lambda { .. } => Err(CompileError {
+            span,
+            kind: CompileErrorKind::UnexpectedLambda,
+        }),
+        Expression::Binding { .. } => Err(CompileError {
+            span,
+            kind: CompileErrorKind::UnexpectedBinding,
+        }),
+        Expression::Indicator(cond) => Ok((compile_indicator(ctx, *cond)?, ValType::Number)),
+        Expression::Restriction { value, cond } => {
+            let vspan = value.0.clone();
+            let value = compile_expect(ctx, vspan, *value, ValType::Number)?;
+            // Multiplying by the indicator is written as value\left\{cond\right\}
+            Ok((
+                Latex::BinaryExpression {
+                    left: Box::new(value),
+                    operator: LatexBinaryOperator::Multiply,
+                    right: Box::new(compile_indicator(ctx, *cond)?),
                 },
                 ValType::Number,
             ))
         }
-        Expression::MapExpression(_) => unimplemented!(),
+        Expression::Logical { .. } => Err(CompileError {
+            span,
+            kind: CompileErrorKind::UnexpectedLogical,
+        }),
+        Expression::If { cond, then, else_ } => {
+            let alternatives = compile_condition(ctx, *cond)?;
+            // Both branches must have the same type, which is the type of the if
+            let (then, ret) = compile_expr(ctx, *then)?;
+            let espan = else_.0.clone();
+            let else_ = compile_expect(ctx, espan, *else_, ret)?;
+            Ok((
+                lower_piecewise(vec![(alternatives, then)], Some(else_)),
+                ret,
+            ))
+        }
+    }
+}
+
+// compile_expr followed by latex_to_str, for callers that only want the output
+pub fn compile_expr_str<'a>(
+    ctx: &mut Context<'a>,
+    expr: LocatedExpression<'a>,
+) -> Result<(String, ValType), CompileError<'a>> {
+    let (latex, t) = compile_expr(ctx, expr)?;
+    Ok((latex_to_str(latex), t))
+}
+
+// The names of the coordinates in an equation or inequality that desmos plots
+const COORDINATES: &[&str] = &["x", "y"];
+
+// Compiles an expression used as a statement. A comparison there is plotted as a region
+//  or curve, so x and y are the coordinates of each point.
+pub fn compile_top_level_expr<'a>(
+    ctx: &mut Context<'a>,
+    expr: LocatedExpression<'a>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    if let Expression::Comparison { .. } = expr.1 {
+        let old_locals = ctx.locals.clone();
+        for coord in COORDINATES {
+            if resolve_variable(ctx, coord).is_none() {
+                ctx.locals.insert(coord, ValType::Number);
+            }
+        }
+        let r = compile_expr(ctx, expr);
+        ctx.locals = old_locals;
+        r
+    } else {
+        compile_expr(ctx, expr)
     }
 }
 
@@ -353,11 +1852,50 @@ pub fn compile_stmt<'a>(
     ctx: &mut Context<'a>,
     expr: LocatedStatement<'a>,
 ) -> Result<Latex, CompileError<'a>> {
+    Ok(compile_stmt_typed(ctx, expr)?.0)
+}
+
+// Like compile_stmt, but also returns the type of the statement. That is the return type
+//  for a function definition and the type of the value for a variable definition.
+pub fn compile_stmt_typed<'a>(
+    ctx: &mut Context<'a>,
+    expr: LocatedStatement<'a>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
     let s = expr.0;
 
     match expr.1 {
-        Statement::Expression(e) => Ok(compile_expr(ctx, (s, e))?.0),
+        Statement::Expression(e) => compile_top_level_expr(ctx, (s, e)),
+        // Desmos graphs y = x^2 rather than defining y
+        Statement::VarDef(name, e) if COORDINATES.contains(&name) => {
+            let comparison = Expression::Comparison {
+                left: Box::new((s.clone(), Expression::Variable(name))),
+                op: CompareOperator::Equal,
+                right: Box::new(e),
+            };
+            compile_top_level_expr(ctx, (s, comparison))
+        }
+        Statement::VarDef(name, e) => {
+            check_name(ctx, name, &s)?;
+            let (value, t) = compile_expr(ctx, e)?;
+            ctx.variables.insert(name, t);
+            Ok((
+                Latex::Assignment(Box::new(Latex::Variable(name.to_string())), Box::new(value)),
+                t,
+            ))
+        }
         Statement::FuncDef(fdef, e) => {
+            check_name(ctx, fdef.name, &s)?;
+            for (aname, _) in fdef.args.iter() {
+                check_name(ctx, aname, &s)?;
+            }
+            // Defaults are filled in at the call, so they can't refer to the other args
+            let defaulted = &fdef.args[fdef.args.len().saturating_sub(fdef.defaults.len())..];
+            let defaults = fdef
+                .defaults
+                .into_iter()
+                .zip(defaulted)
+                .map(|(d, (_, atype))| compile_expect(ctx, d.0.clone(), d, *atype))
+                .collect::<Result<Vec<_>, _>>()?;
             // Clone a copy we can restore later
             let old_locals = ctx.locals.clone();
             // Add args into locals
@@ -366,36 +1904,87 @@ pub fn compile_stmt<'a>(
             }
             let span = e.0.clone();
             // Evaluate the body with the new ctx
-            let (body, ret) = compile_expr(ctx, e)?;
+            let r = compile_expr(ctx, e);
+            // restore old locals, whether or not the body compiled
+            ctx.locals = old_locals;
+            let (body, ret) = r?;
             // Validate the return type annotation
             if let Some(retann) = fdef.ret_annotation {
                 check_type(span, ret, retann)?;
             }
-            // restore old locals
-            ctx.locals = old_locals;
 
             // Add function to context
+            let params: Vec<String> = fdef.args.iter().map(|a| a.0.to_string()).collect();
             ctx.defined_functions.insert(
                 fdef.name,
-                Rc::new(FunctionSignature {
-                    args: fdef.args.iter().map(|a| a.1).collect(),
-                    ret,
+                Rc::new(UserFunction {
+                    signature: Rc::new(FunctionSignature {
+                        args: fdef.args.iter().map(|a| a.1).collect(),
+                        ret,
+                        deprecated: None,
+                    }),
+                    params: params.clone(),
+                    body: body.clone(),
+                    defaults,
+                    inline: fdef.inline,
                 }),
             );
 
-            Ok(Latex::FuncDef {
-                name: fdef.name.to_string(),
-                args: fdef.args.iter().map(|a| a.0.to_string()).collect(),
-                body: Box::new(body),
-            })
+            Ok((
+                Latex::FuncDef {
+                    name: fdef.name.to_string(),
+                    args: params,
+                    body: Box::new(body),
+                },
+                ret,
+            ))
         }
     }
 }
 
+// Compiles expr with a fresh context that resolves names through env, for hosts that keep
+//  their own symbol tables
+pub fn compile_expr_with_env<'a>(
+    env: &'a dyn TypeEnv,
+    expr: LocatedExpression<'a>,
+) -> Result<(Latex, ValType), CompileError<'a>> {
+    let mut ctx = Context::new();
+    ctx.env = Some(env);
+    compile_expr(&mut ctx, expr)
+}
+
+// Whether l, which a statement was just compiled to, defines an inline function. Those
+//  are left out of programs, since every call to them is replaced with the body.
+pub fn hidden_definition(ctx: &Context, l: &Latex) -> bool {
+    match l {
+        Latex::FuncDef { name, .. } => ctx
+            .defined_functions
+            .get(name.as_str())
+            .is_some_and(|f| f.inline),
+        _ => false,
+    }
+}
+
+// Compiles each statement in order, sharing ctx so later statements can use earlier
+//  definitions. The warnings collected in ctx along the way are returned with the output.
+pub fn compile_program<'a>(
+    ctx: &mut Context<'a>,
+    stmts: Vec<LocatedStatement<'a>>,
+) -> Result<(Vec<Latex>, Vec<CompileWarning<'a>>), CompileError<'a>> {
+    let mut out = Vec::with_capacity(stmts.len());
+    for s in stmts {
+        let latex = compile_stmt(ctx, s)?;
+        if !hidden_definition(ctx, &latex) {
+            out.push(latex);
+        }
+    }
+    Ok((out, std::mem::take(&mut ctx.warnings)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::{ast::FunctionDefinition, latex::CompareOperator};
+    use crate::core::ast::FunctionDefinition;
     use pest::Span;
 
     fn new_ctx<'a>() -> Context<'a> {
@@ -407,7 +1996,7 @@ mod tests {
     }
 
     fn compile_with_ctx<'a>(
-        ctx: &mut Context,
+        ctx: &mut Context<'a>,
         exp: Expression<'a>,
     ) -> Result<Latex, CompileError<'a>> {
         Ok(compile_expr(ctx, (spn(), exp))?.0)
@@ -433,7 +2022,7 @@ mod tests {
     }
 
     fn comp_with_var<'a>(
-        v: &str,
+        v: &'a str,
         vtype: ValType,
         exp: Expression<'a>,
     ) -> Result<Latex, CompileError<'a>> {
@@ -442,7 +2031,7 @@ mod tests {
         compile_with_ctx(&mut ctx, exp)
     }
 
-    fn check_with_var<'a>(v: &str, vtype: ValType, exp: Expression<'a>, r: Latex) {
+    fn check_with_var<'a>(v: &'a str, vtype: ValType, exp: Expression<'a>, r: Latex) {
         assert_eq!(comp_with_var(v, vtype, exp), Ok(r));
     }
 
@@ -473,9 +2062,143 @@ mod tests {
         );
     }
 
-    #[test]
-    fn variable_resolution() {
-        assert_eq!(
+    struct ClosureEnv<F: Fn(&str) -> Option<ValType>>(F);
+
+    impl<F: Fn(&str) -> Option<ValType>> TypeEnv for ClosureEnv<F> {
+        fn variable_type(&self, name: &str) -> Option<ValType> {
+            (self.0)(name)
+        }
+
+        fn function_signature(&self, name: &str) -> Option<Rc<FunctionSignature>> {
+            match name {
+                "f" => Some(Rc::new(FunctionSignature {
+                    args: vec![ValType::Number],
+                    ret: ValType::List,
+                    deprecated: None,
+                })),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn external_type_env() {
+        let env = ClosureEnv(|name: &str| match name {
+            "xs" => Some(ValType::List),
+            "pi" => Some(ValType::Number),
+            n if n.starts_with('n') => Some(ValType::Number),
+            _ => None,
+        });
+        let call_f = |arg| Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func: "f",
+            args: vec![(spn(), Expression::Variable(arg))],
+        };
+        assert_eq!(
+            compile_expr_with_env(&env, (spn(), call_f("n1"))),
+            Ok((
+                Latex::Call {
+                    func: "f".to_string(),
+                    kind: CallKind::User,
+                    args: vec![Latex::Variable("n1".to_string())],
+                },
+                ValType::List
+            ))
+        );
+        assert_eq!(
+            compile_expr_with_env(&env, (spn(), call_f("xs")))
+                .unwrap_err()
+                .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+        // The env shadows constants but not locals
+        assert_eq!(
+            compile_expr_with_env(&env, var("pi")),
+            Ok((Latex::Variable("pi".to_string()), ValType::Number))
+        );
+        let mut ctx = new_ctx();
+        ctx.env = Some(&env);
+        ctx.locals.insert("xs", ValType::Number);
+        assert_eq!(
+            compile_expr(&mut ctx, var("xs")).unwrap().1,
+            ValType::Number
+        );
+        assert_eq!(
+            compile_expr_with_env(&env, var("q")).unwrap_err().kind,
+            CompileErrorKind::UndefinedVariable("q")
+        );
+        // Context is a TypeEnv itself, which includes its env and the builtins
+        assert_eq!(ctx.variable_type("n2"), Some(ValType::Number));
+        assert!(ctx.function_signature("sin").is_some());
+    }
+
+    #[test]
+    fn beginner_lints() {
+        let lint = |e: Expression<'static>, enabled| {
+            let mut ctx = new_ctx();
+            ctx.beginner_lints = enabled;
+            compile_with_ctx(&mut ctx, e).unwrap();
+            ctx.warnings.into_iter().map(|w| w.kind).collect::<Vec<_>>()
+        };
+        let factorial = |n| Expression::UnaryExpr {
+            val: Box::new((spn(), Expression::Num(n))),
+            operator: UnaryOperator::Factorial,
+        };
+        assert_eq!(
+            lint(factorial("2.5"), true),
+            vec![CompileWarningKind::NonIntegerFactorial]
+        );
+        assert_eq!(lint(factorial("2"), true), vec![]);
+        assert_eq!(lint(factorial("2.5"), false), vec![]);
+
+        let call = |func, n| Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func,
+            args: vec![(spn(), Expression::Num(n))],
+        };
+        assert_eq!(
+            lint(call("sin", "1e20"), true),
+            vec![CompileWarningKind::LargeTrigArgument("sin")]
+        );
+        assert_eq!(lint(call("sin", "100000"), true), vec![]);
+        assert_eq!(lint(call("ln", "1e20"), true), vec![]);
+    }
+
+    #[test]
+    fn constants() {
+        let pi = || (spn(), Expression::Variable("pi"));
+        assert_eq!(
+            compile_expr(&mut new_ctx(), pi()),
+            Ok((Latex::Constant("\\pi".to_string()), ValType::Number))
+        );
+        let two_pi = binary(
+            (spn(), Expression::Num("2")),
+            BinaryOperator::Multiply,
+            pi(),
+        );
+        assert_eq!(compile(two_pi.1).map(latex_to_str), Ok("2\\pi".to_string()));
+
+        let mut ctx = new_ctx();
+        ctx.constants
+            .insert("g", (ValType::Number, Latex::Constant("9.8".to_string())));
+        assert_eq!(
+            compile_with_ctx(&mut ctx, Expression::Variable("g")).map(latex_to_str),
+            Ok("9.8".to_string())
+        );
+        // User variables shadow constants
+        ctx.variables.insert("pi", ValType::Number);
+        assert_eq!(
+            compile_with_ctx(&mut ctx, Expression::Variable("pi")),
+            Ok(Latex::Variable("pi".to_string()))
+        );
+    }
+
+    #[test]
+    fn variable_resolution() {
+        assert_eq!(
             compile(Expression::Variable("")).unwrap_err().kind,
             CompileErrorKind::UndefinedVariable("")
         );
@@ -485,6 +2208,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unsupported_names() {
+        assert_eq!(
+            compile(Expression::Variable("i")).unwrap_err().kind,
+            CompileErrorKind::UnsupportedFeature("i")
+        );
+        // Even with implicit variables, i isn't made into a slider
+        let mut ctx = new_ctx();
+        ctx.implicit_variables = true;
+        assert_eq!(
+            compile_with_ctx(&mut ctx, Expression::Variable("infinity"))
+                .unwrap_err()
+                .kind,
+            CompileErrorKind::UnsupportedFeature("infinity")
+        );
+        // A local named i is fine
+        check_with_var(
+            "i",
+            ValType::Number,
+            Expression::Variable("i"),
+            Latex::Variable("i".to_string()),
+        );
+    }
+
+    #[test]
+    fn implicit_sliders() {
+        let x_plus_1 = || Expression::BinaryExpr {
+            left: Box::new((spn(), Expression::Variable("x"))),
+            operator: BinaryOperator::Add,
+            right: Box::new((spn(), Expression::Num("1"))),
+        };
+        assert_eq!(
+            compile(x_plus_1()).unwrap_err().kind,
+            CompileErrorKind::UndefinedVariable("x")
+        );
+
+        let mut ctx = new_ctx();
+        ctx.implicit_variables = true;
+        assert_eq!(
+            compile_expr(&mut ctx, (spn(), x_plus_1())).unwrap(),
+            (
+                Latex::BinaryExpression {
+                    left: Box::new(Latex::Variable("x".to_string())),
+                    operator: LatexBinaryOperator::Add,
+                    right: Box::new(Latex::Num("1".to_string())),
+                },
+                ValType::Number
+            )
+        );
+        assert_eq!(ctx.implicit_sliders, vec!["x"].into_iter().collect());
+    }
+
+    #[test]
+    fn slider_definitions() {
+        let mut ctx = new_ctx();
+        ctx.implicit_variables = true;
+        let product = |a, b| Expression::BinaryExpr {
+            left: Box::new(var(a)),
+            operator: BinaryOperator::Multiply,
+            right: Box::new(var(b)),
+        };
+        let sliders = |ctx: &Context| {
+            ctx.slider_definitions()
+                .into_iter()
+                .map(latex_to_str)
+                .collect::<Vec<_>>()
+        };
+        compile_with_ctx(&mut ctx, product("a", "x")).unwrap();
+        assert_eq!(sliders(&ctx), vec!["a=1"]);
+        compile_with_ctx(&mut ctx, product("c", "b")).unwrap();
+        assert_eq!(sliders(&ctx), vec!["a=1", "b=1", "c=1"]);
+        assert_eq!(new_ctx().slider_definitions(), vec![]);
+    }
+
     #[test]
     fn binary_expr() {
         check(
@@ -511,7 +2308,7 @@ mod tests {
             },
             Latex::Call {
                 func: "mod".to_string(),
-                is_builtin: true,
+                kind: CallKind::OperatorName,
                 args: vec![Latex::Num("1".to_string()), Latex::Num("2".to_string())],
             },
         );
@@ -541,7 +2338,7 @@ mod tests {
             },
             Latex::Call {
                 func: "sin".to_string(),
-                is_builtin: true,
+                kind: CallKind::Builtin,
                 args: vec![Latex::Num("1".to_string())],
             },
         );
@@ -557,6 +2354,268 @@ mod tests {
         );
     }
 
+    #[test]
+    fn builtin_signature_cached() {
+        let ctx = new_ctx();
+        let (a, _) = resolve_function(&ctx, "sin").unwrap();
+        let (b, _) = resolve_function(&ctx, "sin").unwrap();
+        assert!(Rc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn builtin_aliases() {
+        for (alias, name) in builtins::BUILTIN_ALIASES.entries() {
+            assert!(
+                builtins::BUILTIN_FUNCTIONS.contains_key(name),
+                "{} is an alias of {}, which isn't a builtin",
+                alias,
+                name
+            );
+        }
+        let ctx = new_ctx();
+        let (a, _) = resolve_function(&ctx, "asin").unwrap();
+        let (b, _) = resolve_function(&ctx, "arcsin").unwrap();
+        assert!(Rc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn allowed_builtins() {
+        let call = |func| Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func,
+            args: vec![(spn(), Expression::Num("1"))],
+        };
+        let mut ctx = new_ctx();
+        ctx.allowed_builtins = Some(["sin"].iter().copied().collect());
+        assert_eq!(
+            compile_with_ctx(&mut ctx, call("sin")).unwrap(),
+            Latex::Call {
+                func: "sin".to_string(),
+                kind: CallKind::Builtin,
+                args: vec![Latex::Num("1".to_string())],
+            }
+        );
+        assert_eq!(
+            compile_with_ctx(&mut ctx, call("cos")).unwrap_err().kind,
+            CompileErrorKind::UnknownFunction("cos")
+        );
+        // Disallowed builtins can't be called through an alias or as an unknown function
+        ctx.allow_unknown_functions = true;
+        for func in &["cos", "acos"] {
+            assert_eq!(
+                compile_with_ctx(&mut ctx, call(func)).unwrap_err().kind,
+                CompileErrorKind::UnknownFunction(func)
+            );
+        }
+        assert!(compile_with_ctx(&mut ctx, call("custom")).is_ok());
+    }
+
+    #[test]
+    fn div_builtin() {
+        check(
+            Expression::Call {
+                modifier: CallModifier::NormalCall,
+                func: "div",
+                args: vec![(spn(), Expression::Num("7")), (spn(), Expression::Num("2"))],
+            },
+            Latex::Floor(Box::new(Latex::BinaryExpression {
+                left: Box::new(Latex::Num("7".to_string())),
+                operator: LatexBinaryOperator::Divide,
+                right: Box::new(Latex::Num("2".to_string())),
+            })),
+        );
+    }
+
+    #[test]
+    fn repeat_builtin() {
+        let (latex, t) = compile_expr(
+            &mut new_ctx(),
+            (
+                spn(),
+                Expression::Call {
+                    modifier: CallModifier::NormalCall,
+                    func: "repeat",
+                    args: vec![(spn(), Expression::Num("0")), (spn(), Expression::Num("5"))],
+                },
+            ),
+        )
+        .unwrap();
+        assert_eq!(t, ValType::List);
+        assert_eq!(
+            latex,
+            Latex::BinaryExpression {
+                left: Box::new(Latex::Num("0".to_string())),
+                operator: LatexBinaryOperator::Multiply,
+                right: Box::new(Latex::BinaryExpression {
+                    left: Box::new(Latex::Range {
+                        start: Box::new(Latex::Num("1".to_string())),
+                        end: Box::new(Latex::Num("5".to_string())),
+                    }),
+                    operator: LatexBinaryOperator::Exponent,
+                    right: Box::new(Latex::Num("0".to_string())),
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn deprecated_builtin() {
+        let mut ctx = new_ctx();
+        let call = Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func: "stddev",
+            args: vec![list_1_2()],
+        };
+        assert_eq!(
+            compile_with_ctx(&mut ctx, call).unwrap(),
+            Latex::Call {
+                func: "stddev".to_string(),
+                kind: CallKind::OperatorName,
+                args: vec![Latex::List(vec![
+                    Latex::Num("1".to_string()),
+                    Latex::Num("2".to_string())
+                ])],
+            }
+        );
+        assert_eq!(
+            ctx.warnings,
+            vec![CompileWarning {
+                kind: CompileWarningKind::DeprecatedFunction {
+                    name: "stddev",
+                    replacement: "stdev",
+                },
+                span: spn(),
+            }]
+        );
+    }
+
+    #[test]
+    fn program_warnings() {
+        let stddev = |args| Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func: "stddev",
+            args,
+        };
+        let stmts = vec![
+            (spn(), Statement::Expression(stddev(vec![list_1_2()]))),
+            (spn(), Statement::Expression(Expression::Num("1"))),
+        ];
+        let mut ctx = new_ctx();
+        let (out, warnings) = compile_program(&mut ctx, stmts).unwrap();
+        assert_eq!(out.len(), 2);
+        assert_eq!(
+            warnings,
+            vec![CompileWarning {
+                kind: CompileWarningKind::DeprecatedFunction {
+                    name: "stddev",
+                    replacement: "stdev",
+                },
+                span: spn(),
+            }]
+        );
+        assert!(ctx.warnings.is_empty());
+
+        // An error still fails the whole program even after a warning
+        let stmts = vec![
+            (spn(), Statement::Expression(stddev(vec![list_1_2()]))),
+            (spn(), Statement::Expression(stddev(vec![]))),
+        ];
+        assert!(compile_program(&mut new_ctx(), stmts).is_err());
+    }
+
+    #[test]
+    fn unknown_functions() {
+        let call = || Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func: "custom",
+            args: vec![(spn(), Expression::Num("1")), (spn(), Expression::Num("2"))],
+        };
+        assert_eq!(
+            compile(call()).unwrap_err().kind,
+            CompileErrorKind::UnknownFunction("custom")
+        );
+
+        let mut ctx = new_ctx();
+        ctx.allow_unknown_functions = true;
+        assert_eq!(
+            compile_expr(&mut ctx, (spn(), call())).unwrap(),
+            (
+                Latex::Call {
+                    func: "custom".to_string(),
+                    kind: CallKind::OperatorName,
+                    args: vec![Latex::Num("1".to_string()), Latex::Num("2".to_string())],
+                },
+                ValType::Number
+            )
+        );
+        assert_eq!(
+            compile_with_ctx(
+                &mut ctx,
+                Expression::Call {
+                    modifier: CallModifier::NormalCall,
+                    func: "custom",
+                    args: vec![list_1_2()],
+                }
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+    }
+
+    #[test]
+    fn number_validation() {
+        for n in &["1", "-2", "1.5"] {
+            check(Expression::Num(n), Latex::Num(n.to_string()));
+        }
+        check(Expression::Num("+3"), Latex::Num("3".to_string()));
+        for (n, mantissa, exponent) in &[
+            ("10e3", "10", "3"),
+            ("2.5E-3", "2.5", "-3"),
+            ("1e+2", "1", "2"),
+        ] {
+            check(
+                Expression::Num(n),
+                Latex::Scientific {
+                    mantissa: mantissa.to_string(),
+                    exponent: exponent.to_string(),
+                },
+            );
+        }
+        for n in &[
+            "", "-", "1.2.3", "1e", "1.", ".5", "1e5.2", "1x", "--1", "e5",
+        ] {
+            assert_eq!(
+                compile(Expression::Num(n)).unwrap_err().kind,
+                CompileErrorKind::InvalidNumber(n),
+            );
+        }
+    }
+
+    #[test]
+    fn many_builtin_calls() {
+        let call = (
+            spn(),
+            Expression::Call {
+                modifier: CallModifier::NormalCall,
+                func: "sin",
+                args: vec![(spn(), Expression::Num("1"))],
+            },
+        );
+        let compiled = Latex::Call {
+            func: "sin".to_string(),
+            kind: CallKind::Builtin,
+            args: vec![Latex::Num("1".to_string())],
+        };
+        check(
+            Expression::List(vec![call; 1_000]),
+            Latex::List(vec![compiled; 1_000]),
+        );
+    }
+
     #[test]
     fn argc_validation() {
         assert_eq!(
@@ -685,6 +2744,8 @@ mod tests {
                     name: "abc",
                     args: vec![("def", ValType::Number)],
                     ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
                 },
                 (spn(), Expression::Num("1")),
             ),
@@ -704,6 +2765,8 @@ mod tests {
                     name: "f",
                     args: vec![("abc", ValType::List), ("def", ValType::Number)],
                     ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
                 },
                 (spn(), Expression::Num("1")),
             ),
@@ -726,6 +2789,8 @@ mod tests {
                         name: "f",
                         args: vec![("a", ValType::Number)],
                         ret_annotation: None,
+                        defaults: vec![],
+                        inline: false,
                     },
                     (spn(), Expression::Variable("a")),
                 )
@@ -736,157 +2801,1391 @@ mod tests {
                 body: Box::new(Latex::Variable("a".to_string())),
             },)
         );
-        // Check that the variable is no longer in scope
-        assert_eq!(
-            compile_with_ctx(&mut ctx, Expression::Variable("a")),
-            Err(CompileError {
-                span: spn(),
-                kind: CompileErrorKind::UndefinedVariable("a")
-            })
+        // Check that the variable is no longer in scope
+        assert_eq!(
+            compile_with_ctx(&mut ctx, Expression::Variable("a")),
+            Err(CompileError {
+                span: spn(),
+                kind: CompileErrorKind::UndefinedVariable("a")
+            })
+        )
+    }
+
+    #[test]
+    fn funcdef_ret_annotation_checked() {
+        assert_eq!(
+            compile_stmt(Statement::FuncDef(
+                FunctionDefinition {
+                    name: "f",
+                    args: vec![("a", ValType::Number)],
+                    ret_annotation: Some(ValType::List),
+                    defaults: vec![],
+                    inline: false,
+                },
+                (spn(), Expression::Num("1")),
+            ))
+            .unwrap_err(),
+            CompileError {
+                kind: CompileErrorKind::TypeMismatch {
+                    got: ValType::Number,
+                    expected: ValType::List
+                },
+                span: spn()
+            },
+        );
+    }
+
+    #[test]
+    fn funcdef_arg_leave_scope() {
+        let mut ctx = new_ctx();
+        compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "f",
+                    args: vec![("a", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                (spn(), Expression::Variable("a")),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            compile_stmt_with_ctx(&mut ctx, Statement::Expression(Expression::Variable("a")))
+                .unwrap_err(),
+            CompileError {
+                kind: CompileErrorKind::UndefinedVariable("a"),
+                span: spn()
+            }
+        );
+    }
+
+    #[test]
+    fn funcdef_func_callable() {
+        let mut ctx = new_ctx();
+        compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "f",
+                    args: vec![("a", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                (spn(), Expression::Variable("a")),
+            ),
+        )
+        .unwrap();
+        compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::Expression(Expression::Call {
+                modifier: CallModifier::NormalCall,
+                func: "f",
+                args: vec![(spn(), Expression::Num("1"))],
+            }),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn funcdef_func_argslen() {
+        let mut ctx = new_ctx();
+        compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "f",
+                    args: vec![],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                (spn(), Expression::Num("1")),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            compile_stmt_with_ctx(
+                &mut ctx,
+                Statement::Expression(Expression::Call {
+                    modifier: CallModifier::NormalCall,
+                    func: "f",
+                    args: vec![(spn(), Expression::Num("1"))],
+                }),
+            )
+            .unwrap_err(),
+            CompileError {
+                span: spn(),
+                kind: CompileErrorKind::WrongArgCount {
+                    got: 1,
+                    expected: 0,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn funcdef_args_typecheck() {
+        let mut ctx = new_ctx();
+        compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "f",
+                    args: vec![("a", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                (spn(), Expression::Num("1")),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            compile_stmt_with_ctx(
+                &mut ctx,
+                Statement::Expression(Expression::Call {
+                    modifier: CallModifier::NormalCall,
+                    func: "f",
+                    args: vec![(spn(), Expression::List(vec![]))],
+                }),
+            )
+            .unwrap_err(),
+            CompileError {
+                span: spn(),
+                kind: CompileErrorKind::TypeMismatch {
+                    expected: ValType::Number,
+                    got: ValType::List
+                }
+            }
+        );
+    }
+
+    fn define_f(ctx: &mut Context) {
+        compile_stmt_with_ctx(
+            ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "f",
+                    args: vec![("a", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                (spn(), Expression::Variable("a")),
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn inline_functions() {
+        let call = |ctx: &mut Context<'static>, func| {
+            compile_with_ctx(
+                ctx,
+                Expression::Call {
+                    modifier: CallModifier::NormalCall,
+                    func,
+                    args: vec![binary(
+                        var("b"),
+                        BinaryOperator::Add,
+                        (spn(), Expression::Num("1")),
+                    )],
+                },
+            )
+            .map(latex_to_str)
+        };
+        let mut ctx = new_ctx();
+        ctx.variables.insert("b", ValType::Number);
+        ctx.inline_limit = Some(3);
+        // g(a) = a * a
+        compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "g",
+                    args: vec![("a", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                binary(var("a"), BinaryOperator::Multiply, var("a")),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            call(&mut ctx, "g"),
+            Ok("\\left(b+1\\right)\\left(b+1\\right)".to_string())
+        );
+        ctx.inline_limit = Some(2);
+        assert_eq!(call(&mut ctx, "g"), Ok("g\\left(b+1\\right)".to_string()));
+
+        // h(a) = h(a) can't be written directly, but can come from redefining h
+        ctx.inline_limit = Some(10);
+        ctx.defined_functions.insert(
+            "h",
+            Rc::new(UserFunction {
+                signature: Rc::new(FunctionSignature {
+                    args: vec![ValType::Number],
+                    ret: ValType::Number,
+                    deprecated: None,
+                }),
+                params: vec!["a".to_string()],
+                body: Latex::Call {
+                    func: "h".to_string(),
+                    kind: CallKind::User,
+                    args: vec![Latex::Variable("a".to_string())],
+                },
+                defaults: vec![],
+                inline: false,
+            }),
+        );
+        assert_eq!(call(&mut ctx, "h"), Ok("h\\left(b+1\\right)".to_string()));
+
+        // k(a) = a + c, where c is a global that a local shadows at the call
+        ctx.variables.insert("c", ValType::Number);
+        compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "k",
+                    args: vec![("a", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                binary(var("a"), BinaryOperator::Add, var("c")),
+            ),
+        )
+        .unwrap();
+        assert_eq!(call(&mut ctx, "k"), Ok("b+1+c".to_string()));
+        ctx.locals.insert("c", ValType::Number);
+        assert_eq!(call(&mut ctx, "k"), Ok("k\\left(b+1\\right)".to_string()));
+    }
+
+    #[test]
+    fn reserved_identifiers() {
+        let define_g = |ctx: &mut Context| {
+            compile_stmt_with_ctx(
+                ctx,
+                Statement::FuncDef(
+                    FunctionDefinition {
+                        name: "g",
+                        args: vec![("x", ValType::Number), ("a", ValType::Number)],
+                        ret_annotation: None,
+                        defaults: vec![],
+                        inline: false,
+                    },
+                    (spn(), Expression::Variable("x")),
+                ),
+            )
+            .unwrap();
+        };
+        let mut ctx = new_ctx();
+        define_g(&mut ctx);
+        assert_eq!(ctx.warnings, vec![]);
+
+        let mut ctx = new_ctx();
+        ctx.warn_reserved_identifiers = true;
+        define_g(&mut ctx);
+        compile_expr(
+            &mut ctx,
+            let_in(
+                "y",
+                (spn(), Expression::Num("1")),
+                (spn(), Expression::Variable("y")),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            ctx.warnings
+                .iter()
+                .map(|w| w.kind.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                CompileWarningKind::ReservedIdentifier("x"),
+                CompileWarningKind::ReservedIdentifier("y")
+            ]
+        );
+    }
+
+    #[test]
+    fn max_identifier_length() {
+        let define = |ctx: &mut Context<'static>, name| {
+            compile_stmt_with_ctx(ctx, Statement::VarDef(name, (spn(), Expression::Num("1"))))
+                .map(|_| ())
+        };
+        let mut ctx = new_ctx();
+        ctx.max_identifier_length = Some(3);
+        assert_eq!(define(&mut ctx, "abc"), Ok(()));
+        assert_eq!(
+            define(&mut ctx, "abcd").unwrap_err().kind,
+            CompileErrorKind::InvalidIdentifier("abcd")
+        );
+        // Function names and parameters are checked too
+        let funcdef = Statement::FuncDef(
+            FunctionDefinition {
+                name: "f",
+                args: vec![("long", ValType::Number)],
+                ret_annotation: None,
+                defaults: vec![],
+                inline: false,
+            },
+            (spn(), Expression::Variable("long")),
+        );
+        assert_eq!(
+            compile_stmt_with_ctx(&mut ctx, funcdef).unwrap_err().kind,
+            CompileErrorKind::InvalidIdentifier("long")
+        );
+        assert!(ctx.locals.is_empty());
+
+        let mut ctx = new_ctx();
+        assert_eq!(define(&mut ctx, "abcdefghijkl"), Ok(()));
+    }
+
+    #[test]
+    fn variable_from_unknown_call() {
+        // Nothing is known about the function, so its result is assumed to be a number
+        let mut ctx = new_ctx();
+        ctx.allow_unknown_functions = true;
+        let value = Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func: "custom",
+            args: vec![list_1_2()],
+        };
+        assert!(compile_stmt_with_ctx(&mut ctx, Statement::VarDef("a", (spn(), value))).is_err());
+        assert_eq!(ctx.variables.get("a"), None);
+
+        let value = Expression::Call {
+            modifier: CallModifier::NormalCall,
+            func: "custom",
+            args: vec![(spn(), Expression::Num("1"))],
+        };
+        assert!(compile_stmt_with_ctx(&mut ctx, Statement::VarDef("a", (spn(), value))).is_ok());
+        assert_eq!(ctx.variables.get("a"), Some(&ValType::Number));
+    }
+
+    fn call_f<'a>(ctx: &mut Context<'a>) -> Result<Latex, CompileError<'a>> {
+        compile_stmt_with_ctx(
+            ctx,
+            Statement::Expression(Expression::Call {
+                modifier: CallModifier::NormalCall,
+                func: "f",
+                args: vec![(spn(), Expression::Num("1"))],
+            }),
+        )
+    }
+
+    #[test]
+    fn stores_function_bodies() {
+        let mut ctx = new_ctx();
+        ctx.variables.insert("b", ValType::Number);
+        // g(a, c) = a * b
+        let compiled = compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "g",
+                    args: vec![("a", ValType::Number), ("c", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                binary(var("a"), BinaryOperator::Multiply, var("b")),
+            ),
+        )
+        .unwrap();
+        let g = &ctx.defined_functions["g"];
+        assert_eq!(g.params, vec!["a".to_string(), "c".to_string()]);
+        assert_eq!(g.signature.args, vec![ValType::Number, ValType::Number]);
+        match compiled {
+            Latex::FuncDef { body, .. } => assert_eq!(g.body, *body),
+            other => panic!("Expected a function definition, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reset_clears_definitions() {
+        let mut ctx = new_ctx();
+        define_f(&mut ctx);
+        ctx.variables.insert("b", ValType::Number);
+        ctx.locals.insert("c", ValType::Number);
+        ctx.inside_map_macro = true;
+        ctx.reset(false);
+
+        assert_eq!(
+            call_f(&mut ctx).unwrap_err().kind,
+            CompileErrorKind::UnknownFunction("f")
+        );
+        assert!(ctx.variables.is_empty());
+        assert!(ctx.locals.is_empty());
+        assert!(!ctx.inside_map_macro);
+    }
+
+    #[test]
+    fn reset_keeps_definitions() {
+        let mut ctx = new_ctx();
+        define_f(&mut ctx);
+        ctx.variables.insert("b", ValType::Number);
+        ctx.locals.insert("c", ValType::Number);
+        ctx.reset(true);
+
+        call_f(&mut ctx).unwrap();
+        assert_eq!(ctx.variables.get("b"), Some(&ValType::Number));
+        assert!(ctx.locals.is_empty());
+    }
+
+    #[test]
+    fn restore_snapshot() {
+        let mut ctx = new_ctx();
+        ctx.implicit_variables = true;
+        ctx.warn_reserved_identifiers = true;
+        define_f(&mut ctx);
+        let snapshot = ctx.snapshot();
+
+        // g(x) = b + q(1) warns about x and adds a slider for b before failing on q
+        let err = compile_stmt_with_ctx(
+            &mut ctx,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "g",
+                    args: vec![("x", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
+                },
+                (
+                    spn(),
+                    Expression::BinaryExpr {
+                        left: Box::new(var("b")),
+                        operator: BinaryOperator::Add,
+                        right: Box::new((
+                            spn(),
+                            Expression::Call {
+                                modifier: CallModifier::NormalCall,
+                                func: "q",
+                                args: vec![(spn(), Expression::Num("1"))],
+                            },
+                        )),
+                    },
+                ),
+            ),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, CompileErrorKind::UnknownFunction("q"));
+        assert_eq!(ctx.warnings.len(), 1);
+        assert!(ctx.implicit_sliders.contains("b"));
+
+        ctx.restore(snapshot);
+        assert!(ctx.warnings.is_empty());
+        assert!(ctx.implicit_sliders.is_empty());
+        assert!(ctx.locals.is_empty());
+        assert_eq!(ctx.defined_functions.keys().collect::<Vec<_>>(), vec![&"f"]);
+        call_f(&mut ctx).unwrap();
+
+        // Successful statements are rolled back too
+        let snapshot = ctx.snapshot();
+        ctx.variables.insert("c", ValType::Number);
+        ctx.defined_functions.clear();
+        ctx.restore(snapshot);
+        assert!(ctx.variables.is_empty());
+        call_f(&mut ctx).unwrap();
+    }
+
+    #[test]
+    fn import_definitions() {
+        let mut prelude = new_ctx();
+        define_f(&mut prelude);
+        prelude.variables.insert("b", ValType::Number);
+
+        let mut ctx = new_ctx();
+        ctx.import(&prelude, ImportConflict::Error).unwrap();
+        assert_eq!(call_f(&mut ctx).unwrap().to_string(), "f\\left(1\\right)");
+        assert_eq!(ctx.variables.get("b"), Some(&ValType::Number));
+        // The prelude itself is left alone
+        assert_eq!(prelude.defined_functions.len(), 1);
+    }
+
+    #[test]
+    fn import_conflicts() {
+        let mut prelude = new_ctx();
+        define_f(&mut prelude);
+        prelude.variables.insert("b", ValType::Number);
+        let with_conflicts = || {
+            let mut ctx = new_ctx();
+            ctx.variables.insert("f", ValType::List);
+            ctx.variables.insert("b", ValType::List);
+            ctx
+        };
+
+        let mut ctx = with_conflicts();
+        // Functions are checked before variables
+        assert_eq!(ctx.import(&prelude, ImportConflict::Error), Err("f"));
+        assert!(ctx.defined_functions.is_empty());
+        assert_eq!(ctx.variables.get("b"), Some(&ValType::List));
+
+        let mut ctx = with_conflicts();
+        ctx.import(&prelude, ImportConflict::KeepExisting).unwrap();
+        assert_eq!(
+            call_f(&mut ctx).unwrap_err().kind,
+            CompileErrorKind::NotAFunction("f")
+        );
+        assert_eq!(ctx.variables.get("b"), Some(&ValType::List));
+
+        let mut ctx = with_conflicts();
+        ctx.import(&prelude, ImportConflict::Overwrite).unwrap();
+        call_f(&mut ctx).unwrap();
+        assert!(!ctx.variables.contains_key("f"));
+        assert_eq!(ctx.variables.get("b"), Some(&ValType::Number));
+    }
+
+    fn var(v: &str) -> LocatedExpression<'_> {
+        (spn(), Expression::Variable(v))
+    }
+
+    fn let_in<'a>(
+        name: &'a str,
+        value: LocatedExpression<'a>,
+        body: LocatedExpression<'a>,
+    ) -> LocatedExpression<'a> {
+        (
+            spn(),
+            Expression::Let {
+                name,
+                value: Box::new(value),
+                body: Box::new(body),
+            },
+        )
+    }
+
+    fn binary<'a>(
+        left: LocatedExpression<'a>,
+        operator: BinaryOperator,
+        right: LocatedExpression<'a>,
+    ) -> LocatedExpression<'a> {
+        (
+            spn(),
+            Expression::BinaryExpr {
+                left: Box::new(left),
+                operator,
+                right: Box::new(right),
+            },
+        )
+    }
+
+    fn range_1_5() -> LocatedExpression<'static> {
+        (
+            spn(),
+            Expression::Range {
+                start: Box::new((spn(), Expression::Num("1"))),
+                end: Box::new((spn(), Expression::Num("5"))),
+            },
+        )
+    }
+
+    #[test]
+    fn comprehension() {
+        let mut ctx = new_ctx();
+        let (latex, t) = compile_expr(
+            &mut ctx,
+            (
+                spn(),
+                Expression::Comprehension {
+                    body: Box::new(binary(
+                        var("i"),
+                        BinaryOperator::Exponent,
+                        (spn(), Expression::Num("2")),
+                    )),
+                    var: "i",
+                    list: Box::new(range_1_5()),
+                    second: None,
+                },
+            ),
+        )
+        .unwrap();
+        assert_eq!(t, ValType::List);
+        assert_eq!(
+            latex,
+            Latex::Comprehension {
+                body: Box::new(Latex::BinaryExpression {
+                    left: Box::new(Latex::Variable("i".to_string())),
+                    operator: LatexBinaryOperator::Exponent,
+                    right: Box::new(Latex::Num("2".to_string())),
+                }),
+                var: "i".to_string(),
+                list: Box::new(Latex::Range {
+                    start: Box::new(Latex::Num("1".to_string())),
+                    end: Box::new(Latex::Num("5".to_string())),
+                }),
+                second: None,
+            }
+        );
+        assert!(ctx.locals.is_empty());
+    }
+
+    #[test]
+    fn comprehension_errors() {
+        // The list has to be a list
+        assert_eq!(
+            compile(Expression::Comprehension {
+                body: Box::new(var("i")),
+                var: "i",
+                list: Box::new((spn(), Expression::Num("1"))),
+                second: None,
+            })
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::Number,
+                expected: ValType::List
+            }
+        );
+        // The variable is out of scope afterwards, even when the body fails
+        let mut ctx = new_ctx();
+        assert_eq!(
+            compile_with_ctx(
+                &mut ctx,
+                Expression::Comprehension {
+                    body: Box::new(var("j")),
+                    var: "i",
+                    list: Box::new(range_1_5()),
+                    second: None,
+                }
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::UndefinedVariable("j")
+        );
+        assert!(ctx.locals.is_empty());
+    }
+
+    #[test]
+    fn two_variable_comprehension() {
+        let range = |end| {
+            (
+                spn(),
+                Expression::Range {
+                    start: Box::new((spn(), Expression::Num("1"))),
+                    end: Box::new((spn(), Expression::Num(end))),
+                },
+            )
+        };
+        let latex_range = |end: &str| {
+            Box::new(Latex::Range {
+                start: Box::new(Latex::Num("1".to_string())),
+                end: Box::new(Latex::Num(end.to_string())),
+            })
+        };
+        let mut ctx = new_ctx();
+        // [(i,j) for i=[1...3], j=[1...3]]
+        let (latex, t) = compile_expr(
+            &mut ctx,
+            (
+                spn(),
+                Expression::Comprehension {
+                    body: Box::new((
+                        spn(),
+                        Expression::Point(Box::new(var("i")), Box::new(var("j"))),
+                    )),
+                    var: "i",
+                    list: Box::new(range("3")),
+                    second: Some(("j", Box::new(range("3")))),
+                },
+            ),
+        )
+        .unwrap();
+        assert_eq!(t, ValType::PointList);
+        assert_eq!(
+            latex,
+            Latex::Comprehension {
+                body: Box::new(Latex::Point(
+                    Box::new(Latex::Variable("i".to_string())),
+                    Box::new(Latex::Variable("j".to_string())),
+                )),
+                var: "i".to_string(),
+                list: latex_range("3"),
+                second: Some(("j".to_string(), latex_range("3"))),
+            }
+        );
+        assert!(ctx.locals.is_empty());
+
+        // [i+j for i=[1...3], j=[1...3]] is a list of numbers
+        let (_, t) = compile_expr(
+            &mut ctx,
+            (
+                spn(),
+                Expression::Comprehension {
+                    body: Box::new(binary(var("i"), BinaryOperator::Add, var("j"))),
+                    var: "i",
+                    list: Box::new(range("3")),
+                    second: Some(("j", Box::new(range("3")))),
+                },
+            ),
+        )
+        .unwrap();
+        assert_eq!(t, ValType::List);
+
+        // The body can't be a list, and neither variable leaks out
+        assert_eq!(
+            compile_with_ctx(
+                &mut ctx,
+                Expression::Comprehension {
+                    body: Box::new(list_1_2()),
+                    var: "i",
+                    list: Box::new(range("3")),
+                    second: Some(("j", Box::new(range("3")))),
+                }
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+        assert!(ctx.locals.is_empty());
+    }
+
+    #[test]
+    fn built_piecewise() {
+        let branch = |v| Branch {
+            cond: comparison(
+                var("a"),
+                CompareOperator::Equal,
+                (spn(), Expression::Num("1")),
+            ),
+            val: (spn(), Expression::Num(v)),
+        };
+        let num = |n: &str| Latex::Num(n.to_string());
+        let cond = |v| Cond {
+            left: Latex::Variable("a".to_string()),
+            op: CompareOperator::Equal,
+            right: num("1"),
+            result: num(v),
+        };
+        let piecewise = build_piecewise(
+            spn(),
+            vec![branch("2"), branch("3")],
+            Some((spn(), Expression::Num("4"))),
+        )
+        .unwrap();
+        assert_eq!(
+            comp_with_var("a", ValType::Number, piecewise.1).unwrap(),
+            Latex::Piecewise {
+                first: Box::new(cond("2")),
+                rest: vec![cond("3")],
+                default: Some(Box::new(num("4"))),
+            }
+        );
+
+        let src = "{}";
+        let span = Span::new(src, 0, 2).unwrap();
+        let err = build_piecewise(span.clone(), vec![], None).unwrap_err();
+        assert_eq!(err.kind, CompileErrorKind::EmptyPiecewise);
+        assert_eq!(err.span, span);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let divide = |divisor| binary(var("a"), BinaryOperator::Divide, divisor);
+        for zero in &["0", "0.0", "-0"] {
+            assert_eq!(
+                comp_with_var(
+                    "a",
+                    ValType::Number,
+                    divide((spn(), Expression::Num(zero))).1
+                )
+                .unwrap_err()
+                .kind,
+                CompileErrorKind::DivisionByZero
+            );
+        }
+        let mut ctx = new_ctx();
+        ctx.variables.insert("a", ValType::Number);
+        ctx.variables.insert("b", ValType::Number);
+        assert_eq!(
+            compile_with_ctx(&mut ctx, divide(var("b")).1).unwrap(),
+            Latex::BinaryExpression {
+                left: Box::new(Latex::Variable("a".to_string())),
+                operator: LatexBinaryOperator::Divide,
+                right: Box::new(Latex::Variable("b".to_string())),
+            }
+        );
+    }
+
+    #[test]
+    fn constant_folding() {
+        let n = |v| (spn(), Expression::Num(v));
+        let folded = |e: LocatedExpression<'static>| {
+            let mut ctx = new_ctx();
+            ctx.fold_constants = true;
+            ctx.variables.insert("a", ValType::Number);
+            compile_expr(&mut ctx, e).map(|(l, _)| latex_to_str(l))
+        };
+        let large = binary(n("1000000"), BinaryOperator::Multiply, n("1000000000"));
+        assert_eq!(
+            folded(binary(large, BinaryOperator::Multiply, n("1000"))),
+            Ok("1000000000000000000".to_string())
+        );
+        let negative = binary(n("2"), BinaryOperator::Subtract, n("5"));
+        assert_eq!(
+            folded(binary(negative, BinaryOperator::Multiply, var("a"))),
+            Ok("-3a".to_string())
+        );
+        // 0.333... is left for desmos, but other constants around it are still folded
+        let third = binary(n("1"), BinaryOperator::Divide, n("3"));
+        assert_eq!(
+            folded(binary(
+                third,
+                BinaryOperator::Add,
+                binary(n("1"), BinaryOperator::Add, n("1"))
+            )),
+            Ok("\\frac{1}{3}+2".to_string())
+        );
+        let err = folded(binary(n("1e200"), BinaryOperator::Multiply, n("1e200"))).unwrap_err();
+        assert_eq!(err.kind, CompileErrorKind::NonFiniteConstant);
+        // Folding is off by default
+        assert_eq!(
+            compile(binary(n("1"), BinaryOperator::Add, n("2")).1).map(latex_to_str),
+            Ok("1+2".to_string())
+        );
+    }
+
+    #[test]
+    fn merged_spans() {
+        let src = "a + [1]";
+        let s = |start, end| Span::new(src, start, end).unwrap();
+        assert_eq!(merge_spans(&s(0, 1), &s(4, 7)).as_str(), "a + [1]");
+        assert_eq!(merge_spans(&s(4, 7), &s(0, 1)).as_str(), "a + [1]");
+        assert_eq!(merge_spans(&s(0, 7), &s(2, 3)), s(0, 7));
+
+        // Only the operands have real spans here, but the error covers both
+        let mut ctx = new_ctx();
+        ctx.variables.insert("a", ValType::Number);
+        let err = compile_expr(
+            &mut ctx,
+            (
+                s(0, 0),
+                Expression::BinaryExpr {
+                    left: Box::new((s(0, 1), Expression::Variable("a"))),
+                    operator: BinaryOperator::Add,
+                    right: Box::new((
+                        s(4, 7),
+                        Expression::List(vec![(s(5, 6), Expression::Num("1"))]),
+                    )),
+                },
+            ),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+        assert_eq!(err.span.as_str(), "a + [1]");
+    }
+
+    #[test]
+    fn let_bindings_in_function() {
+        let mut ctx = new_ctx();
+        // f(x) = let a = x * 2 in let b = a + 1 in a * b
+        let body = let_in(
+            "a",
+            binary(
+                var("x"),
+                BinaryOperator::Multiply,
+                (spn(), Expression::Num("2")),
+            ),
+            let_in(
+                "b",
+                binary(var("a"), BinaryOperator::Add, (spn(), Expression::Num("1"))),
+                binary(var("a"), BinaryOperator::Multiply, var("b")),
+            ),
+        );
+        let def = FunctionDefinition {
+            name: "f",
+            args: vec![("x", ValType::Number)],
+            ret_annotation: None,
+            defaults: vec![],
+            inline: false,
+        };
+        let a = Latex::Paren(Box::new(Latex::BinaryExpression {
+            left: Box::new(Latex::Variable("x".to_string())),
+            operator: LatexBinaryOperator::Multiply,
+            right: Box::new(Latex::Num("2".to_string())),
+        }));
+        let b = Latex::Paren(Box::new(Latex::BinaryExpression {
+            left: Box::new(a.clone()),
+            operator: LatexBinaryOperator::Add,
+            right: Box::new(Latex::Num("1".to_string())),
+        }));
+        assert_eq!(
+            compile_stmt_with_ctx(&mut ctx, Statement::FuncDef(def.clone(), body)).unwrap(),
+            Latex::FuncDef {
+                name: "f".to_string(),
+                args: vec!["x".to_string()],
+                body: Box::new(Latex::BinaryExpression {
+                    left: Box::new(a),
+                    operator: LatexBinaryOperator::Multiply,
+                    right: Box::new(b),
+                }),
+            }
+        );
+        assert!(ctx.locals.is_empty());
+        assert!(ctx.let_bindings.is_empty());
+
+        // Scope is restored even when the body fails
+        let bad = let_in("a", var("x"), let_in("b", var("a"), var("nope")));
+        assert_eq!(
+            compile_stmt_with_ctx(&mut ctx, Statement::FuncDef(def, bad))
+                .unwrap_err()
+                .kind,
+            CompileErrorKind::UndefinedVariable("nope")
+        );
+        assert!(ctx.locals.is_empty());
+        assert!(ctx.let_bindings.is_empty());
+    }
+
+    #[test]
+    fn let_binding_is_atomic() {
+        // Simple values are substituted without parentheses
+        check(
+            let_in(
+                "a",
+                (spn(), Expression::Num("3")),
+                binary(var("a"), BinaryOperator::Add, var("a")),
+            )
+            .1,
+            Latex::BinaryExpression {
+                left: Box::new(Latex::Num("3".to_string())),
+                operator: LatexBinaryOperator::Add,
+                right: Box::new(Latex::Num("3".to_string())),
+            },
+        );
+    }
+
+    fn comparison<'a>(
+        left: LocatedExpression<'a>,
+        op: CompareOperator,
+        right: LocatedExpression<'a>,
+    ) -> LocatedExpression<'a> {
+        (
+            spn(),
+            Expression::Comparison {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            },
+        )
+    }
+
+    fn greater_than_zero<'a>(v: &'a str) -> LocatedExpression<'a> {
+        comparison(
+            (spn(), Expression::Variable(v)),
+            CompareOperator::GreaterThan,
+            (spn(), Expression::Num("0")),
+        )
+    }
+
+    #[test]
+    fn if_expression() {
+        check_with_var(
+            "a",
+            ValType::Number,
+            Expression::If {
+                cond: Box::new(greater_than_zero("a")),
+                then: Box::new((spn(), Expression::Num("1"))),
+                else_: Box::new((spn(), Expression::Num("-1"))),
+            },
+            Latex::Piecewise {
+                first: Box::new(Cond {
+                    left: Latex::Variable("a".to_string()),
+                    op: CompareOperator::GreaterThan,
+                    right: Latex::Num("0".to_string()),
+                    result: Latex::Num("1".to_string()),
+                }),
+                rest: vec![],
+                default: Some(Box::new(Latex::Num("-1".to_string()))),
+            },
+        );
+    }
+
+    #[test]
+    fn if_typecheck() {
+        assert_eq!(
+            compile(Expression::If {
+                cond: Box::new((spn(), Expression::Num("1"))),
+                then: Box::new((spn(), Expression::Num("1"))),
+                else_: Box::new((spn(), Expression::Num("2"))),
+            })
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::Number,
+                expected: ValType::Bool
+            }
+        );
+        assert_eq!(
+            comp_with_var(
+                "a",
+                ValType::Number,
+                Expression::If {
+                    cond: Box::new(greater_than_zero("a")),
+                    then: Box::new((spn(), Expression::Num("1"))),
+                    else_: Box::new((spn(), Expression::List(vec![]))),
+                }
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+    }
+
+    #[test]
+    fn bool_variable_condition() {
+        let mut ctx = new_ctx();
+        ctx.variables.insert("b", ValType::Bool);
+        assert_eq!(
+            compile_with_ctx(
+                &mut ctx,
+                Expression::If {
+                    cond: Box::new((spn(), Expression::Variable("b"))),
+                    then: Box::new((spn(), Expression::Num("1"))),
+                    else_: Box::new((spn(), Expression::Num("2"))),
+                }
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::ExpectedComparison
+        );
+    }
+
+    #[test]
+    fn logical_outside_condition() {
+        assert_eq!(
+            comp_with_var(
+                "a",
+                ValType::Number,
+                Expression::Logical {
+                    left: Box::new(greater_than_zero("a")),
+                    op: LogicalOperator::And,
+                    right: Box::new(greater_than_zero("a")),
+                }
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::UnexpectedLogical
+        );
+        // Parentheses built by a front-end are looked through
+        check_with_var(
+            "a",
+            ValType::Number,
+            Expression::If {
+                cond: Box::new((spn(), Expression::Paren(Box::new(greater_than_zero("a"))))),
+                then: Box::new((spn(), Expression::Num("1"))),
+                else_: Box::new((spn(), Expression::Num("2"))),
+            },
+            Latex::Piecewise {
+                first: Box::new(Cond {
+                    left: Latex::Variable("a".to_string()),
+                    op: CompareOperator::GreaterThan,
+                    right: Latex::Num("0".to_string()),
+                    result: Latex::Num("1".to_string()),
+                }),
+                rest: vec![],
+                default: Some(Box::new(Latex::Num("2".to_string()))),
+            },
+        );
+    }
+
+    fn map_lambda<'a>(body: Expression<'a>, lists: Vec<LocatedExpression<'a>>) -> Expression<'a> {
+        let mut args = vec![(
+            spn(),
+            Expression::Lambda {
+                param: "x",
+                body: Box::new((spn(), body)),
+            },
+        )];
+        args.extend(lists);
+        Expression::Call {
+            modifier: CallModifier::MacroCall,
+            func: "map",
+            args,
+        }
+    }
+
+    fn x_times_2<'a>() -> Expression<'a> {
+        Expression::BinaryExpr {
+            left: Box::new((spn(), Expression::Variable("x"))),
+            operator: BinaryOperator::Multiply,
+            right: Box::new((spn(), Expression::Num("2"))),
+        }
+    }
+
+    fn list_1_2<'a>() -> LocatedExpression<'a> {
+        (
+            spn(),
+            Expression::List(vec![
+                (spn(), Expression::Num("1")),
+                (spn(), Expression::Num("2")),
+            ]),
         )
     }
 
     #[test]
-    fn funcdef_ret_annotation_checked() {
-        assert_eq!(
-            compile_stmt(Statement::FuncDef(
-                FunctionDefinition {
-                    name: "f",
-                    args: vec![("a", ValType::Number)],
-                    ret_annotation: Some(ValType::List),
-                },
-                (spn(), Expression::Num("1")),
-            ))
-            .unwrap_err(),
-            CompileError {
-                kind: CompileErrorKind::TypeMismatch {
-                    got: ValType::Number,
-                    expected: ValType::List
-                },
-                span: spn()
+    fn map_call() {
+        let sin_list = Latex::Call {
+            func: "sin".to_string(),
+            kind: CallKind::Builtin,
+            args: vec![Latex::List(vec![
+                Latex::Num("1".to_string()),
+                Latex::Num("2".to_string()),
+            ])],
+        };
+        check(
+            Expression::Call {
+                modifier: CallModifier::MapCall,
+                func: "sin",
+                args: vec![list_1_2()],
             },
+            sin_list.clone(),
+        );
+        // Same as the macro form
+        check(
+            Expression::Call {
+                modifier: CallModifier::MacroCall,
+                func: "map",
+                args: vec![(spn(), Expression::Variable("sin")), list_1_2()],
+            },
+            sin_list,
+        );
+        // Lists are still rejected outside of a map
+        assert_eq!(
+            compile(Expression::Call {
+                modifier: CallModifier::NormalCall,
+                func: "sin",
+                args: vec![list_1_2()],
+            })
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
         );
     }
 
     #[test]
-    fn funcdef_arg_leave_scope() {
+    fn total_of_map() {
         let mut ctx = new_ctx();
+        // square(x) = x * x
         compile_stmt_with_ctx(
             &mut ctx,
             Statement::FuncDef(
                 FunctionDefinition {
-                    name: "f",
-                    args: vec![("a", ValType::Number)],
+                    name: "square",
+                    args: vec![("x", ValType::Number)],
                     ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
                 },
-                (spn(), Expression::Variable("a")),
+                binary(var("x"), BinaryOperator::Multiply, var("x")),
             ),
         )
         .unwrap();
+        let map = || {
+            (
+                spn(),
+                Expression::Call {
+                    modifier: CallModifier::MacroCall,
+                    func: "map",
+                    args: vec![var("square"), list_1_2()],
+                },
+            )
+        };
+        let squares = Latex::Call {
+            func: "square".to_string(),
+            kind: CallKind::User,
+            args: vec![Latex::List(vec![
+                Latex::Num("1".to_string()),
+                Latex::Num("2".to_string()),
+            ])],
+        };
         assert_eq!(
-            compile_stmt_with_ctx(&mut ctx, Statement::Expression(Expression::Variable("a")))
-                .unwrap_err(),
-            CompileError {
-                kind: CompileErrorKind::UndefinedVariable("a"),
-                span: spn()
-            }
+            compile_expr(&mut ctx, map()).unwrap(),
+            (squares.clone(), ValType::List)
+        );
+        assert_eq!(
+            compile_expr(
+                &mut ctx,
+                (
+                    spn(),
+                    Expression::Call {
+                        modifier: CallModifier::NormalCall,
+                        func: "total",
+                        args: vec![map()],
+                    }
+                )
+            )
+            .unwrap(),
+            (
+                Latex::Call {
+                    func: "total".to_string(),
+                    kind: CallKind::OperatorName,
+                    args: vec![squares],
+                },
+                ValType::Number
+            )
         );
     }
 
     #[test]
-    fn funcdef_func_callable() {
+    fn expr_str() {
+        let expr = || {
+            binary(
+                var("a"),
+                BinaryOperator::Exponent,
+                (spn(), Expression::Num("2")),
+            )
+        };
         let mut ctx = new_ctx();
-        compile_stmt_with_ctx(
-            &mut ctx,
-            Statement::FuncDef(
-                FunctionDefinition {
-                    name: "f",
-                    args: vec![("a", ValType::Number)],
-                    ret_annotation: None,
-                },
-                (spn(), Expression::Variable("a")),
-            ),
-        )
-        .unwrap();
-        compile_stmt_with_ctx(
-            &mut ctx,
-            Statement::Expression(Expression::Call {
-                modifier: CallModifier::NormalCall,
-                func: "f",
-                args: vec![(spn(), Expression::Num("1"))],
-            }),
-        )
-        .unwrap();
+        ctx.variables.insert("a", ValType::Number);
+        let (latex, t) = compile_expr(&mut ctx, expr()).unwrap();
+        assert_eq!(
+            compile_expr_str(&mut ctx, expr()),
+            Ok((latex_to_str(latex), t))
+        );
+        assert_eq!(
+            compile_expr_str(&mut ctx, var("b")).unwrap_err().kind,
+            CompileErrorKind::UndefinedVariable("b")
+        );
     }
 
     #[test]
-    fn funcdef_func_argslen() {
+    fn map_list_returning_function() {
         let mut ctx = new_ctx();
         compile_stmt_with_ctx(
             &mut ctx,
             Statement::FuncDef(
                 FunctionDefinition {
-                    name: "f",
-                    args: vec![],
+                    name: "pair",
+                    args: vec![("x", ValType::Number)],
                     ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
                 },
-                (spn(), Expression::Num("1")),
+                (
+                    spn(),
+                    Expression::List(vec![
+                        (spn(), Expression::Variable("x")),
+                        (spn(), Expression::Variable("x")),
+                    ]),
+                ),
             ),
         )
         .unwrap();
+        assert_eq!(ctx.defined_functions["pair"].signature.ret, ValType::List);
         assert_eq!(
-            compile_stmt_with_ctx(
+            compile_with_ctx(
                 &mut ctx,
-                Statement::Expression(Expression::Call {
-                    modifier: CallModifier::NormalCall,
-                    func: "f",
-                    args: vec![(spn(), Expression::Num("1"))],
-                }),
-            )
-            .unwrap_err(),
-            CompileError {
-                span: spn(),
-                kind: CompileErrorKind::WrongArgCount {
-                    got: 1,
-                    expected: 0,
+                Expression::Call {
+                    modifier: CallModifier::MapCall,
+                    func: "pair",
+                    args: vec![list_1_2()],
                 }
-            }
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::NoNestedList
         );
     }
 
     #[test]
-    fn funcdef_args_typecheck() {
+    fn map_macro_lambda() {
         let mut ctx = new_ctx();
-        compile_stmt_with_ctx(
-            &mut ctx,
-            Statement::FuncDef(
-                FunctionDefinition {
-                    name: "f",
-                    args: vec![("a", ValType::Number)],
-                    ret_annotation: None,
-                },
-                (spn(), Expression::Num("1")),
-            ),
-        )
-        .unwrap();
         assert_eq!(
-            compile_stmt_with_ctx(
+            compile_expr(
                 &mut ctx,
-                Statement::Expression(Expression::Call {
-                    modifier: CallModifier::NormalCall,
-                    func: "f",
-                    args: vec![(spn(), Expression::List(vec![]))],
-                }),
-            )
-            .unwrap_err(),
-            CompileError {
-                span: spn(),
-                kind: CompileErrorKind::TypeMismatch {
-                    expected: ValType::Number,
-                    got: ValType::List
-                }
+                (
+                    spn(),
+                    map_lambda(
+                        x_times_2(),
+                        vec![(
+                            spn(),
+                            Expression::List(vec![
+                                (spn(), Expression::Num("1")),
+                                (spn(), Expression::Num("2")),
+                                (spn(), Expression::Num("3")),
+                            ])
+                        )]
+                    )
+                )
+            ),
+            Ok((
+                Latex::Comprehension {
+                    body: Box::new(Latex::BinaryExpression {
+                        left: Box::new(Latex::Variable("x".to_string())),
+                        operator: LatexBinaryOperator::Multiply,
+                        right: Box::new(Latex::Num("2".to_string())),
+                    }),
+                    var: "x".to_string(),
+                    list: Box::new(Latex::List(vec![
+                        Latex::Num("1".to_string()),
+                        Latex::Num("2".to_string()),
+                        Latex::Num("3".to_string()),
+                    ])),
+                    second: None,
+                },
+                ValType::List
+            ))
+        );
+        // The parameter doesn't leak out of the lambda
+        assert!(ctx.locals.is_empty());
+    }
+
+    #[test]
+    fn map_macro_lambda_errors() {
+        assert_eq!(
+            compile(map_lambda(x_times_2(), vec![(spn(), Expression::Num("1"))]))
+                .unwrap_err()
+                .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::Number,
+                expected: ValType::List
+            }
+        );
+        assert_eq!(
+            compile(map_lambda(
+                x_times_2(),
+                vec![
+                    (spn(), Expression::List(vec![])),
+                    (spn(), Expression::List(vec![]))
+                ]
+            ))
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::WrongArgCount {
+                got: 2,
+                expected: 1
             }
         );
+        assert_eq!(
+            compile(Expression::Lambda {
+                param: "x",
+                body: Box::new((spn(), Expression::Variable("x")))
+            })
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::UnexpectedLambda
+        );
     }
 
     #[test]
@@ -899,13 +4198,15 @@ mod tests {
                 &mut ctx,
                 Expression::Piecewise {
                     first: Box::new(Branch {
-                        cond_left: (spn(), Expression::Variable("a")),
-                        cond: CompareOperator::Equal,
-                        cond_right: (spn(), Expression::Num("1")),
+                        cond: comparison(
+                            (spn(), Expression::Variable("a")),
+                            CompareOperator::Equal,
+                            (spn(), Expression::Num("1"))
+                        ),
                         val: (spn(), Expression::Num("2"))
                     }),
                     rest: vec![],
-                    default: Box::new((spn(), Expression::Num("3")))
+                    default: Some(Box::new((spn(), Expression::Num("3"))))
                 }
             ),
             Ok(Latex::Piecewise {
@@ -916,11 +4217,45 @@ mod tests {
                     result: Latex::Num("2".to_string())
                 }),
                 rest: vec![],
-                default: Box::new(Latex::Num("3".to_string()))
+                default: Some(Box::new(Latex::Num("3".to_string())))
             })
         );
     }
 
+    #[test]
+    fn piecewise_condition_types() {
+        let piecewise = |left, right| Expression::Piecewise {
+            first: Box::new(Branch {
+                cond: comparison(left, CompareOperator::Equal, right),
+                val: (spn(), Expression::Num("2")),
+            }),
+            rest: vec![],
+            default: None,
+        };
+        let mismatch = CompileErrorKind::TypeMismatch {
+            got: ValType::List,
+            expected: ValType::Number,
+        };
+        assert_eq!(
+            comp_with_var("a", ValType::Number, piecewise(var("a"), list_1_2()))
+                .unwrap_err()
+                .kind,
+            mismatch
+        );
+        assert_eq!(
+            comp_with_var("a", ValType::Number, piecewise(list_1_2(), var("a")))
+                .unwrap_err()
+                .kind,
+            mismatch
+        );
+        assert_eq!(
+            comp_with_var("a", ValType::Number, piecewise(var("a"), var("b")))
+                .unwrap_err()
+                .kind,
+            CompileErrorKind::UndefinedVariable("b")
+        );
+    }
+
     #[test]
     fn piecewise_multi() {
         let mut ctx = new_ctx();
@@ -931,32 +4266,40 @@ mod tests {
                 &mut ctx,
                 Expression::Piecewise {
                     first: Box::new(Branch {
-                        cond_left: (spn(), Expression::Variable("a")),
-                        cond: CompareOperator::GreaterThanEqual,
-                        cond_right: (spn(), Expression::Num("1")),
+                        cond: comparison(
+                            (spn(), Expression::Variable("a")),
+                            CompareOperator::GreaterThanEqual,
+                            (spn(), Expression::Num("1"))
+                        ),
                         val: (spn(), Expression::Num("2"))
                     }),
                     rest: vec![
                         Branch {
-                            cond_left: (spn(), Expression::Variable("a")),
-                            cond: CompareOperator::LessThanEqual,
-                            cond_right: (spn(), Expression::Num("3")),
+                            cond: comparison(
+                                (spn(), Expression::Variable("a")),
+                                CompareOperator::LessThanEqual,
+                                (spn(), Expression::Num("3"))
+                            ),
                             val: (spn(), Expression::Num("4"))
                         },
                         Branch {
-                            cond_left: (spn(), Expression::Variable("a")),
-                            cond: CompareOperator::LessThan,
-                            cond_right: (spn(), Expression::Num("5")),
+                            cond: comparison(
+                                (spn(), Expression::Variable("a")),
+                                CompareOperator::LessThan,
+                                (spn(), Expression::Num("5"))
+                            ),
                             val: (spn(), Expression::Num("6"))
                         },
                         Branch {
-                            cond_left: (spn(), Expression::Variable("a")),
-                            cond: CompareOperator::GreaterThan,
-                            cond_right: (spn(), Expression::Num("7")),
+                            cond: comparison(
+                                (spn(), Expression::Variable("a")),
+                                CompareOperator::GreaterThan,
+                                (spn(), Expression::Num("7"))
+                            ),
                             val: (spn(), Expression::Num("8"))
                         }
                     ],
-                    default: Box::new((spn(), Expression::Num("9")))
+                    default: Some(Box::new((spn(), Expression::Num("9"))))
                 }
             ),
             Ok(Latex::Piecewise {
@@ -986,8 +4329,132 @@ mod tests {
                         result: Latex::Num("8".to_string())
                     }
                 ],
-                default: Box::new(Latex::Num("9".to_string()))
+                default: Some(Box::new(Latex::Num("9".to_string())))
             }),
         );
     }
+
+    #[test]
+    fn all_errors() {
+        let call = |func, arg| {
+            (
+                spn(),
+                Expression::Call {
+                    modifier: CallModifier::NormalCall,
+                    func,
+                    args: vec![arg],
+                },
+            )
+        };
+        let list_1 = || (spn(), Expression::List(vec![(spn(), Expression::Num("1"))]));
+        let mismatch = CompileErrorKind::TypeMismatch {
+            got: ValType::List,
+            expected: ValType::Number,
+        };
+        fn kinds(r: Result<(Latex, ValType), Vec<CompileError>>) -> Vec<CompileErrorKind> {
+            r.unwrap_err().into_iter().map(|e| e.kind).collect()
+        }
+
+        // sin([1]) + cos([2])
+        let expr = binary(
+            call("sin", list_1()),
+            BinaryOperator::Add,
+            call("cos", list_1()),
+        );
+        assert_eq!(
+            kinds(compile_expr_all_errors(&mut new_ctx(), expr.clone())),
+            vec![mismatch.clone(), mismatch.clone()]
+        );
+        // compile_expr still stops at the first
+        assert_eq!(
+            compile_expr(&mut new_ctx(), expr).unwrap_err().kind,
+            mismatch
+        );
+
+        // a + sin([1])
+        assert_eq!(
+            kinds(compile_expr_all_errors(
+                &mut new_ctx(),
+                binary(var("a"), BinaryOperator::Add, call("sin", list_1()))
+            )),
+            vec![CompileErrorKind::UndefinedVariable("a"), mismatch.clone()]
+        );
+        // Errors that can't be recovered from come after the ones before them
+        assert_eq!(
+            kinds(compile_expr_all_errors(
+                &mut new_ctx(),
+                binary(
+                    call("sin", list_1()),
+                    BinaryOperator::Add,
+                    call("nope", list_1())
+                )
+            )),
+            vec![mismatch, CompileErrorKind::UnknownFunction("nope")]
+        );
+        assert_eq!(
+            compile_expr_all_errors(&mut new_ctx(), call("sin", (spn(), Expression::Num("1")))),
+            Ok((
+                Latex::Call {
+                    func: "sin".to_string(),
+                    kind: CallKind::Builtin,
+                    args: vec![Latex::Num("1".to_string())],
+                },
+                ValType::Number
+            ))
+        );
+    }
+
+    #[test]
+    fn list_addition() {
+        let list = |n| (spn(), Expression::List(vec![(spn(), Expression::Num(n))]));
+        let latex_list = |n: &str| Latex::List(vec![Latex::Num(n.to_string())]);
+        let add = || binary(list("1"), BinaryOperator::Add, list("2"));
+        assert_eq!(
+            compile_expr(&mut new_ctx(), add()),
+            Ok((
+                Latex::Call {
+                    func: "join".to_string(),
+                    kind: CallKind::OperatorName,
+                    args: vec![latex_list("1"), latex_list("2")],
+                },
+                ValType::List
+            ))
+        );
+        // Lists are broadcast over inside a map, so they are added item by item
+        let mut ctx = new_ctx();
+        ctx.inside_map_macro = true;
+        assert_eq!(
+            compile_expr(&mut ctx, add()),
+            Ok((
+                Latex::BinaryExpression {
+                    left: Box::new(latex_list("1")),
+                    operator: LatexBinaryOperator::Add,
+                    right: Box::new(latex_list("2")),
+                },
+                ValType::List
+            ))
+        );
+        // The left operand is checked before the right one is compiled
+        assert_eq!(
+            compile_expr(
+                &mut new_ctx(),
+                binary(list("1"), BinaryOperator::Multiply, var("nope"))
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+        assert_eq!(
+            compile_expr(
+                &mut new_ctx(),
+                binary(list("1"), BinaryOperator::Add, var("nope"))
+            )
+            .unwrap_err()
+            .kind,
+            CompileErrorKind::UndefinedVariable("nope")
+        );
+    }
 }
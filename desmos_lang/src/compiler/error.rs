@@ -2,6 +2,10 @@ use crate::core::runtime::{ArgCount, ValType};
 use pest::{error as pest_err, Span};
 use std::fmt;
 
+// New kinds are added as the language grows, so code outside this crate has to have a
+//  wildcard arm when matching on this. Inside the crate matches stay exhaustive, and
+//  the tests check that ALL_KINDS lists every variant.
+#[non_exhaustive]
 #[derive(Clone, Debug, PartialEq)]
 pub enum CompileErrorKind<'a> {
     UnknownFunction(&'a str),
@@ -12,6 +16,30 @@ pub enum CompileErrorKind<'a> {
     BadMapMacro,
     ExpectedFunction,
     NoNestedList,
+    UnexpectedLambda,
+    InvalidNumber(&'a str),
+    DivisionByZero,
+    EmptyPiecewise,
+    // A name that looks like it should mean something, but desmos can't express it
+    UnsupportedFeature(&'a str),
+    // A map inside the arguments of another map, the span is the inner one
+    NestedMap,
+    // Constant folding produced infinity or NaN
+    NonFiniteConstant,
+    UnexpectedBinding,
+    BadSumMacro,
+    // A name longer than Context::max_identifier_length
+    InvalidIdentifier(&'a str),
+    // A call to a name that is defined as a variable
+    NotAFunction(&'a str),
+    UnexpectedLogical,
+    // A program without statements where there has to be a last one to take the type of
+    EmptyProgram,
+    // A global used by an inline function or a default that a param or local shadows at
+    //  the call, so pasting it there would refer to the wrong variable
+    CapturedVariable(&'a str),
+    // A condition with type Bool that isn't a comparison, like a variable of that type
+    ExpectedComparison,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -20,6 +48,26 @@ pub struct CompileError<'a> {
     pub span: Span<'a>,
 }
 
+// Problems that don't stop compilation, collected in Context::warnings
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileWarningKind<'a> {
+    DeprecatedFunction {
+        name: &'a str,
+        replacement: &'static str,
+    },
+    // A user name that desmos treats specially
+    ReservedIdentifier(&'a str),
+    // The rest are from Context::beginner_lints
+    NonIntegerFactorial,
+    LargeTrigArgument(&'a str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileWarning<'a> {
+    pub kind: CompileWarningKind<'a>,
+    pub span: Span<'a>,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash, Copy, PartialOrd, Ord)]
 struct DummyRuleType {}
 
@@ -31,14 +79,14 @@ impl CompileError<'_> {
                 format!("Expected {} arguments but got {}", expected, got)
             }
             CompileErrorKind::TypeMismatch { got, expected } => {
-                format!("Expected type {:#?} but got {:#?}", expected, got)
+                format!("Expected type {} but got {}", expected, got)
             }
             CompileErrorKind::UndefinedVariable(var) => {
                 format!("Undefined variable '{}'", var)
             }
             CompileErrorKind::UndefinedMacro(name) => format!("Undefined macro '{}'", name),
             CompileErrorKind::BadMapMacro => {
-                "The map! macro takes a function and then at least one list to pass\
+                "The map! macro takes a function and then at least one list to pass \
                 as an argument"
                     .to_string()
             }
@@ -46,18 +94,302 @@ impl CompileError<'_> {
             CompileErrorKind::NoNestedList => {
                 "Storing lists inside of lists is not allowed.".to_string()
             }
+            CompileErrorKind::UnexpectedLambda => {
+                "Lambdas can only be passed as an argument to a macro".to_string()
+            }
+            CompileErrorKind::InvalidNumber(n) => format!("Invalid number '{}'", n),
+            CompileErrorKind::DivisionByZero => "Division by zero".to_string(),
+            CompileErrorKind::EmptyPiecewise => "A piecewise needs at least one branch".to_string(),
+            CompileErrorKind::UnsupportedFeature(name) => {
+                let note = match name {
+                    "i" => "desmos doesn't support complex numbers",
+                    "infinity" => "desmos only supports infinity as a bound, not as a value",
+                    _ => "desmos doesn't support it",
+                };
+                format!("'{}' is not supported, {}", name, note)
+            }
+            CompileErrorKind::NestedMap => {
+                "Maps can't be nested, define a function for the inner map instead".to_string()
+            }
+            CompileErrorKind::NonFiniteConstant => {
+                "This constant is infinite or not a number, which desmos can't represent"
+                    .to_string()
+            }
+            CompileErrorKind::UnexpectedBinding => {
+                "Bindings like 'i in xs' can only be passed as an argument to a macro".to_string()
+            }
+            CompileErrorKind::BadSumMacro => {
+                "The sum! macro takes a binding like 'i in xs' and then the expression to sum"
+                    .to_string()
+            }
+            CompileErrorKind::InvalidIdentifier(name) => {
+                format!("The name '{}' is longer than the maximum allowed", name)
+            }
+            CompileErrorKind::NotAFunction(name) => {
+                format!("'{}' is a variable, not a function", name)
+            }
+            CompileErrorKind::UnexpectedLogical => {
+                "'and' and 'or' can only be used in the condition of a piecewise or if".to_string()
+            }
+            CompileErrorKind::EmptyProgram => "The program has no statements".to_string(),
+            CompileErrorKind::CapturedVariable(name) => format!(
+                "'{}' is shadowed here, so it can't refer to the global of the same name",
+                name
+            ),
+            CompileErrorKind::ExpectedComparison => {
+                "Expected a comparison like 'a > 0' as the condition".to_string()
+            }
         }
     }
 }
 
+impl CompileError<'_> {
+    // Renders the message followed by the source lines the span covers, with carets
+    //  under the offending part of the first line, similar to rustc.
+    pub fn pretty_print(&self) -> String {
+        let (line, col) = self.span.start_pos().line_col();
+        let mut lines = self
+            .span
+            .lines()
+            .map(|l| l.trim_end_matches(&['\r', '\n'][..]))
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            // The span is at the very end of the input
+            lines.push(self.span.start_pos().line_of());
+        }
+
+        let gutter = (line + lines.len() - 1).to_string().len();
+        let mut out = format!(
+            "error: {}\n{:gutter$}--> {}:{}\n{:gutter$} |\n",
+            self.as_msg(),
+            "",
+            line,
+            col,
+            "",
+            gutter = gutter
+        );
+        for (i, text) in lines.iter().enumerate() {
+            let numbered = format!("{:>gutter$} | {}", line + i, text, gutter = gutter);
+            out += numbered.trim_end();
+            out.push('\n');
+        }
+
+        // Underline up to the end of the span or the first line, whichever is first
+        let first_len = lines[0].chars().count();
+        let span_len = self.span.as_str().chars().count();
+        let carets = span_len.min(first_len.saturating_sub(col - 1)).max(1);
+        out += &format!(
+            "{:gutter$} | {}{}",
+            "",
+            " ".repeat(col - 1),
+            "^".repeat(carets),
+            gutter = gutter
+        );
+        out
+    }
+}
+
 impl fmt::Display for CompileError<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s: pest_err::Error<DummyRuleType> = pest_err::Error::new_from_span(
-            pest_err::ErrorVariant::CustomError {
-                message: self.as_msg(),
+        write_with_span(f, self.as_msg(), &self.span)
+    }
+}
+
+impl CompileWarning<'_> {
+    fn as_msg(&self) -> String {
+        match self.kind {
+            CompileWarningKind::DeprecatedFunction { name, replacement } => {
+                format!("'{}' is deprecated, use '{}' instead", name, replacement)
+            }
+            CompileWarningKind::ReservedIdentifier(name) => {
+                format!("'{}' has a special meaning in desmos", name)
+            }
+            CompileWarningKind::NonIntegerFactorial => {
+                "The factorial of a number that isn't an integer is computed with the gamma \
+                function"
+                    .to_string()
+            }
+            CompileWarningKind::LargeTrigArgument(func) => {
+                format!(
+                    "'{}' of a number this large gives a meaningless result",
+                    func
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for CompileWarning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_with_span(f, format!("warning: {}", self.as_msg()), &self.span)
+    }
+}
+
+fn write_with_span(f: &mut fmt::Formatter, message: String, span: &Span) -> fmt::Result {
+    let s: pest_err::Error<DummyRuleType> = pest_err::Error::new_from_span(
+        pest_err::ErrorVariant::CustomError { message },
+        span.clone(),
+    );
+    write!(f, "{}", s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One instance of every kind
+    const ALL_KINDS: &[CompileErrorKind<'static>] = &[
+        CompileErrorKind::UnknownFunction("f"),
+        CompileErrorKind::WrongArgCount {
+            got: 1,
+            expected: 2,
+        },
+        CompileErrorKind::TypeMismatch {
+            got: ValType::List,
+            expected: ValType::Number,
+        },
+        CompileErrorKind::UndefinedVariable("a"),
+        CompileErrorKind::UndefinedMacro("m"),
+        CompileErrorKind::BadMapMacro,
+        CompileErrorKind::ExpectedFunction,
+        CompileErrorKind::NoNestedList,
+        CompileErrorKind::UnexpectedLambda,
+        CompileErrorKind::InvalidNumber("1.2.3"),
+        CompileErrorKind::DivisionByZero,
+        CompileErrorKind::EmptyPiecewise,
+        CompileErrorKind::UnsupportedFeature("i"),
+        CompileErrorKind::NestedMap,
+        CompileErrorKind::NonFiniteConstant,
+        CompileErrorKind::UnexpectedBinding,
+        CompileErrorKind::BadSumMacro,
+        CompileErrorKind::InvalidIdentifier("abc"),
+        CompileErrorKind::NotAFunction("a"),
+        CompileErrorKind::UnexpectedLogical,
+        CompileErrorKind::EmptyProgram,
+        CompileErrorKind::CapturedVariable("c"),
+        CompileErrorKind::ExpectedComparison,
+    ];
+
+    // Adding a kind breaks this match until it is given the next index here and
+    //  KIND_COUNT is bumped, and then all_kinds_listed fails until it is added to
+    //  ALL_KINDS as well.
+    const KIND_COUNT: usize = 23;
+
+    fn kind_index(kind: &CompileErrorKind) -> usize {
+        match kind {
+            CompileErrorKind::UnknownFunction(_) => 0,
+            CompileErrorKind::WrongArgCount { .. } => 1,
+            CompileErrorKind::TypeMismatch { .. } => 2,
+            CompileErrorKind::UndefinedVariable(_) => 3,
+            CompileErrorKind::UndefinedMacro(_) => 4,
+            CompileErrorKind::BadMapMacro => 5,
+            CompileErrorKind::ExpectedFunction => 6,
+            CompileErrorKind::NoNestedList => 7,
+            CompileErrorKind::UnexpectedLambda => 8,
+            CompileErrorKind::InvalidNumber(_) => 9,
+            CompileErrorKind::DivisionByZero => 10,
+            CompileErrorKind::EmptyPiecewise => 11,
+            CompileErrorKind::UnsupportedFeature(_) => 12,
+            CompileErrorKind::NestedMap => 13,
+            CompileErrorKind::NonFiniteConstant => 14,
+            CompileErrorKind::UnexpectedBinding => 15,
+            CompileErrorKind::BadSumMacro => 16,
+            CompileErrorKind::InvalidIdentifier(_) => 17,
+            CompileErrorKind::NotAFunction(_) => 18,
+            CompileErrorKind::UnexpectedLogical => 19,
+            CompileErrorKind::EmptyProgram => 20,
+            CompileErrorKind::CapturedVariable(_) => 21,
+            CompileErrorKind::ExpectedComparison => 22,
+        }
+    }
+
+    #[test]
+    fn all_kinds_listed() {
+        let mut indexes = ALL_KINDS.iter().map(kind_index).collect::<Vec<_>>();
+        indexes.sort_unstable();
+        assert_eq!(indexes, (0..KIND_COUNT).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn all_kinds_have_messages() {
+        for kind in ALL_KINDS {
+            let err = CompileError {
+                kind: kind.clone(),
+                span: Span::new("", 0, 0).unwrap(),
+            };
+            assert!(!err.as_msg().is_empty(), "{:?} has no message", kind);
+        }
+    }
+
+    #[test]
+    fn type_mismatch_message() {
+        let msg = CompileError {
+            kind: CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number,
             },
-            self.span.clone(),
+            span: Span::new("", 0, 0).unwrap(),
+        }
+        .as_msg();
+        assert_eq!(msg, "Expected type number but got list");
+        assert!(!msg.contains('\n'));
+    }
+
+    #[test]
+    fn deprecation_message() {
+        let warning = CompileWarning {
+            kind: CompileWarningKind::DeprecatedFunction {
+                name: "stddev",
+                replacement: "stdev",
+            },
+            span: Span::new("", 0, 0).unwrap(),
+        };
+        assert_eq!(
+            warning.as_msg(),
+            "'stddev' is deprecated, use 'stdev' instead"
+        );
+    }
+
+    #[test]
+    fn pretty_print() {
+        let src = "f(a) = a\n1 + abc(2)";
+        let err = CompileError {
+            kind: CompileErrorKind::UnknownFunction("abc"),
+            span: Span::new(src, 13, 19).unwrap(),
+        };
+        assert_eq!(
+            err.pretty_print(),
+            "error: Unknown function 'abc'
+ --> 2:5
+  |
+2 | 1 + abc(2)
+  |     ^^^^^^"
+        );
+    }
+
+    #[test]
+    fn pretty_print_multiline() {
+        let src = "1 +\n\n\n\n\n\n\n\n\nabc";
+        let err = CompileError {
+            kind: CompileErrorKind::UndefinedVariable("abc"),
+            span: Span::new(src, 0, src.len()).unwrap(),
+        };
+        assert_eq!(
+            err.pretty_print(),
+            "error: Undefined variable 'abc'
+  --> 1:1
+   |
+ 1 | 1 +
+ 2 |
+ 3 |
+ 4 |
+ 5 |
+ 6 |
+ 7 |
+ 8 |
+ 9 |
+10 | abc
+   | ^^^"
         );
-        write!(f, "{}", s)
     }
 }
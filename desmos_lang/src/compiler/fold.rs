@@ -0,0 +1,142 @@
+use super::error::CompileErrorKind;
+use crate::core::latex::{BinaryOperator, Latex, UnaryOperator};
+
+// Desmos evaluates with doubles, which hold at least this many significant decimal digits
+//  exactly. Results that need more, like 1/3 or 0.1+0.2, are left for desmos to compute
+//  so the output doesn't fill up with long decimals.
+const MAX_SIGNIFICANT_DIGITS: usize = 15;
+
+// The value of a number literal, including negated, scientific and parenthesized ones
+pub fn literal_value(l: &Latex) -> Option<f64> {
+    match l {
+        Latex::Num(n) => n.parse().ok(),
+        Latex::Paren(inner) => literal_value(inner),
+        Latex::Scientific { mantissa, exponent } => {
+            let mantissa: f64 = mantissa.parse().ok()?;
+            let exponent: i32 = exponent.parse().ok()?;
+            Some(mantissa * 10f64.powi(exponent))
+        }
+        Latex::UnaryExpression {
+            left,
+            operator: UnaryOperator::Negate,
+        } => literal_value(left).map(|v| -v),
+        _ => None,
+    }
+}
+
+// Formats v as a plain decimal that desmos accepts, never as inf, NaN or with an
+//  exponent. Returns None when v has too many significant digits to be worth folding.
+pub fn format_number(v: f64) -> Result<Option<String>, CompileErrorKind<'static>> {
+    if !v.is_finite() {
+        return Err(CompileErrorKind::NonFiniteConstant);
+    }
+    // Display for f64 already gives the shortest decimal that parses back to v, without
+    //  trailing zeros after the point or an exponent. -0 is the only special case.
+    let s = if v == 0.0 {
+        "0".to_string()
+    } else {
+        v.abs().to_string()
+    };
+    let digits = s.trim_start_matches(['0', '.']).replace('.', "");
+    if digits.trim_end_matches('0').len() > MAX_SIGNIFICANT_DIGITS {
+        return Ok(None);
+    }
+    Ok(Some(if v < 0.0 { format!("-{}", s) } else { s }))
+}
+
+fn number_to_latex(s: String) -> Latex {
+    match s.strip_prefix('-') {
+        // Negative results are negations so the precedence rules parenthesize them
+        Some(abs) => Latex::UnaryExpression {
+            left: Box::new(Latex::Num(abs.to_string())),
+            operator: UnaryOperator::Negate,
+        },
+        None => Latex::Num(s),
+    }
+}
+
+// Evaluates left op right when both sides are literals. Returns Ok(None) when they
+//  aren't or the result isn't worth folding, and an error when it doesn't fit in a double.
+pub fn fold_binary(
+    left: &Latex,
+    op: BinaryOperator,
+    right: &Latex,
+) -> Result<Option<Latex>, CompileErrorKind<'static>> {
+    let (l, r) = match (literal_value(left), literal_value(right)) {
+        (Some(l), Some(r)) => (l, r),
+        _ => return Ok(None),
+    };
+    let v = match op {
+        BinaryOperator::Add => l + r,
+        BinaryOperator::Subtract => l - r,
+        BinaryOperator::Multiply => l * r,
+        BinaryOperator::Divide => l / r,
+        BinaryOperator::Exponent => l.powf(r),
+    };
+    Ok(format_number(v)?.map(number_to_latex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: &str) -> Latex {
+        Latex::Num(n.to_string())
+    }
+
+    #[test]
+    fn formats_plain_decimals() {
+        assert_eq!(format_number(2.0), Ok(Some("2".to_string())));
+        assert_eq!(format_number(-0.0), Ok(Some("0".to_string())));
+        assert_eq!(format_number(-2.5), Ok(Some("-2.5".to_string())));
+        assert_eq!(format_number(0.00125), Ok(Some("0.00125".to_string())));
+        assert_eq!(
+            format_number(1e20),
+            Ok(Some("100000000000000000000".to_string()))
+        );
+        assert_eq!(format_number(1.0 / 3.0), Ok(None));
+        assert_eq!(
+            format_number(f64::INFINITY),
+            Err(CompileErrorKind::NonFiniteConstant)
+        );
+        assert_eq!(
+            format_number(f64::NAN),
+            Err(CompileErrorKind::NonFiniteConstant)
+        );
+    }
+
+    #[test]
+    fn folds_literals() {
+        assert_eq!(
+            fold_binary(&num("1.5"), BinaryOperator::Add, &num("2.5")),
+            Ok(Some(num("4")))
+        );
+        assert_eq!(
+            fold_binary(&num("1"), BinaryOperator::Subtract, &num("3")),
+            Ok(Some(Latex::UnaryExpression {
+                left: Box::new(num("2")),
+                operator: UnaryOperator::Negate,
+            }))
+        );
+        assert_eq!(
+            fold_binary(&num("0.1"), BinaryOperator::Add, &num("0.2")),
+            Ok(None)
+        );
+        assert_eq!(
+            fold_binary(
+                &num("1"),
+                BinaryOperator::Add,
+                &Latex::Variable("a".to_string())
+            ),
+            Ok(None)
+        );
+        assert_eq!(
+            fold_binary(
+                &Latex::Paren(Box::new(num("3"))),
+                BinaryOperator::Multiply,
+                &num("2")
+            ),
+            Ok(Some(num("6")))
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::core::latex::{CallKind, Latex, UnaryOperator};
+use std::{collections::HashSet, convert::TryFrom};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LintWarning {
+    UnusedFunction(String),
+    CallDepthExceeded { depth: usize, max: usize },
+}
+
+// Reports user functions that are defined in the program but never called from any
+//  statement. Other calls are ignored since they can't refer to a user definition.
+pub fn unused_functions(program: &[Latex]) -> Vec<LintWarning> {
+    let mut called = HashSet::new();
+    for stmt in program {
+        stmt.walk(&mut |node| {
+            if let Latex::Call {
+                func,
+                kind: CallKind::User,
+                ..
+            } = node
+            {
+                called.insert(func.clone());
+            }
+        });
+    }
+
+    program
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Latex::FuncDef { name, .. } if !called.contains(name) => {
+                Some(LintWarning::UnusedFunction(name.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// The deepest chain of nested calls in l, so f(g(1)) has a depth of 2
+pub fn call_depth(l: &Latex) -> usize {
+    let mut deepest = 0;
+    l.for_each_child(|c| deepest = deepest.max(call_depth(c)));
+    match l {
+        Latex::Call { .. } => deepest + 1,
+        _ => deepest,
+    }
+}
+
+// The number of nodes in l, as a rough measure of how large its output is
+pub fn node_count(l: &Latex) -> usize {
+    let mut count = 0;
+    l.walk(&mut |_| count += 1);
+    count
+}
+
+// Warns about statements whose calls nest deeper than max. Desmos gets slow with deeply
+//  nested expressions, so this is meant as a hint rather than a hard limit.
+pub fn check_call_depth(program: &[Latex], max: usize) -> Vec<LintWarning> {
+    program
+        .iter()
+        .map(call_depth)
+        .filter(|depth| *depth > max)
+        .map(|depth| LintWarning::CallDepthExceeded { depth, max })
+        .collect()
+}
+
+// An integer written directly in the source, possibly negated
+pub fn literal_int(l: &Latex) -> Option<i64> {
+    match l {
+        Latex::Num(n) => n.parse().ok(),
+        Latex::UnaryExpression {
+            left,
+            operator: UnaryOperator::Negate,
+        } => literal_int(left)?.checked_neg(),
+        Latex::Paren(inner) => literal_int(inner),
+        _ => None,
+    }
+}
+
+// The number of items in l, if it is a list whose length is known without evaluating it.
+//  That is list literals and ranges between integer literals, which desmos counts
+//  down when the end is below the start.
+pub fn list_len(l: &Latex) -> Option<usize> {
+    match l {
+        Latex::List(items) => Some(items.len()),
+        Latex::Range { start, end } => {
+            let (start, end) = (literal_int(start)?, literal_int(end)?);
+            let gap = end.checked_sub(start)?.unsigned_abs();
+            usize::try_from(gap).ok()?.checked_add(1)
+        }
+        Latex::Paren(inner) => list_len(inner),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::latex::BinaryOperator;
+
+    fn funcdef(name: &str, body: Latex) -> Latex {
+        Latex::FuncDef {
+            name: name.to_string(),
+            args: vec!["a".to_string()],
+            body: Box::new(body),
+        }
+    }
+
+    fn call(func: &str, is_builtin: bool) -> Latex {
+        call_with(func, is_builtin, Latex::Num("1".to_string()))
+    }
+
+    fn call_with(func: &str, is_builtin: bool, arg: Latex) -> Latex {
+        Latex::Call {
+            func: func.to_string(),
+            kind: if is_builtin {
+                CallKind::Builtin
+            } else {
+                CallKind::User
+            },
+            args: vec![arg],
+        }
+    }
+
+    #[test]
+    fn reports_unused() {
+        let program = vec![
+            funcdef("f", Latex::Variable("a".to_string())),
+            funcdef("g", Latex::Variable("a".to_string())),
+            call("f", false),
+        ];
+        assert_eq!(
+            unused_functions(&program),
+            vec![LintWarning::UnusedFunction("g".to_string())]
+        );
+    }
+
+    #[test]
+    fn nested_calls_count() {
+        let program = vec![
+            funcdef("f", Latex::Variable("a".to_string())),
+            funcdef("g", call("f", false)),
+            Latex::BinaryExpression {
+                left: Box::new(Latex::Num("2".to_string())),
+                operator: BinaryOperator::Add,
+                right: Box::new(call("g", false)),
+            },
+        ];
+        assert_eq!(unused_functions(&program), vec![]);
+    }
+
+    #[test]
+    fn builtin_calls_ignored() {
+        let program = vec![
+            funcdef("sin", Latex::Variable("a".to_string())),
+            call("sin", true),
+        ];
+        assert_eq!(
+            unused_functions(&program),
+            vec![LintWarning::UnusedFunction("sin".to_string())]
+        );
+    }
+
+    #[test]
+    fn depth() {
+        assert_eq!(call_depth(&Latex::Num("1".to_string())), 0);
+        assert_eq!(call_depth(&call("sin", true)), 1);
+
+        // sin(cos(1)) + f(1)
+        let tree = Latex::BinaryExpression {
+            left: Box::new(call_with("sin", true, call("cos", true))),
+            operator: BinaryOperator::Add,
+            right: Box::new(call("f", false)),
+        };
+        assert_eq!(call_depth(&tree), 2);
+
+        // f(a) = sin(cos(tan(1)))
+        let def = funcdef(
+            "f",
+            call_with(
+                "sin",
+                true,
+                call_with(
+                    "cos",
+                    true,
+                    call_with("tan", true, Latex::Num("1".to_string())),
+                ),
+            ),
+        );
+        assert_eq!(call_depth(&def), 3);
+    }
+
+    #[test]
+    fn known_list_len() {
+        let num = |n: &str| Latex::Num(n.to_string());
+        let range = |start, end| Latex::Range {
+            start: Box::new(start),
+            end: Box::new(end),
+        };
+        assert_eq!(
+            list_len(&Latex::List(vec![num("1"), num("2"), num("3")])),
+            Some(3)
+        );
+        assert_eq!(list_len(&range(num("1"), num("10"))), Some(10));
+        assert_eq!(list_len(&range(num("10"), num("1"))), Some(10));
+        let negated = Latex::UnaryExpression {
+            left: Box::new(num("2")),
+            operator: UnaryOperator::Negate,
+        };
+        assert_eq!(list_len(&range(negated, num("2"))), Some(5));
+        // Too long to count
+        let max = i64::MAX.to_string();
+        let negated_max = Latex::UnaryExpression {
+            left: Box::new(num(&max)),
+            operator: UnaryOperator::Negate,
+        };
+        assert_eq!(list_len(&range(negated_max, num(&max))), None);
+
+        assert_eq!(
+            list_len(&range(num("1"), Latex::Variable("a".to_string()))),
+            None
+        );
+        assert_eq!(list_len(&range(num("1"), num("2.5"))), None);
+        assert_eq!(list_len(&num("1")), None);
+    }
+
+    #[test]
+    fn count_nodes() {
+        assert_eq!(node_count(&Latex::Num("1".to_string())), 1);
+        // f(a) = sin(1)
+        assert_eq!(node_count(&funcdef("f", call("sin", true))), 3);
+    }
+
+    #[test]
+    fn depth_threshold() {
+        let program = vec![
+            call("f", false),
+            call_with("sin", true, call_with("cos", true, call("tan", true))),
+        ];
+        assert_eq!(check_call_depth(&program, 3), vec![]);
+        assert_eq!(
+            check_call_depth(&program, 2),
+            vec![LintWarning::CallDepthExceeded { depth: 3, max: 2 }]
+        );
+    }
+}
@@ -1,3 +1,8 @@
 pub mod builtins;
+#[allow(clippy::module_inception)]
 pub mod compiler;
 pub mod error;
+pub mod fold;
+pub mod lint;
+pub mod source_map;
+pub mod transform;
@@ -0,0 +1,95 @@
+use crate::core::latex::{Latex, LatexFormat, LatexWriter};
+use pest::Span;
+use std::ops::Range;
+
+// Where parts of the output came from, as ranges of the output and the spans of the
+//  source that they were compiled from
+#[derive(Debug, PartialEq)]
+pub struct SourceMap<'a> {
+    // Children come before their parents, so the first range containing an offset is
+    //  the innermost one
+    pub entries: Vec<(Range<usize>, Span<'a>)>,
+}
+
+impl<'a> SourceMap<'a> {
+    // The span of the innermost node written at offset in the output
+    pub fn span_at(&self, offset: usize) -> Option<&Span<'a>> {
+        self.entries
+            .iter()
+            .find(|(range, _)| range.contains(&offset))
+            .map(|(_, span)| span)
+    }
+
+    // Adds the entries of another map, whose output starts at offset in this one
+    pub fn append(&mut self, other: SourceMap<'a>, offset: usize) {
+        self.entries.extend(
+            other
+                .entries
+                .into_iter()
+                .map(|(range, span)| (range.start + offset..range.end + offset, span)),
+        );
+    }
+}
+
+// Writes l and maps its nodes back to the spans in Context::source_spans. Nodes don't
+//  keep their spans, so each one is matched with the first unused recorded expression
+//  that compiled to the same latex. Both are in source order, so this lines up except
+//  where values were copied, like let bindings, where the first copy gets the span.
+pub fn build<'a>(
+    l: &Latex,
+    mut spans: Vec<(Span<'a>, Latex)>,
+    format: &LatexFormat,
+) -> (String, SourceMap<'a>) {
+    let mut out = String::new();
+    let mut entries = vec![];
+    let mut record = |node: &Latex, range: Range<usize>| {
+        if let Some(i) = spans.iter().position(|(_, recorded)| recorded == node) {
+            entries.push((range, spans.remove(i).0));
+        }
+    };
+    LatexWriter::new(&mut out, format)
+        .on_node(&mut record)
+        .write(l)
+        .expect("writing to a string can't fail");
+    (out, SourceMap { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::latex::CallKind;
+
+    #[test]
+    fn matches_in_order() {
+        let src = "f(1) + 1";
+        let one = || Latex::Num("1".to_string());
+        let call = Latex::Call {
+            func: "f".to_string(),
+            kind: CallKind::User,
+            args: vec![one()],
+        };
+        let sum = Latex::BinaryExpression {
+            left: Box::new(call.clone()),
+            operator: crate::core::latex::BinaryOperator::Add,
+            right: Box::new(one()),
+        };
+        let span = |start, end| Span::new(src, start, end).unwrap();
+        let spans = vec![
+            (span(2, 3), one()),
+            (span(0, 4), call),
+            (span(7, 8), one()),
+            (span(0, 8), sum.clone()),
+        ];
+        let (out, map) = build(&sum, spans, &LatexFormat::default());
+        assert_eq!(out, "f\\left(1\\right)+1");
+        let at = |offset| map.span_at(offset).map(|s| s.as_str());
+        assert_eq!(at(0), Some("f(1)"));
+        // The argument is the 1 inside the call, and the last 1 is the one after +
+        let arg = out.find('1').unwrap();
+        assert_eq!(at(arg), Some("1"));
+        assert_eq!(map.span_at(arg).unwrap().start(), 2);
+        assert_eq!(map.span_at(out.len() - 1).unwrap().start(), 7);
+        assert_eq!(at(out.len() - 2), Some("f(1) + 1"));
+        assert_eq!(at(out.len()), None);
+    }
+}
@@ -0,0 +1,459 @@
+use crate::core::latex::{CallKind, Latex};
+use std::collections::HashMap;
+
+// Renames every variable and user function in l with f, including function parameters
+//  and the variables of comprehensions, sums and products so that references to them
+//  stay consistent. Builtins are left alone. Names are still identifiers, so they are
+//  formatted as usual when emitted (renaming a to a1 gives a_{1}).
+pub fn rename_variables<F: Fn(&str) -> String>(l: Latex, f: F) -> Latex {
+    rename(l, &f)
+}
+
+fn rename<F: Fn(&str) -> String>(l: Latex, f: &F) -> Latex {
+    let l = match l {
+        Latex::Variable(v) => Latex::Variable(f(&v)),
+        Latex::Call {
+            func,
+            kind: CallKind::User,
+            args,
+        } => Latex::Call {
+            func: f(&func),
+            kind: CallKind::User,
+            args,
+        },
+        Latex::FuncDef { name, args, body } => Latex::FuncDef {
+            name: f(&name),
+            args: args.iter().map(|a| f(a)).collect(),
+            body,
+        },
+        Latex::Comprehension {
+            body,
+            var,
+            list,
+            second,
+        } => Latex::Comprehension {
+            body,
+            var: f(&var),
+            list,
+            second: second.map(|(var2, list2)| (f(&var2), list2)),
+        },
+        Latex::Sum {
+            var,
+            lower,
+            upper,
+            body,
+        } => Latex::Sum {
+            var: f(&var),
+            lower,
+            upper,
+            body,
+        },
+        Latex::Product {
+            var,
+            lower,
+            upper,
+            body,
+        } => Latex::Product {
+            var: f(&var),
+            lower,
+            upper,
+            body,
+        },
+        other => other,
+    };
+    l.map_children(|c| rename(c, f))
+}
+
+fn uses_variable(l: &Latex, name: &str) -> bool {
+    let mut found = false;
+    l.walk(&mut |node| found |= matches!(node, Latex::Variable(v) if v == name));
+    found
+}
+
+// The variables l uses without binding them itself, in the order they appear. A name can
+//  appear more than once.
+pub fn free_variables(l: &Latex) -> Vec<String> {
+    let mut free = vec![];
+    collect_free(l, &mut vec![], &mut free);
+    free
+}
+
+fn collect_free(l: &Latex, bound: &mut Vec<String>, free: &mut Vec<String>) {
+    match l {
+        Latex::Variable(v) if !bound.contains(v) => free.push(v.clone()),
+        Latex::Comprehension {
+            body,
+            var,
+            list,
+            second,
+        } => {
+            let mut outside = vec![list.as_ref()];
+            let mut vars = vec![var];
+            if let Some((var2, list2)) = second {
+                outside.push(list2);
+                vars.push(var2);
+            }
+            collect_free_in_scope(&outside, vars, body, bound, free)
+        }
+        Latex::Sum {
+            var,
+            lower,
+            upper,
+            body,
+        }
+        | Latex::Product {
+            var,
+            lower,
+            upper,
+            body,
+        } => collect_free_in_scope(&[lower, upper], vec![var], body, bound, free),
+        Latex::FuncDef { args, body, .. } => {
+            collect_free_in_scope(&[], args.iter().collect(), body, bound, free)
+        }
+        _ => l.for_each_child(|c| collect_free(c, bound, free)),
+    }
+}
+
+// Collects the free variables of the children that are outside the scope of vars, and
+//  then those of body with vars bound
+fn collect_free_in_scope(
+    outside: &[&Latex],
+    vars: Vec<&String>,
+    body: &Latex,
+    bound: &mut Vec<String>,
+    free: &mut Vec<String>,
+) {
+    for c in outside {
+        collect_free(c, bound, free);
+    }
+    let len = bound.len();
+    bound.extend(vars.into_iter().cloned());
+    collect_free(body, bound, free);
+    bound.truncate(len);
+}
+
+// A variable named base, or base1, base2, ... if that is used in l, for binding a new
+//  variable around l without capturing any of its own
+pub fn fresh_variable(l: &Latex, base: &str) -> String {
+    std::iter::once(base.to_string())
+        .chain((1..).map(|n| format!("{}{}", base, n)))
+        .find(|name| !uses_variable(l, name))
+        .unwrap()
+}
+
+// Brings var into scope for body, removing any substitution for it since it shadows
+//  them. If one of the remaining values uses a variable named var, the value would be
+//  captured, so var is renamed to something unused. Returns the name to bind.
+fn bind(substitutions: &mut HashMap<String, Latex>, body: &Latex, var: String) -> String {
+    substitutions.remove(&var);
+    if !substitutions.values().any(|v| uses_variable(v, &var)) {
+        return var;
+    }
+    let fresh = (1..)
+        .map(|n| format!("{}{}", var, n))
+        .find(|name| {
+            !uses_variable(body, name) && !substitutions.values().any(|v| uses_variable(v, name))
+        })
+        .unwrap();
+    substitutions.insert(var, Latex::Variable(fresh.clone()));
+    fresh
+}
+
+// Replaces each variable named in substitutions with its value, as when inlining a call
+//  to a function by substituting the arguments for its parameters
+pub fn substitute(l: Latex, substitutions: &HashMap<String, Latex>) -> Latex {
+    match l {
+        Latex::Variable(v) => match substitutions.get(&v) {
+            Some(value) => value.clone(),
+            None => Latex::Variable(v),
+        },
+        // The lists are outside the scope of the comprehension variables, but the body isn't
+        Latex::Comprehension {
+            body,
+            var,
+            list,
+            second,
+        } => {
+            let list = Box::new(substitute(*list, substitutions));
+            let mut inner = substitutions.clone();
+            let var = bind(&mut inner, &body, var);
+            let second = second.map(|(var2, list2)| {
+                let list2 = Box::new(substitute(*list2, substitutions));
+                (bind(&mut inner, &body, var2), list2)
+            });
+            Latex::Comprehension {
+                body: Box::new(substitute(*body, &inner)),
+                var,
+                list,
+                second,
+            }
+        }
+        // Likewise the bounds of a sum or product are outside the scope of its variable
+        Latex::Sum {
+            var,
+            lower,
+            upper,
+            body,
+        } => {
+            let (var, lower, upper, body) =
+                substitute_big_operator(var, *lower, *upper, *body, substitutions);
+            Latex::Sum {
+                var,
+                lower,
+                upper,
+                body,
+            }
+        }
+        Latex::Product {
+            var,
+            lower,
+            upper,
+            body,
+        } => {
+            let (var, lower, upper, body) =
+                substitute_big_operator(var, *lower, *upper, *body, substitutions);
+            Latex::Product {
+                var,
+                lower,
+                upper,
+                body,
+            }
+        }
+        other => other.map_children(|c| substitute(c, substitutions)),
+    }
+}
+
+type BigOperatorParts = (String, Box<Latex>, Box<Latex>, Box<Latex>);
+
+fn substitute_big_operator(
+    var: String,
+    lower: Latex,
+    upper: Latex,
+    body: Latex,
+    substitutions: &HashMap<String, Latex>,
+) -> BigOperatorParts {
+    let lower = Box::new(substitute(lower, substitutions));
+    let upper = Box::new(substitute(upper, substitutions));
+    let mut inner = substitutions.clone();
+    let var = bind(&mut inner, &body, var);
+    (var, lower, upper, Box::new(substitute(body, &inner)))
+}
+
+// Removes every parenthesis that was written in the source. The precedence rules add
+//  back the ones that are needed when l is written, so this only shortens the output.
+pub fn strip_parens(l: Latex) -> Latex {
+    match l {
+        Latex::Paren(inner) => strip_parens(*inner),
+        other => other.map_children(strip_parens),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::latex::{latex_to_str, BinaryOperator};
+
+    fn var(v: &str) -> Latex {
+        Latex::Variable(v.to_string())
+    }
+
+    #[test]
+    fn rename_nested() {
+        // g(a) = f(a + sin(a))
+        let def = Latex::FuncDef {
+            name: "g".to_string(),
+            args: vec!["a".to_string()],
+            body: Box::new(Latex::Call {
+                func: "f".to_string(),
+                kind: CallKind::User,
+                args: vec![Latex::BinaryExpression {
+                    left: Box::new(var("a")),
+                    operator: BinaryOperator::Add,
+                    right: Box::new(Latex::Call {
+                        func: "sin".to_string(),
+                        kind: CallKind::Builtin,
+                        args: vec![var("a")],
+                    }),
+                }],
+            }),
+        };
+        let renamed = rename_variables(def, |name| format!("{}1", name));
+        assert_eq!(
+            latex_to_str(renamed),
+            "g1\\left(a_{1}\\right)=f1\\left(a_{1}+\\sin\\left(a_{1}\\right)\\right)"
+        );
+    }
+
+    fn substitutions(pairs: Vec<(&str, Latex)>) -> HashMap<String, Latex> {
+        pairs
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    #[test]
+    fn substitute_variables() {
+        // a * b with a = 2 + b
+        let l = Latex::BinaryExpression {
+            left: Box::new(var("a")),
+            operator: BinaryOperator::Multiply,
+            right: Box::new(var("b")),
+        };
+        let sum = Latex::BinaryExpression {
+            left: Box::new(Latex::Num("2".to_string())),
+            operator: BinaryOperator::Add,
+            right: Box::new(var("b")),
+        };
+        assert_eq!(
+            latex_to_str(substitute(l, &substitutions(vec![("a", sum)]))),
+            "\\left(2+b\\right)b"
+        );
+    }
+
+    #[test]
+    fn substitute_avoids_capture() {
+        // [a + i for i=[1, a]]
+        let comprehension = || Latex::Comprehension {
+            body: Box::new(Latex::BinaryExpression {
+                left: Box::new(var("a")),
+                operator: BinaryOperator::Add,
+                right: Box::new(var("i")),
+            }),
+            var: "i".to_string(),
+            list: Box::new(Latex::List(vec![Latex::Num("1".to_string()), var("a")])),
+            second: None,
+        };
+        // Substituting i for a would make it refer to the comprehension variable
+        assert_eq!(
+            latex_to_str(substitute(
+                comprehension(),
+                &substitutions(vec![("a", var("i"))])
+            )),
+            "\\left[i+i_{1}\\operatorname{for}i_{1}=\\left[1,i\\right]\\right]"
+        );
+        // The comprehension variable shadows a substitution with its name
+        assert_eq!(
+            latex_to_str(substitute(
+                comprehension(),
+                &substitutions(vec![("i", Latex::Num("5".to_string()))])
+            )),
+            "\\left[a+i\\operatorname{for}i=\\left[1,a\\right]\\right]"
+        );
+    }
+
+    #[test]
+    fn substitute_sum() {
+        // \sum_{j=1}^{a}j*k
+        let sum = || Latex::Sum {
+            var: "j".to_string(),
+            lower: Box::new(Latex::Num("1".to_string())),
+            upper: Box::new(var("a")),
+            body: Box::new(Latex::BinaryExpression {
+                left: Box::new(var("j")),
+                operator: BinaryOperator::Multiply,
+                right: Box::new(var("k")),
+            }),
+        };
+        // The sum variable shadows a substitution with its name, but the bounds don't
+        assert_eq!(
+            latex_to_str(substitute(
+                sum(),
+                &substitutions(vec![
+                    ("j", Latex::Num("7".to_string())),
+                    ("a", Latex::Num("3".to_string()))
+                ])
+            )),
+            "\\sum_{j=1}^{3}jk"
+        );
+        // Substituting j for k would make it refer to the sum variable
+        assert_eq!(
+            latex_to_str(substitute(sum(), &substitutions(vec![("k", var("j"))]))),
+            "\\sum_{j_{1}=1}^{a}j_{1}j"
+        );
+        let product = Latex::Product {
+            var: "j".to_string(),
+            lower: Box::new(Latex::Num("1".to_string())),
+            upper: Box::new(var("a")),
+            body: Box::new(var("j")),
+        };
+        assert_eq!(
+            latex_to_str(substitute(
+                product,
+                &substitutions(vec![("j", Latex::Num("7".to_string()))])
+            )),
+            "\\prod_{j=1}^{a}j"
+        );
+    }
+
+    #[test]
+    fn free_variables_of_binders() {
+        // [a + i for i=[1...i]]
+        let comprehension = Latex::Comprehension {
+            body: Box::new(Latex::BinaryExpression {
+                left: Box::new(var("a")),
+                operator: BinaryOperator::Add,
+                right: Box::new(var("i")),
+            }),
+            var: "i".to_string(),
+            list: Box::new(Latex::Range {
+                start: Box::new(Latex::Num("1".to_string())),
+                end: Box::new(var("i")),
+            }),
+            second: None,
+        };
+        assert_eq!(free_variables(&comprehension), vec!["i", "a"]);
+        // \sum_{j=1}^{j}jk
+        let sum = Latex::Sum {
+            var: "j".to_string(),
+            lower: Box::new(Latex::Num("1".to_string())),
+            upper: Box::new(var("j")),
+            body: Box::new(Latex::BinaryExpression {
+                left: Box::new(var("j")),
+                operator: BinaryOperator::Multiply,
+                right: Box::new(var("k")),
+            }),
+        };
+        assert_eq!(free_variables(&sum), vec!["j", "k"]);
+    }
+
+    #[test]
+    fn rename_comprehension() {
+        let l = Latex::Comprehension {
+            body: Box::new(var("a")),
+            var: "a".to_string(),
+            list: Box::new(Latex::List(vec![var("b")])),
+            second: None,
+        };
+        assert_eq!(
+            rename_variables(l, |name| name.to_uppercase()),
+            Latex::Comprehension {
+                body: Box::new(var("A")),
+                var: "A".to_string(),
+                list: Box::new(Latex::List(vec![var("B")])),
+                second: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parens_stripped() {
+        let paren = |l| Latex::Paren(Box::new(l));
+        let sum = Latex::BinaryExpression {
+            left: Box::new(var("a")),
+            operator: BinaryOperator::Add,
+            right: Box::new(paren(var("b"))),
+        };
+        // (a+(b))*((c))
+        let l = Latex::BinaryExpression {
+            left: Box::new(paren(sum)),
+            operator: BinaryOperator::Multiply,
+            right: Box::new(paren(paren(var("c")))),
+        };
+        assert_eq!(
+            latex_to_str(l.clone()),
+            "\\left(a+\\left(b\\right)\\right)\\left(\\left(c\\right)\\right)"
+        );
+        // The parentheses that are needed come back
+        assert_eq!(latex_to_str(strip_parens(l)), "\\left(a+b\\right)c");
+    }
+}
@@ -8,18 +8,31 @@ pub enum BinaryOperator {
     Multiply,
     Divide,
     Mod,
+    Exponent,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum UnaryOperator {
     Factorial,
+    // x!! = x(x-2)(x-4)..., down to 1 or 2
+    DoubleFactorial,
+    Negate,
+    // +x, which is just x
+    Plus,
+    // x%, which is x/100
+    Percent,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LogicalOperator {
+    And,
+    Or,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Branch<'a> {
-    pub cond_left: LocatedExpression<'a>,
-    pub cond: CompareOperator,
-    pub cond_right: LocatedExpression<'a>,
+    // A comparison, or comparisons joined with and/or
+    pub cond: LocatedExpression<'a>,
     pub val: LocatedExpression<'a>,
 }
 
@@ -27,6 +40,7 @@ pub struct Branch<'a> {
 pub enum CallModifier {
     MapCall,
     NormalCall,
+    MacroCall,
 }
 
 // Expression is a component of a statement
@@ -54,9 +68,78 @@ pub enum Expression<'a> {
     Piecewise {
         first: Box<Branch<'a>>,
         rest: Vec<Branch<'a>>,
-        default: Box<LocatedExpression<'a>>,
+        // Desmos leaves the piecewise undefined when no branch matches and there is no default
+        default: Option<Box<LocatedExpression<'a>>>,
     },
     MapExpression(Box<LocatedExpression<'a>>),
+    // [start...end]
+    Range {
+        start: Box<LocatedExpression<'a>>,
+        end: Box<LocatedExpression<'a>>,
+    },
+    // [body for var=list], optionally with a second variable
+    Comprehension {
+        body: Box<LocatedExpression<'a>>,
+        var: &'a str,
+        list: Box<LocatedExpression<'a>>,
+        second: Option<(&'a str, Box<LocatedExpression<'a>>)>,
+    },
+    // (x, y)
+    Point(Box<LocatedExpression<'a>>, Box<LocatedExpression<'a>>),
+    // Evaluates to a Bool
+    Comparison {
+        left: Box<LocatedExpression<'a>>,
+        op: CompareOperator,
+        right: Box<LocatedExpression<'a>>,
+    },
+    // a < 1 and b > 2, only allowed as a condition
+    Logical {
+        left: Box<LocatedExpression<'a>>,
+        op: LogicalOperator,
+        right: Box<LocatedExpression<'a>>,
+    },
+    If {
+        cond: Box<LocatedExpression<'a>>,
+        then: Box<LocatedExpression<'a>>,
+        else_: Box<LocatedExpression<'a>>,
+    },
+    // Parentheses written by the user, kept so they show up in the output
+    Paren(Box<LocatedExpression<'a>>),
+    // |x|
+    Abs(Box<LocatedExpression<'a>>),
+    // {a > 0}, a condition used as a number
+    Indicator(Box<LocatedExpression<'a>>),
+    // x^2 {x > 0}, which is value where the condition holds and undefined elsewhere
+    Restriction {
+        value: Box<LocatedExpression<'a>>,
+        cond: Box<LocatedExpression<'a>>,
+    },
+    // let name = value in body
+    Let {
+        name: &'a str,
+        value: Box<LocatedExpression<'a>>,
+        body: Box<LocatedExpression<'a>>,
+    },
+    // Anonymous function, only valid as a macro argument
+    Lambda {
+        param: &'a str,
+        body: Box<LocatedExpression<'a>>,
+    },
+    // var in list, only valid as a macro argument
+    Binding {
+        var: &'a str,
+        list: Box<LocatedExpression<'a>>,
+    },
+    // list[index], which is a list itself when the index is
+    Index {
+        list: Box<LocatedExpression<'a>>,
+        index: Box<LocatedExpression<'a>>,
+    },
+    // value |> f |> g, which is g(f(value))
+    Pipe {
+        value: Box<LocatedExpression<'a>>,
+        funcs: Vec<(Span<'a>, &'a str)>,
+    },
 }
 
 pub type LocatedExpression<'a> = (Span<'a>, Expression<'a>);
@@ -66,13 +149,75 @@ pub struct FunctionDefinition<'a> {
     pub name: &'a str,
     pub args: Vec<(&'a str, ValType)>,
     pub ret_annotation: Option<ValType>,
+    // Values for the last defaults.len() args, used when a call leaves them out
+    pub defaults: Vec<LocatedExpression<'a>>,
+    // Declared with inline, so calls are always replaced with the body and the definition
+    //  itself is left out of the program
+    pub inline: bool,
 }
 
 // A statement is a part of a program
 #[derive(Clone, Debug, PartialEq)]
 pub enum Statement<'a> {
     FuncDef(FunctionDefinition<'a>, LocatedExpression<'a>),
+    // a = 1
+    VarDef(&'a str, LocatedExpression<'a>),
     Expression(Expression<'a>),
 }
 
 pub type LocatedStatement<'a> = (Span<'a>, Statement<'a>);
+
+// A value and the span of source it came from. This converts to and from the (span,
+//  value) tuples used by the rest of the AST, and is harder to build the wrong way around
+//  when writing a tree by hand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Located<'a, T> {
+    pub span: Span<'a>,
+    pub value: T,
+}
+
+impl<'a, T> Located<'a, T> {
+    pub fn new(span: Span<'a>, value: T) -> Self {
+        Self { span, value }
+    }
+}
+
+impl<'a, T> From<(Span<'a>, T)> for Located<'a, T> {
+    fn from((span, value): (Span<'a>, T)) -> Self {
+        Self { span, value }
+    }
+}
+
+impl<'a, T> From<Located<'a, T>> for (Span<'a>, T) {
+    fn from(l: Located<'a, T>) -> Self {
+        (l.span, l.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn located_tuples() {
+        let src = "1 + 2";
+        let one = Located::new(Span::new(src, 0, 1).unwrap(), Expression::Num("1"));
+        let two = Located::new(Span::new(src, 4, 5).unwrap(), Expression::Num("2"));
+        let sum: LocatedExpression = Located::new(
+            Span::new(src, 0, 5).unwrap(),
+            Expression::BinaryExpr {
+                left: Box::new(one.clone().into()),
+                operator: BinaryOperator::Add,
+                right: Box::new(two.into()),
+            },
+        )
+        .into();
+        assert_eq!(sum.0.as_str(), "1 + 2");
+        match &sum.1 {
+            Expression::BinaryExpr { left, .. } => {
+                assert_eq!(Located::from((**left).clone()), one)
+            }
+            e => panic!("Expected a binary expression, got {:?}", e),
+        }
+    }
+}
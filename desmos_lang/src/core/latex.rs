@@ -1,14 +1,19 @@
+use std::fmt::{self, Write};
+use std::ops::Range;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BinaryOperator {
     Add,
     Subtract,
     Multiply,
     Divide,
+    Exponent,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum UnaryOperator {
     Factorial,
+    Negate,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -20,6 +25,17 @@ pub enum CompareOperator {
     LessThanEqual,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CallKind {
+    // Defined in the program, emitted as f\left(...\right)
+    User,
+    // Emitted as a latex command, \sin\left(...\right)
+    Builtin,
+    // Emitted as \operatorname{f}\left(...\right), for builtins that don't have a command
+    //  and functions not known to the compiler
+    OperatorName,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Cond {
     pub left: Latex,
@@ -32,9 +48,14 @@ pub struct Cond {
 pub enum Latex {
     Variable(String),
     Num(String),
+    // mantissa*10^exponent, from a literal like 1.5e3
+    Scientific {
+        mantissa: String,
+        exponent: String,
+    },
     Call {
         func: String,
-        is_builtin: bool,
+        kind: CallKind,
         args: Vec<Latex>,
     },
     BinaryExpression {
@@ -56,8 +77,242 @@ pub enum Latex {
     Piecewise {
         first: Box<Cond>,
         rest: Vec<Cond>,
-        default: Box<Latex>,
+        default: Option<Box<Latex>>,
     },
+    Comparison {
+        left: Box<Latex>,
+        op: CompareOperator,
+        right: Box<Latex>,
+    },
+    // [body for var=list], or [body for var=list,var2=list2] with a second variable
+    Comprehension {
+        body: Box<Latex>,
+        var: String,
+        list: Box<Latex>,
+        second: Option<(String, Box<Latex>)>,
+    },
+    // (x,y)
+    Point(Box<Latex>, Box<Latex>),
+    Floor(Box<Latex>),
+    // |x|
+    Abs(Box<Latex>),
+    // \left\{a>0\right\}, which is 1 where the comparison inside holds and undefined
+    //  elsewhere
+    Indicator(Box<Latex>),
+    // [start...end]
+    Range {
+        start: Box<Latex>,
+        end: Box<Latex>,
+    },
+    // list\left[index\right]
+    Index {
+        list: Box<Latex>,
+        index: Box<Latex>,
+    },
+    // Always wrapped in parentheses, regardless of precedence
+    Paren(Box<Latex>),
+    // Written out exactly, such as \pi for the constant pi
+    Constant(String),
+    // \sum_{var=lower}^{upper}body
+    Sum {
+        var: String,
+        lower: Box<Latex>,
+        upper: Box<Latex>,
+        body: Box<Latex>,
+    },
+    // \prod_{var=lower}^{upper}body
+    Product {
+        var: String,
+        lower: Box<Latex>,
+        upper: Box<Latex>,
+        body: Box<Latex>,
+    },
+}
+
+impl Latex {
+    // Calls f on each direct child of this node
+    pub fn for_each_child<F: FnMut(&Latex)>(&self, mut f: F) {
+        match self {
+            Latex::Variable(_) | Latex::Num(_) | Latex::Scientific { .. } | Latex::Constant(_) => {}
+            Latex::Call { args, .. } => args.iter().for_each(f),
+            Latex::BinaryExpression { left, right, .. } => {
+                f(left);
+                f(right);
+            }
+            Latex::UnaryExpression { left, .. } => f(left),
+            Latex::List(items) => items.iter().for_each(f),
+            Latex::Assignment(left, right) => {
+                f(left);
+                f(right);
+            }
+            Latex::FuncDef { body, .. } => f(body),
+            Latex::Piecewise {
+                first,
+                rest,
+                default,
+            } => {
+                for cond in std::iter::once(first.as_ref()).chain(rest.iter()) {
+                    f(&cond.left);
+                    f(&cond.right);
+                    f(&cond.result);
+                }
+                if let Some(default) = default {
+                    f(default);
+                }
+            }
+            Latex::Comparison { left, right, .. } => {
+                f(left);
+                f(right);
+            }
+            Latex::Comprehension {
+                body, list, second, ..
+            } => {
+                f(body);
+                f(list);
+                if let Some((_, list2)) = second {
+                    f(list2);
+                }
+            }
+            Latex::Point(x, y) => {
+                f(x);
+                f(y);
+            }
+            Latex::Floor(inner)
+            | Latex::Abs(inner)
+            | Latex::Indicator(inner)
+            | Latex::Paren(inner) => f(inner),
+            Latex::Range { start, end } => {
+                f(start);
+                f(end);
+            }
+            Latex::Index { list, index } => {
+                f(list);
+                f(index);
+            }
+            Latex::Sum {
+                lower, upper, body, ..
+            }
+            | Latex::Product {
+                lower, upper, body, ..
+            } => {
+                f(lower);
+                f(upper);
+                f(body);
+            }
+        }
+    }
+
+    // Rebuilds this node with each direct child replaced by f(child)
+    pub fn map_children<F: FnMut(Latex) -> Latex>(self, mut f: F) -> Latex {
+        match self {
+            Latex::Variable(_) | Latex::Num(_) | Latex::Scientific { .. } | Latex::Constant(_) => {
+                self
+            }
+            Latex::Call { func, kind, args } => Latex::Call {
+                func,
+                kind,
+                args: args.into_iter().map(&mut f).collect(),
+            },
+            Latex::BinaryExpression {
+                left,
+                operator,
+                right,
+            } => Latex::BinaryExpression {
+                left: Box::new(f(*left)),
+                operator,
+                right: Box::new(f(*right)),
+            },
+            Latex::UnaryExpression { left, operator } => Latex::UnaryExpression {
+                left: Box::new(f(*left)),
+                operator,
+            },
+            Latex::List(items) => Latex::List(items.into_iter().map(&mut f).collect()),
+            Latex::Assignment(left, right) => {
+                Latex::Assignment(Box::new(f(*left)), Box::new(f(*right)))
+            }
+            Latex::FuncDef { name, args, body } => Latex::FuncDef {
+                name,
+                args,
+                body: Box::new(f(*body)),
+            },
+            Latex::Piecewise {
+                first,
+                rest,
+                default,
+            } => {
+                let mut map_cond = |c: Cond| Cond {
+                    left: f(c.left),
+                    op: c.op,
+                    right: f(c.right),
+                    result: f(c.result),
+                };
+                let first = Box::new(map_cond(*first));
+                let rest = rest.into_iter().map(&mut map_cond).collect();
+                Latex::Piecewise {
+                    first,
+                    rest,
+                    default: default.map(|d| Box::new(f(*d))),
+                }
+            }
+            Latex::Comparison { left, op, right } => Latex::Comparison {
+                left: Box::new(f(*left)),
+                op,
+                right: Box::new(f(*right)),
+            },
+            Latex::Comprehension {
+                body,
+                var,
+                list,
+                second,
+            } => Latex::Comprehension {
+                body: Box::new(f(*body)),
+                var,
+                list: Box::new(f(*list)),
+                second: second.map(|(var2, list2)| (var2, Box::new(f(*list2)))),
+            },
+            Latex::Point(x, y) => Latex::Point(Box::new(f(*x)), Box::new(f(*y))),
+            Latex::Floor(inner) => Latex::Floor(Box::new(f(*inner))),
+            Latex::Abs(inner) => Latex::Abs(Box::new(f(*inner))),
+            Latex::Indicator(inner) => Latex::Indicator(Box::new(f(*inner))),
+            Latex::Paren(inner) => Latex::Paren(Box::new(f(*inner))),
+            Latex::Range { start, end } => Latex::Range {
+                start: Box::new(f(*start)),
+                end: Box::new(f(*end)),
+            },
+            Latex::Index { list, index } => Latex::Index {
+                list: Box::new(f(*list)),
+                index: Box::new(f(*index)),
+            },
+            Latex::Sum {
+                var,
+                lower,
+                upper,
+                body,
+            } => Latex::Sum {
+                var,
+                lower: Box::new(f(*lower)),
+                upper: Box::new(f(*upper)),
+                body: Box::new(f(*body)),
+            },
+            Latex::Product {
+                var,
+                lower,
+                upper,
+                body,
+            } => Latex::Product {
+                var,
+                lower: Box::new(f(*lower)),
+                upper: Box::new(f(*upper)),
+                body: Box::new(f(*body)),
+            },
+        }
+    }
+
+    // Calls f on this node and then on every node below it, in pre-order
+    pub fn walk<F: FnMut(&Latex)>(&self, f: &mut F) {
+        f(self);
+        self.for_each_child(|c| c.walk(f));
+    }
 }
 
 pub fn format_latex_identifier(v: String) -> String {
@@ -77,22 +332,29 @@ pub fn format_latex_identifier(v: String) -> String {
     }
 }
 
+// Greek letters with a latex command of the same name. The capitals that look like latin
+//  letters, like Alpha, don't have one.
+const GREEK_LETTERS: &[&str] = &[
+    "alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa",
+    "lambda", "mu", "nu", "xi", "pi", "rho", "sigma", "tau", "upsilon", "phi", "chi", "psi",
+    "omega", "Gamma", "Delta", "Theta", "Lambda", "Xi", "Pi", "Sigma", "Upsilon", "Phi", "Psi",
+    "Omega",
+];
+
+// The latex command for name if it is a greek letter, so theta is \theta
+pub fn greek_letter(name: &str) -> Option<String> {
+    GREEK_LETTERS.contains(&name).then(|| format!("\\{}", name))
+}
+
 pub fn multi_latex_to_str(items: Vec<Latex>) -> Vec<String> {
     items.into_iter().map(latex_to_str).collect()
 }
 
-pub fn binaryoperator_to_str(left: Latex, operator: BinaryOperator, right: Latex) -> String {
-    let ls = latex_to_str(left.clone());
-    let rs = latex_to_str(right.clone());
-    match operator {
-        BinaryOperator::Add => format!("{}+{}", ls, rs),
-        BinaryOperator::Subtract => format!("{}-{}", ls, rs),
-        BinaryOperator::Multiply => match (left, right) {
-            (Latex::Num(_), Latex::Num(_)) => format!("{}\\cdot {}", ls, rs),
-            _ => format!("{}{}", ls, rs),
-        },
-        BinaryOperator::Divide => format!("\\frac{{{}}}{{{}}}", ls, rs),
-    }
+pub fn multi_latex_to_str_with_format(items: Vec<Latex>, format: &LatexFormat) -> Vec<String> {
+    items
+        .into_iter()
+        .map(|l| latex_to_str_with_format(l, format))
+        .collect()
 }
 
 pub fn compareop_to_str(op: CompareOperator) -> &'static str {
@@ -100,77 +362,680 @@ pub fn compareop_to_str(op: CompareOperator) -> &'static str {
         CompareOperator::Equal => "=",
         CompareOperator::GreaterThan => ">", // or \gt
         CompareOperator::LessThan => "<",    // or \lt
-        CompareOperator::GreaterThanEqual => "\\le",
-        CompareOperator::LessThanEqual => "\\ge",
+        // The space stops a following letter from becoming part of the command, as in
+        //  \gef instead of \ge f
+        CompareOperator::GreaterThanEqual => "\\ge ",
+        CompareOperator::LessThanEqual => "\\le ",
     }
 }
 
-pub fn cond_to_str(cond: Cond) -> String {
-    format!(
-        "{}{}{}:{}",
-        latex_to_str(cond.left),
-        compareop_to_str(cond.op),
-        latex_to_str(cond.right),
-        latex_to_str(cond.result)
-    )
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum MulStyle {
+    // a\cdot b
+    Cdot,
+    // a\times b
+    Times,
+    // ab, falling back to \cdot where that would be ambiguous (2\cdot 3)
+    #[default]
+    Juxtapose,
 }
 
-pub fn latex_to_str(l: Latex) -> String {
+// Options for how Latex is written out. Display uses the defaults.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatexFormat {
+    pub mul_style: MulStyle,
+    // Write calls without arguments as just the name, so f() is f. Definitions without
+    //  parameters are written the same way, as f=1, so the two still match.
+    pub bare_zero_arg_calls: bool,
+    // Write user functions named after a greek letter as the letter, so theta(x) is
+    //  \theta\left(x\right) instead of theta\left(x\right)
+    pub greek_function_names: bool,
+    // Written between call arguments, list items and the like instead of a plain comma,
+    //  such as ", " to make the output easier to read
+    pub separator: Option<String>,
+}
+
+// A backend that turns Latex into some kind of output, with a method for each kind of
+//  node. Methods get the children of the node rather than their output, so a backend
+//  decides how to visit them with emit, or whether to at all. Display writes latex
+//  through the LatexWriter backend.
+pub trait Emit {
+    type Output;
+
+    fn variable(&mut self, name: &str) -> Self::Output;
+    fn num(&mut self, n: &str) -> Self::Output;
+    fn scientific(&mut self, mantissa: &str, exponent: &str) -> Self::Output;
+    fn constant(&mut self, c: &str) -> Self::Output;
+    fn call(&mut self, func: &str, kind: CallKind, args: &[Latex]) -> Self::Output;
+    fn binary_expression(
+        &mut self,
+        left: &Latex,
+        operator: BinaryOperator,
+        right: &Latex,
+    ) -> Self::Output;
+    fn unary_expression(&mut self, left: &Latex, operator: UnaryOperator) -> Self::Output;
+    fn list(&mut self, items: &[Latex]) -> Self::Output;
+    fn assignment(&mut self, left: &Latex, right: &Latex) -> Self::Output;
+    fn func_def(&mut self, name: &str, args: &[String], body: &Latex) -> Self::Output;
+    fn piecewise(&mut self, first: &Cond, rest: &[Cond], default: Option<&Latex>) -> Self::Output;
+    fn comparison(&mut self, left: &Latex, op: CompareOperator, right: &Latex) -> Self::Output;
+    fn comprehension(
+        &mut self,
+        body: &Latex,
+        var: &str,
+        list: &Latex,
+        second: Option<(&str, &Latex)>,
+    ) -> Self::Output;
+    fn point(&mut self, x: &Latex, y: &Latex) -> Self::Output;
+    fn floor(&mut self, inner: &Latex) -> Self::Output;
+    fn abs(&mut self, inner: &Latex) -> Self::Output;
+    fn indicator(&mut self, cond: &Latex) -> Self::Output;
+    fn range(&mut self, start: &Latex, end: &Latex) -> Self::Output;
+    fn index(&mut self, list: &Latex, index: &Latex) -> Self::Output;
+    fn paren(&mut self, inner: &Latex) -> Self::Output;
+    fn sum(&mut self, var: &str, lower: &Latex, upper: &Latex, body: &Latex) -> Self::Output;
+    fn product(&mut self, var: &str, lower: &Latex, upper: &Latex, body: &Latex) -> Self::Output;
+}
+
+// Passes l to the method of e for its kind of node
+pub fn emit<E: Emit + ?Sized>(e: &mut E, l: &Latex) -> E::Output {
     match l {
-        Latex::Variable(s) => format_latex_identifier(s),
-        Latex::Num(s) => s.to_string(),
-        Latex::Call {
-            func,
-            is_builtin,
-            args,
-        } => format!(
-            "{}{}\\left({}\\right)",
-            if is_builtin { "\\" } else { "" },
-            func,
-            multi_latex_to_str(args).join(",")
-        ),
+        Latex::Variable(name) => e.variable(name),
+        Latex::Num(n) => e.num(n),
+        Latex::Scientific { mantissa, exponent } => e.scientific(mantissa, exponent),
+        Latex::Constant(c) => e.constant(c),
+        Latex::Call { func, kind, args } => e.call(func, *kind, args),
         Latex::BinaryExpression {
             left,
             operator,
             right,
-        } => binaryoperator_to_str(*left, operator, *right),
-        Latex::UnaryExpression { left, operator } => match operator {
-            UnaryOperator::Factorial => format!("{}!", latex_to_str(*left),),
-        },
-
-        Latex::List(items) => multi_latex_to_str(items).join(","),
-        Latex::Assignment(left, right) => {
-            format!("{}={}", latex_to_str(*left), latex_to_str(*right))
-        }
-        Latex::FuncDef { name, args, body } => format!(
-            "{}\\left({}\\right)={}",
-            name,
-            args.into_iter()
-                .map(format_latex_identifier)
-                .collect::<Vec<String>>()
-                .join(","),
-            latex_to_str(*body)
-        ),
+        } => e.binary_expression(left, *operator, right),
+        Latex::UnaryExpression { left, operator } => e.unary_expression(left, *operator),
+        Latex::List(items) => e.list(items),
+        Latex::Assignment(left, right) => e.assignment(left, right),
+        Latex::FuncDef { name, args, body } => e.func_def(name, args, body),
         Latex::Piecewise {
             first,
             rest,
             default,
-        } => format!(
-            "\\left\\{{{},{}{}\\right\\}}",
-            cond_to_str(*first),
-            rest.into_iter()
-                .map(|cond| cond_to_str(cond) + ",")
-                .collect::<String>(),
-            latex_to_str(*default)
+        } => e.piecewise(first, rest, default.as_deref()),
+        Latex::Comparison { left, op, right } => e.comparison(left, *op, right),
+        Latex::Comprehension {
+            body,
+            var,
+            list,
+            second,
+        } => e.comprehension(
+            body,
+            var,
+            list,
+            second
+                .as_ref()
+                .map(|(var2, list2)| (var2.as_str(), list2.as_ref())),
         ),
+        Latex::Point(x, y) => e.point(x, y),
+        Latex::Floor(inner) => e.floor(inner),
+        Latex::Abs(inner) => e.abs(inner),
+        Latex::Indicator(cond) => e.indicator(cond),
+        Latex::Range { start, end } => e.range(start, end),
+        Latex::Index { list, index } => e.index(list, index),
+        Latex::Paren(inner) => e.paren(inner),
+        Latex::Sum {
+            var,
+            lower,
+            upper,
+            body,
+        } => e.sum(var, lower, upper, body),
+        Latex::Product {
+            var,
+            lower,
+            upper,
+            body,
+        } => e.product(var, lower, upper, body),
+    }
+}
+
+// Latex paired with the format to write it in, see Latex::with_format
+pub struct Formatted<'a> {
+    latex: &'a Latex,
+    format: &'a LatexFormat,
+}
+
+impl Latex {
+    pub fn with_format<'a>(&'a self, format: &'a LatexFormat) -> Formatted<'a> {
+        Formatted {
+            latex: self,
+            format,
+        }
     }
 }
 
+// How tightly an expression binds when it is the operand of another one, higher binds
+//  tighter. Anything that delimits itself (calls, lists, fractions, ...) never needs
+//  parentheses.
+const PREC_SUM: u8 = 1;
+const PREC_PRODUCT: u8 = 2;
+const PREC_NEGATE: u8 = 2;
+const PREC_EXPONENT: u8 = 3;
+const PREC_POSTFIX: u8 = 4;
+const PREC_ATOM: u8 = 5;
+
+fn precedence(l: &Latex) -> u8 {
+    match l {
+        Latex::BinaryExpression { operator, .. } => match operator {
+            BinaryOperator::Add | BinaryOperator::Subtract => PREC_SUM,
+            BinaryOperator::Multiply => PREC_PRODUCT,
+            BinaryOperator::Divide => PREC_ATOM,
+            BinaryOperator::Exponent => PREC_EXPONENT,
+        },
+        Latex::UnaryExpression { operator, .. } => match operator {
+            UnaryOperator::Factorial => PREC_POSTFIX,
+            UnaryOperator::Negate => PREC_NEGATE,
+        },
+        // A signed literal behaves like a negation
+        Latex::Num(n) if n.starts_with('-') || n.starts_with('+') => PREC_NEGATE,
+        Latex::Scientific { .. } => PREC_PRODUCT,
+        // The body of a sum extends over a product, so \sum_{i=1}^{3}i\cdot a is the sum of
+        //  i*a. A sum can only be juxtaposed or multiplied when it is parenthesized.
+        Latex::Sum { .. } | Latex::Product { .. } => PREC_SUM,
+        _ => PREC_ATOM,
+    }
+}
+
+// Whether l can be used as an operand of anything without parentheses
+pub fn is_atomic(l: &Latex) -> bool {
+    precedence(l) == PREC_ATOM
+}
+
+// The node whose output comes first when l is written
+fn leftmost(l: &Latex) -> &Latex {
+    match l {
+        Latex::BinaryExpression {
+            left,
+            operator: BinaryOperator::Exponent,
+            ..
+        } if precedence(left) > PREC_EXPONENT => leftmost(left),
+        Latex::UnaryExpression {
+            left,
+            operator: UnaryOperator::Factorial,
+        } if precedence(left) > PREC_POSTFIX => leftmost(left),
+        Latex::Index { list, .. } if is_atomic(list) => leftmost(list),
+        _ => l,
+    }
+}
+
+// Whether the output of l ends with a command like \pi, which anything written right
+//  after it without a separator would become part of
+fn ends_with_command(l: &Latex) -> bool {
+    match l {
+        Latex::Constant(c) => c.starts_with('\\') && c.ends_with(|c: char| c.is_ascii_alphabetic()),
+        Latex::BinaryExpression {
+            right,
+            operator: BinaryOperator::Multiply,
+            ..
+        } => ends_with_command(right),
+        Latex::UnaryExpression {
+            left,
+            operator: UnaryOperator::Negate,
+        } => precedence(left) >= PREC_NEGATE && ends_with_command(left),
+        _ => false,
+    }
+}
+
+// Whether the output of l ends with a digit, or with an exponent or factorial of one,
+//  when l is written as the left operand of a product. A number written right after it
+//  would read as more digits of the same one, like 2\cdot 34 for 2*3*4.
+fn ends_with_digit(l: &Latex) -> bool {
+    match l {
+        Latex::Num(n) => n.ends_with(|c: char| c.is_ascii_digit()),
+        Latex::Scientific { .. } => true,
+        Latex::BinaryExpression {
+            right,
+            operator: BinaryOperator::Exponent,
+            ..
+        } => ends_with_digit(right),
+        // The last factor of a chain, unless it is parenthesized
+        Latex::BinaryExpression {
+            right,
+            operator: BinaryOperator::Multiply,
+            ..
+        } => precedence(right) > PREC_PRODUCT && ends_with_digit(right),
+        Latex::UnaryExpression {
+            left,
+            operator: UnaryOperator::Factorial,
+        } => ends_with_digit(left),
+        Latex::UnaryExpression {
+            left,
+            operator: UnaryOperator::Negate,
+        } => precedence(left) >= PREC_NEGATE && ends_with_digit(left),
+        _ => false,
+    }
+}
+
+// Whether writing left and right next to each other would change the meaning, which
+//  happens when digits run together (2 3^2 would become 23^2), right starts with a
+//  list bracket (a[1] is the first item of a) or a point is scaled (a(1,2) is a call).
+//  A decimal is never juxtaposed, since a1.5 is easily misread.
+fn unsafe_to_juxtapose(left: &Latex, right: &Latex) -> bool {
+    if ends_with_command(left) || matches!(left, Latex::Point(..)) {
+        return true;
+    }
+    match leftmost(right) {
+        Latex::List(_) | Latex::Range { .. } | Latex::Comprehension { .. } | Latex::Point(..) => {
+            true
+        }
+        Latex::Num(n) => {
+            n.starts_with(|c: char| c.is_ascii_digit())
+                && (ends_with_digit(left) || n.contains('.'))
+        }
+        Latex::Scientific { .. } => ends_with_digit(left),
+        _ => false,
+    }
+}
+
+// Passes writes on to inner, counting how much has been written so far
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    written: usize,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.written += s.len();
+        self.inner.write_str(s)
+    }
+}
+
+// Called with each node and the range of the output it was written to
+pub type NodeCallback<'a> = dyn FnMut(&Latex, Range<usize>) + 'a;
+
+// The Emit backend that writes latex, straight into out so large outputs aren't built up
+//  from many small strings
+pub struct LatexWriter<'a> {
+    out: CountingWriter<'a>,
+    format: &'a LatexFormat,
+    on_node: Option<&'a mut NodeCallback<'a>>,
+}
+
+impl<'a> LatexWriter<'a> {
+    pub fn new(out: &'a mut dyn Write, format: &'a LatexFormat) -> Self {
+        Self {
+            out: CountingWriter {
+                inner: out,
+                written: 0,
+            },
+            format,
+            on_node: None,
+        }
+    }
+
+    // Reports where every node ends up in the output, children before their parents.
+    //  Only nodes written through write are reported, not ones passed to emit directly.
+    pub fn on_node(mut self, f: &'a mut NodeCallback<'a>) -> Self {
+        self.on_node = Some(f);
+        self
+    }
+
+    pub fn write(&mut self, l: &Latex) -> fmt::Result {
+        self.child(l)
+    }
+
+    fn child(&mut self, l: &Latex) -> fmt::Result {
+        let start = self.out.written;
+        emit(self, l)?;
+        if let Some(f) = &mut self.on_node {
+            f(l, start..self.out.written);
+        }
+        Ok(())
+    }
+
+    fn write_identifier(&mut self, v: &str) -> fmt::Result {
+        self.out.write_str(&format_latex_identifier(v.to_string()))
+    }
+
+    // The name of a user function, at a call or its definition
+    fn write_function_name(&mut self, name: &str) -> fmt::Result {
+        match greek_letter(name) {
+            Some(letter) if self.format.greek_function_names => self.out.write_str(&letter),
+            _ => self.out.write_str(name),
+        }
+    }
+
+    fn write_separator(&mut self) -> fmt::Result {
+        let sep = self.format.separator.as_deref().unwrap_or(",");
+        self.out.write_str(sep)
+    }
+
+    fn write_separated(&mut self, items: &[Latex]) -> fmt::Result {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.write_separator()?;
+            }
+            self.child(item)?;
+        }
+        Ok(())
+    }
+
+    // Writes l, wrapped in parentheses if it binds looser than min
+    fn write_operand(&mut self, l: &Latex, min: u8) -> fmt::Result {
+        if precedence(l) < min {
+            self.out.write_str("\\left(")?;
+            self.child(l)?;
+            self.out.write_str("\\right)")
+        } else {
+            self.child(l)
+        }
+    }
+
+    // Writes left, then s, then right
+    fn write_infix(&mut self, left: &Latex, s: &str, right: &Latex) -> fmt::Result {
+        self.child(left)?;
+        self.out.write_str(s)?;
+        self.child(right)
+    }
+
+    fn write_cond(&mut self, cond: &Cond) -> fmt::Result {
+        self.write_infix(&cond.left, compareop_to_str(cond.op), &cond.right)?;
+        self.out.write_str(":")?;
+        self.child(&cond.result)
+    }
+
+    fn write_product(&mut self, left: &Latex, right: &Latex) -> fmt::Result {
+        let separator = match self.format.mul_style {
+            MulStyle::Cdot => "\\cdot ",
+            MulStyle::Times => "\\times ",
+            MulStyle::Juxtapose => match (left, right) {
+                (Latex::Num(_), Latex::Num(_)) => return self.write_infix(left, "\\cdot ", right),
+                _ if unsafe_to_juxtapose(left, right) => "\\cdot ",
+                _ => "",
+            },
+        };
+        self.write_operand(left, PREC_PRODUCT)?;
+        self.out.write_str(separator)?;
+        // Juxtaposing a negative right side would read as a subtraction
+        self.write_operand(right, PREC_PRODUCT + 1)
+    }
+
+    // Writes a sum or product, command_{var=lower}^{upper}body
+    fn write_big_operator(
+        &mut self,
+        command: &str,
+        var: &str,
+        lower: &Latex,
+        upper: &Latex,
+        body: &Latex,
+    ) -> fmt::Result {
+        self.out.write_str(command)?;
+        self.out.write_str("_{")?;
+        self.write_identifier(var)?;
+        self.out.write_str("=")?;
+        self.child(lower)?;
+        self.out.write_str("}^{")?;
+        self.child(upper)?;
+        self.out.write_str("}")?;
+        self.write_operand(body, PREC_PRODUCT)
+    }
+}
+
+impl Emit for LatexWriter<'_> {
+    type Output = fmt::Result;
+
+    fn variable(&mut self, name: &str) -> fmt::Result {
+        self.write_identifier(name)
+    }
+
+    fn num(&mut self, n: &str) -> fmt::Result {
+        self.out.write_str(n)
+    }
+
+    fn scientific(&mut self, mantissa: &str, exponent: &str) -> fmt::Result {
+        write!(self.out, "{}\\cdot10^{{{}}}", mantissa, exponent)
+    }
+
+    fn constant(&mut self, c: &str) -> fmt::Result {
+        self.out.write_str(c)
+    }
+
+    fn call(&mut self, func: &str, kind: CallKind, args: &[Latex]) -> fmt::Result {
+        match kind {
+            CallKind::User => self.write_function_name(func)?,
+            CallKind::Builtin => write!(self.out, "\\{}", func)?,
+            CallKind::OperatorName => write!(self.out, "\\operatorname{{{}}}", func)?,
+        }
+        if args.is_empty() && self.format.bare_zero_arg_calls {
+            return Ok(());
+        }
+        self.out.write_str("\\left(")?;
+        self.write_separated(args)?;
+        self.out.write_str("\\right)")
+    }
+
+    fn binary_expression(
+        &mut self,
+        left: &Latex,
+        operator: BinaryOperator,
+        right: &Latex,
+    ) -> fmt::Result {
+        match operator {
+            BinaryOperator::Add => self.write_infix(left, "+", right),
+            BinaryOperator::Subtract => {
+                self.child(left)?;
+                self.out.write_str("-")?;
+                self.write_operand(right, PREC_SUM + 1)
+            }
+            BinaryOperator::Multiply => self.write_product(left, right),
+            BinaryOperator::Divide => {
+                self.out.write_str("\\frac{")?;
+                self.write_infix(left, "}{", right)?;
+                self.out.write_str("}")
+            }
+            BinaryOperator::Exponent => {
+                self.write_operand(left, PREC_EXPONENT + 1)?;
+                self.out.write_str("^{")?;
+                self.child(right)?;
+                self.out.write_str("}")
+            }
+        }
+    }
+
+    fn unary_expression(&mut self, left: &Latex, operator: UnaryOperator) -> fmt::Result {
+        match operator {
+            UnaryOperator::Factorial => {
+                self.write_operand(left, PREC_POSTFIX + 1)?;
+                self.out.write_str("!")
+            }
+            UnaryOperator::Negate => {
+                self.out.write_str("-")?;
+                self.write_operand(left, PREC_NEGATE)
+            }
+        }
+    }
+
+    fn list(&mut self, items: &[Latex]) -> fmt::Result {
+        self.out.write_str("\\left[")?;
+        self.write_separated(items)?;
+        self.out.write_str("\\right]")
+    }
+
+    fn assignment(&mut self, left: &Latex, right: &Latex) -> fmt::Result {
+        self.write_infix(left, "=", right)
+    }
+
+    fn func_def(&mut self, name: &str, args: &[String], body: &Latex) -> fmt::Result {
+        self.write_function_name(name)?;
+        if !args.is_empty() || !self.format.bare_zero_arg_calls {
+            self.out.write_str("\\left(")?;
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    self.write_separator()?;
+                }
+                self.write_identifier(arg)?;
+            }
+            self.out.write_str("\\right)")?;
+        }
+        self.out.write_str("=")?;
+        self.child(body)
+    }
+
+    fn piecewise(&mut self, first: &Cond, rest: &[Cond], default: Option<&Latex>) -> fmt::Result {
+        self.out.write_str("\\left\\{")?;
+        self.write_cond(first)?;
+        for cond in rest {
+            self.write_separator()?;
+            self.write_cond(cond)?;
+        }
+        if let Some(default) = default {
+            self.write_separator()?;
+            self.child(default)?;
+        }
+        self.out.write_str("\\right\\}")
+    }
+
+    fn comparison(&mut self, left: &Latex, op: CompareOperator, right: &Latex) -> fmt::Result {
+        self.write_infix(left, compareop_to_str(op), right)
+    }
+
+    fn comprehension(
+        &mut self,
+        body: &Latex,
+        var: &str,
+        list: &Latex,
+        second: Option<(&str, &Latex)>,
+    ) -> fmt::Result {
+        self.out.write_str("\\left[")?;
+        self.child(body)?;
+        self.out.write_str("\\operatorname{for}")?;
+        self.write_identifier(var)?;
+        self.out.write_str("=")?;
+        self.child(list)?;
+        if let Some((var2, list2)) = second {
+            self.write_separator()?;
+            self.write_identifier(var2)?;
+            self.out.write_str("=")?;
+            self.child(list2)?;
+        }
+        self.out.write_str("\\right]")
+    }
+
+    fn point(&mut self, x: &Latex, y: &Latex) -> fmt::Result {
+        self.out.write_str("\\left(")?;
+        self.child(x)?;
+        self.write_separator()?;
+        self.child(y)?;
+        self.out.write_str("\\right)")
+    }
+
+    fn floor(&mut self, inner: &Latex) -> fmt::Result {
+        self.out.write_str("\\left\\lfloor")?;
+        self.child(inner)?;
+        self.out.write_str("\\right\\rfloor")
+    }
+
+    fn abs(&mut self, inner: &Latex) -> fmt::Result {
+        self.out.write_str("\\left|")?;
+        self.child(inner)?;
+        self.out.write_str("\\right|")
+    }
+
+    fn indicator(&mut self, cond: &Latex) -> fmt::Result {
+        self.out.write_str("\\left\\{")?;
+        self.child(cond)?;
+        self.out.write_str("\\right\\}")
+    }
+
+    fn range(&mut self, start: &Latex, end: &Latex) -> fmt::Result {
+        self.out.write_str("\\left[")?;
+        self.write_infix(start, "...", end)?;
+        self.out.write_str("\\right]")
+    }
+
+    fn index(&mut self, list: &Latex, index: &Latex) -> fmt::Result {
+        self.write_operand(list, PREC_ATOM)?;
+        self.out.write_str("\\left[")?;
+        self.child(index)?;
+        self.out.write_str("\\right]")
+    }
+
+    fn paren(&mut self, inner: &Latex) -> fmt::Result {
+        self.out.write_str("\\left(")?;
+        self.child(inner)?;
+        self.out.write_str("\\right)")
+    }
+
+    fn sum(&mut self, var: &str, lower: &Latex, upper: &Latex, body: &Latex) -> fmt::Result {
+        self.write_big_operator("\\sum", var, lower, upper, body)
+    }
+
+    fn product(&mut self, var: &str, lower: &Latex, upper: &Latex, body: &Latex) -> fmt::Result {
+        self.write_big_operator("\\prod", var, lower, upper, body)
+    }
+}
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        emit(&mut LatexWriter::new(f, self.format), self.latex)
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        LatexWriter::new(f, &LatexFormat::default()).write_cond(self)
+    }
+}
+
+impl fmt::Display for Latex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.with_format(&LatexFormat::default()).fmt(f)
+    }
+}
+
+pub fn latex_to_str(l: Latex) -> String {
+    l.to_string()
+}
+
+pub fn latex_to_str_with_format(l: Latex, format: &LatexFormat) -> String {
+    l.with_format(format).to_string()
+}
+
+// Counts the bytes written to it instead of storing them
+struct LenCounter(usize);
+
+impl Write for LenCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+// The length of latex_to_str(l) without building the string, so callers can reject
+//  outputs that are too large up front. l is written out the same way as when
+//  displaying it, which keeps the two from getting out of sync.
+pub fn latex_len(l: &Latex) -> usize {
+    let mut counter = LenCounter(0);
+    // Writing to a LenCounter can't fail
+    write!(counter, "{}", l).unwrap();
+    counter.0
+}
+
+// The emitted LaTeX escaped for use inside a JSON string literal, without the
+//  surrounding quotes. Desmos state exports store every expression this way.
+pub fn latex_to_json_escaped(l: &Latex) -> String {
+    let mut out = String::new();
+    for c in l.to_string().chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn check(input: Latex, output: &'static str) {
+    fn check(input: Latex, output: &str) {
         assert_eq!(latex_to_str(input), output.to_string());
     }
 
@@ -185,12 +1050,58 @@ mod tests {
                     result: Latex::Num("3".to_string()),
                 }),
                 rest: vec![],
-                default: Box::new(Latex::Num("4".to_string())),
+                default: Some(Box::new(Latex::Num("4".to_string()))),
             },
             "\\left\\{1=2:3,4\\right\\}",
         )
     }
 
+    #[test]
+    fn piecewise_without_default() {
+        check(
+            Latex::Piecewise {
+                first: Box::new(Cond {
+                    left: Latex::Num("1".to_string()),
+                    op: CompareOperator::Equal,
+                    right: Latex::Num("2".to_string()),
+                    result: Latex::Num("3".to_string()),
+                }),
+                rest: vec![Cond {
+                    left: Latex::Num("4".to_string()),
+                    op: CompareOperator::LessThan,
+                    right: Latex::Num("5".to_string()),
+                    result: Latex::Num("6".to_string()),
+                }],
+                default: None,
+            },
+            "\\left\\{1=2:3,4<5:6\\right\\}",
+        )
+    }
+
+    #[test]
+    fn sum() {
+        let sum = |body| Latex::Sum {
+            var: "i".to_string(),
+            lower: Box::new(Latex::Num("1".to_string())),
+            upper: Box::new(Latex::Variable("n".to_string())),
+            body: Box::new(body),
+        };
+        let i_times_2 = || Latex::BinaryExpression {
+            left: Box::new(Latex::Variable("i".to_string())),
+            operator: BinaryOperator::Multiply,
+            right: Box::new(Latex::Num("2".to_string())),
+        };
+        check(sum(i_times_2()), "\\sum_{i=1}^{n}i2");
+        check(
+            Latex::BinaryExpression {
+                left: Box::new(sum(Latex::Variable("i".to_string()))),
+                operator: BinaryOperator::Multiply,
+                right: Box::new(Latex::Variable("i".to_string())),
+            },
+            "\\left(\\sum_{i=1}^{n}i\\right)i",
+        );
+    }
+
     #[test]
     fn piecewise_multi() {
         check(
@@ -207,9 +1118,553 @@ mod tests {
                     right: Latex::Num("5".to_string()),
                     result: Latex::Num("6".to_string()),
                 }],
-                default: Box::new(Latex::Num("7".to_string())),
+                default: Some(Box::new(Latex::Num("7".to_string()))),
             },
             "\\left\\{1=2:3,4<5:6,7\\right\\}",
         )
     }
+
+    fn var(v: &str) -> Latex {
+        Latex::Variable(v.to_string())
+    }
+
+    fn num(n: &str) -> Latex {
+        Latex::Num(n.to_string())
+    }
+
+    fn binary(left: Latex, operator: BinaryOperator, right: Latex) -> Latex {
+        Latex::BinaryExpression {
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    fn negate(l: Latex) -> Latex {
+        Latex::UnaryExpression {
+            left: Box::new(l),
+            operator: UnaryOperator::Negate,
+        }
+    }
+
+    #[test]
+    fn negation_and_exponent() {
+        // -x^2 and -(x^2) are the same tree
+        check(
+            negate(binary(var("x"), BinaryOperator::Exponent, num("2"))),
+            "-x^{2}",
+        );
+        // (-x)^2
+        check(
+            binary(negate(var("x")), BinaryOperator::Exponent, num("2")),
+            "\\left(-x\\right)^{2}",
+        );
+        check(
+            binary(num("-2"), BinaryOperator::Exponent, num("2")),
+            "\\left(-2\\right)^{2}",
+        );
+        // (x^2)^3
+        check(
+            binary(
+                binary(var("x"), BinaryOperator::Exponent, num("2")),
+                BinaryOperator::Exponent,
+                num("3"),
+            ),
+            "\\left(x^{2}\\right)^{3}",
+        );
+        // x^(2^3) only needs the braces, unlike a-(b-c)
+        check(
+            binary(
+                var("x"),
+                BinaryOperator::Exponent,
+                binary(num("2"), BinaryOperator::Exponent, num("3")),
+            ),
+            "x^{2^{3}}",
+        );
+        check(
+            negate(binary(var("a"), BinaryOperator::Add, var("b"))),
+            "-\\left(a+b\\right)",
+        );
+        check(
+            binary(num("2"), BinaryOperator::Multiply, negate(var("x"))),
+            "2\\left(-x\\right)",
+        );
+    }
+
+    #[test]
+    fn mul_styles() {
+        let format = |mul_style| LatexFormat {
+            mul_style,
+            ..Default::default()
+        };
+        let nums = || binary(num("2"), BinaryOperator::Multiply, num("3"));
+        let vars = || binary(var("a"), BinaryOperator::Multiply, var("b"));
+        for (style, expect_nums, expect_vars) in &[
+            (MulStyle::Cdot, "2\\cdot 3", "a\\cdot b"),
+            (MulStyle::Times, "2\\times 3", "a\\times b"),
+            (MulStyle::Juxtapose, "2\\cdot 3", "ab"),
+        ] {
+            assert_eq!(
+                latex_to_str_with_format(nums(), &format(*style)),
+                *expect_nums
+            );
+            assert_eq!(
+                latex_to_str_with_format(vars(), &format(*style)),
+                *expect_vars
+            );
+        }
+        // Juxtaposing 2 and 3^2 would give 23^2
+        let square = || binary(num("3"), BinaryOperator::Exponent, num("2"));
+        check(
+            binary(num("2"), BinaryOperator::Multiply, square()),
+            "2\\cdot 3^{2}",
+        );
+        check(
+            binary(var("a"), BinaryOperator::Multiply, square()),
+            "a3^{2}",
+        );
+        // \pi followed directly by a would be read as the command \pia
+        let pi = || Latex::Constant("\\pi".to_string());
+        check(binary(num("2"), BinaryOperator::Multiply, pi()), "2\\pi");
+        check(
+            binary(pi(), BinaryOperator::Multiply, var("a")),
+            "\\pi\\cdot a",
+        );
+        check(
+            binary(
+                binary(num("2"), BinaryOperator::Multiply, pi()),
+                BinaryOperator::Multiply,
+                var("a"),
+            ),
+            "2\\pi\\cdot a",
+        );
+        // The same goes for a negated constant, as in -pi * x
+        let negated_pi = Latex::UnaryExpression {
+            left: Box::new(pi()),
+            operator: UnaryOperator::Negate,
+        };
+        check(
+            binary(negated_pi, BinaryOperator::Multiply, var("x")),
+            "-\\pi\\cdot x",
+        );
+    }
+
+    #[test]
+    fn numeric_factors() {
+        let mul = |l, r| binary(l, BinaryOperator::Multiply, r);
+        // 2*3*4
+        check(mul(mul(num("2"), num("3")), num("4")), "2\\cdot 3\\cdot 4");
+        // a*2*3
+        check(mul(mul(var("a"), num("2")), num("3")), "a2\\cdot 3");
+        check(mul(var("a"), num("1.5")), "a\\cdot 1.5");
+        // 1e5*2
+        check(
+            mul(
+                Latex::Scientific {
+                    mantissa: "1".to_string(),
+                    exponent: "5".to_string(),
+                },
+                num("2"),
+            ),
+            "1\\cdot10^{5}\\cdot 2",
+        );
+        // a^2*3
+        check(
+            mul(
+                binary(var("a"), BinaryOperator::Exponent, num("2")),
+                num("3"),
+            ),
+            "a^{2}\\cdot 3",
+        );
+        check(
+            mul(
+                Latex::UnaryExpression {
+                    left: Box::new(num("3")),
+                    operator: UnaryOperator::Factorial,
+                },
+                num("2"),
+            ),
+            "3!\\cdot 2",
+        );
+        check(mul(negate(num("2")), num("3")), "-2\\cdot 3");
+        // Parenthesized on the right, so nothing runs together
+        check(mul(num("2"), mul(var("a"), num("3"))), "2\\left(a3\\right)");
+    }
+
+    #[test]
+    fn scientific() {
+        let sci = || Latex::Scientific {
+            mantissa: "2.5".to_string(),
+            exponent: "-3".to_string(),
+        };
+        check(sci(), "2.5\\cdot10^{-3}");
+        check(
+            binary(sci(), BinaryOperator::Exponent, num("2")),
+            "\\left(2.5\\cdot10^{-3}\\right)^{2}",
+        );
+    }
+
+    #[test]
+    fn comparison_operators() {
+        let compare = |op| Latex::Comparison {
+            left: Box::new(var("y")),
+            op,
+            right: Box::new(var("f")),
+        };
+        check(compare(CompareOperator::LessThan), "y<f");
+        check(compare(CompareOperator::GreaterThan), "y>f");
+        // \ge and \le need a space so that f isn't read as part of the command
+        check(compare(CompareOperator::GreaterThanEqual), "y\\ge f");
+        check(compare(CompareOperator::LessThanEqual), "y\\le f");
+    }
+
+    #[test]
+    fn list_product() {
+        check(
+            binary(
+                var("a"),
+                BinaryOperator::Multiply,
+                Latex::List(vec![num("1")]),
+            ),
+            "a\\cdot \\left[1\\right]",
+        );
+        check(
+            binary(
+                num("2"),
+                BinaryOperator::Multiply,
+                binary(
+                    Latex::Range {
+                        start: Box::new(num("1")),
+                        end: Box::new(var("n")),
+                    },
+                    BinaryOperator::Exponent,
+                    num("0"),
+                ),
+            ),
+            "2\\cdot \\left[1...n\\right]^{0}",
+        );
+    }
+
+    #[test]
+    fn point_product() {
+        let point = || Latex::Point(Box::new(num("1")), Box::new(num("2")));
+        // a(1,2) would be a call
+        check(
+            binary(var("a"), BinaryOperator::Multiply, point()),
+            "a\\cdot \\left(1,2\\right)",
+        );
+        check(
+            binary(point(), BinaryOperator::Multiply, var("a")),
+            "\\left(1,2\\right)\\cdot a",
+        );
+    }
+
+    #[test]
+    fn call_kinds() {
+        let call = |kind| Latex::Call {
+            func: "f".to_string(),
+            kind,
+            args: vec![num("1")],
+        };
+        check(call(CallKind::User), "f\\left(1\\right)");
+        check(call(CallKind::Builtin), "\\f\\left(1\\right)");
+        check(
+            call(CallKind::OperatorName),
+            "\\operatorname{f}\\left(1\\right)",
+        );
+    }
+
+    #[test]
+    fn comprehension() {
+        check(
+            Latex::Comprehension {
+                body: Box::new(Latex::Variable("xs".to_string())),
+                var: "xs".to_string(),
+                list: Box::new(Latex::List(vec![Latex::Num("1".to_string())])),
+                second: None,
+            },
+            "\\left[x_{s}\\operatorname{for}x_{s}=\\left[1\\right]\\right]",
+        )
+    }
+
+    #[test]
+    fn two_variable_comprehension() {
+        check(
+            Latex::Comprehension {
+                body: Box::new(Latex::Point(Box::new(var("i")), Box::new(var("j")))),
+                var: "i".to_string(),
+                list: Box::new(Latex::List(vec![num("1")])),
+                second: Some(("j".to_string(), Box::new(Latex::List(vec![num("2")])))),
+            },
+            "\\left[\\left(i,j\\right)\\operatorname{for}i=\\left[1\\right],j=\\left[2\\right]\\right]",
+        )
+    }
+
+    #[test]
+    fn large_list() {
+        let items = (0..10_000).map(|i| Latex::Num(i.to_string())).collect();
+        let expected = (0..10_000)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        check(Latex::List(items), &format!("\\left[{}\\right]", expected));
+    }
+
+    #[test]
+    fn json_escaped() {
+        let l = Latex::Call {
+            func: "sin".to_string(),
+            kind: CallKind::Builtin,
+            args: vec![var("x")],
+        };
+        assert_eq!(latex_to_json_escaped(&l), "\\\\sin\\\\left(x\\\\right)");
+        assert_eq!(latex_to_json_escaped(&num("1")), "1");
+    }
+
+    #[test]
+    fn len_matches_output() {
+        let trees = vec![
+            num("1"),
+            var("xy"),
+            negate(binary(var("a"), BinaryOperator::Add, num("2"))),
+            Latex::Call {
+                func: "sin".to_string(),
+                kind: CallKind::Builtin,
+                args: vec![binary(num("1"), BinaryOperator::Divide, var("a"))],
+            },
+            Latex::Piecewise {
+                first: Box::new(Cond {
+                    left: var("a"),
+                    op: CompareOperator::GreaterThan,
+                    right: num("0"),
+                    result: Latex::List(vec![num("1"), num("2")]),
+                }),
+                rest: vec![],
+                default: Some(Box::new(Latex::Abs(Box::new(var("a"))))),
+            },
+        ];
+        for tree in trees {
+            assert_eq!(latex_len(&tree), latex_to_str(tree.clone()).len());
+        }
+    }
+
+    // Counts nodes instead of writing anything
+    struct NodeCounter;
+
+    impl NodeCounter {
+        fn all(&mut self, items: &[&Latex]) -> usize {
+            1 + items.iter().map(|l| emit(self, l)).sum::<usize>()
+        }
+    }
+
+    impl Emit for NodeCounter {
+        type Output = usize;
+
+        fn variable(&mut self, _: &str) -> usize {
+            1
+        }
+        fn num(&mut self, _: &str) -> usize {
+            1
+        }
+        fn scientific(&mut self, _: &str, _: &str) -> usize {
+            1
+        }
+        fn constant(&mut self, _: &str) -> usize {
+            1
+        }
+        fn call(&mut self, _: &str, _: CallKind, args: &[Latex]) -> usize {
+            self.all(&args.iter().collect::<Vec<_>>())
+        }
+        fn binary_expression(&mut self, left: &Latex, _: BinaryOperator, right: &Latex) -> usize {
+            self.all(&[left, right])
+        }
+        fn unary_expression(&mut self, left: &Latex, _: UnaryOperator) -> usize {
+            self.all(&[left])
+        }
+        fn list(&mut self, items: &[Latex]) -> usize {
+            self.all(&items.iter().collect::<Vec<_>>())
+        }
+        fn assignment(&mut self, left: &Latex, right: &Latex) -> usize {
+            self.all(&[left, right])
+        }
+        fn func_def(&mut self, _: &str, _: &[String], body: &Latex) -> usize {
+            self.all(&[body])
+        }
+        fn piecewise(&mut self, first: &Cond, rest: &[Cond], default: Option<&Latex>) -> usize {
+            let mut nodes = vec![];
+            for cond in std::iter::once(first).chain(rest) {
+                nodes.extend([&cond.left, &cond.right, &cond.result]);
+            }
+            nodes.extend(default);
+            self.all(&nodes)
+        }
+        fn comparison(&mut self, left: &Latex, _: CompareOperator, right: &Latex) -> usize {
+            self.all(&[left, right])
+        }
+        fn comprehension(
+            &mut self,
+            body: &Latex,
+            _: &str,
+            list: &Latex,
+            second: Option<(&str, &Latex)>,
+        ) -> usize {
+            let mut nodes = vec![body, list];
+            nodes.extend(second.map(|(_, list2)| list2));
+            self.all(&nodes)
+        }
+        fn point(&mut self, x: &Latex, y: &Latex) -> usize {
+            self.all(&[x, y])
+        }
+        fn floor(&mut self, inner: &Latex) -> usize {
+            self.all(&[inner])
+        }
+        fn abs(&mut self, inner: &Latex) -> usize {
+            self.all(&[inner])
+        }
+        fn indicator(&mut self, cond: &Latex) -> usize {
+            self.all(&[cond])
+        }
+        fn range(&mut self, start: &Latex, end: &Latex) -> usize {
+            self.all(&[start, end])
+        }
+        fn index(&mut self, list: &Latex, index: &Latex) -> usize {
+            self.all(&[list, index])
+        }
+        fn paren(&mut self, inner: &Latex) -> usize {
+            self.all(&[inner])
+        }
+        fn sum(&mut self, _: &str, lower: &Latex, upper: &Latex, body: &Latex) -> usize {
+            self.all(&[lower, upper, body])
+        }
+        fn product(&mut self, _: &str, lower: &Latex, upper: &Latex, body: &Latex) -> usize {
+            self.all(&[lower, upper, body])
+        }
+    }
+
+    #[test]
+    fn alternate_backend() {
+        // f(a) = sin(-a) + [1, 2]
+        let l = Latex::FuncDef {
+            name: "f".to_string(),
+            args: vec!["a".to_string()],
+            body: Box::new(binary(
+                Latex::Call {
+                    func: "sin".to_string(),
+                    kind: CallKind::Builtin,
+                    args: vec![negate(var("a"))],
+                },
+                BinaryOperator::Add,
+                Latex::List(vec![num("1"), num("2")]),
+            )),
+        };
+        assert_eq!(emit(&mut NodeCounter, &l), 8);
+
+        let mut out = String::new();
+        emit(&mut LatexWriter::new(&mut out, &LatexFormat::default()), &l).unwrap();
+        assert_eq!(out, l.to_string());
+    }
+
+    #[test]
+    fn zero_arg_calls() {
+        let call = Latex::Call {
+            func: "f".to_string(),
+            kind: CallKind::User,
+            args: vec![],
+        };
+        let def = Latex::FuncDef {
+            name: "f".to_string(),
+            args: vec![],
+            body: Box::new(num("1")),
+        };
+        check(call.clone(), "f\\left(\\right)");
+        check(def.clone(), "f\\left(\\right)=1");
+
+        let bare = LatexFormat {
+            bare_zero_arg_calls: true,
+            ..Default::default()
+        };
+        assert_eq!(latex_to_str_with_format(call, &bare), "f");
+        assert_eq!(latex_to_str_with_format(def, &bare), "f=1");
+        // Calls with arguments are unaffected
+        assert_eq!(
+            latex_to_str_with_format(
+                Latex::Call {
+                    func: "g".to_string(),
+                    kind: CallKind::User,
+                    args: vec![num("2")],
+                },
+                &bare
+            ),
+            "g\\left(2\\right)"
+        );
+    }
+
+    #[test]
+    fn greek_function_names() {
+        let call = |func: &str| Latex::Call {
+            func: func.to_string(),
+            kind: CallKind::User,
+            args: vec![num("1")],
+        };
+        let def = Latex::FuncDef {
+            name: "theta".to_string(),
+            args: vec!["a".to_string()],
+            body: Box::new(call("Gamma")),
+        };
+        check(call("theta"), "theta\\left(1\\right)");
+
+        let greek = LatexFormat {
+            greek_function_names: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            latex_to_str_with_format(call("theta"), &greek),
+            "\\theta\\left(1\\right)"
+        );
+        assert_eq!(
+            latex_to_str_with_format(def, &greek),
+            "\\theta\\left(a\\right)=\\Gamma\\left(1\\right)"
+        );
+        // Only whole names are letters
+        assert_eq!(
+            latex_to_str_with_format(call("thetas"), &greek),
+            "thetas\\left(1\\right)"
+        );
+    }
+    #[test]
+    fn separators() {
+        let call = Latex::Call {
+            func: "max".to_string(),
+            kind: CallKind::OperatorName,
+            args: vec![num("1"), var("a"), num("2")],
+        };
+        let point = Latex::Point(Box::new(num("1")), Box::new(num("2")));
+        let spaced = LatexFormat {
+            separator: Some(", ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            multi_latex_to_str(vec![call.clone(), point.clone()]),
+            vec![
+                "\\operatorname{max}\\left(1,a,2\\right)",
+                "\\left(1,2\\right)"
+            ]
+        );
+        assert_eq!(
+            multi_latex_to_str_with_format(vec![call, point], &spaced),
+            vec![
+                "\\operatorname{max}\\left(1, a, 2\\right)",
+                "\\left(1, 2\\right)"
+            ]
+        );
+        let def = Latex::FuncDef {
+            name: "f".to_string(),
+            args: vec!["a".to_string(), "b".to_string()],
+            body: Box::new(Latex::List(vec![var("a"), var("b")])),
+        };
+        assert_eq!(
+            latex_to_str_with_format(def, &spaced),
+            "f\\left(a, b\\right)=\\left[a, b\\right]"
+        );
+    }
 }
@@ -1,13 +1,38 @@
+use std::fmt;
+
 pub type ArgCount = usize;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ValType {
     Number,
     List,
+    Bool,
+    Point,
+    // A list of points, List is always a list of numbers
+    PointList,
+    Polygon,
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ValType::Number => "number",
+            ValType::List => "list",
+            ValType::Bool => "bool",
+            ValType::Point => "point",
+            ValType::PointList => "point list",
+            ValType::Polygon => "polygon",
+        })
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Function<'a> {
     pub args: &'a [ValType],
     pub ret: ValType,
+    // The function to use instead, if this one is deprecated
+    pub deprecated: Option<&'a str>,
+    // Whether latex has a command for this function, like \sin. Others are written as
+    //  \operatorname{sign}, which desmos accepts for any function.
+    pub latex_command: bool,
 }
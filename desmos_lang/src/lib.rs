@@ -1,3 +1,47 @@
 pub mod compiler;
 pub mod core;
 pub mod parser;
+pub mod source;
+
+use crate::{core::ast::LocatedStatement, parser::error::ParseError};
+
+// Parses a whole program into its statements, which is the first step of compiling it.
+//  The statements can be passed to compiler::compiler::compile_program.
+#[allow(clippy::result_large_err)]
+pub fn parse(src: &str) -> Result<Vec<LocatedStatement<'_>>, ParseError> {
+    parser::parser::parse_statements(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::compiler::{compile_program, Context},
+        core::ast::{BinaryOperator, Expression, Statement},
+        source::compile_source,
+    };
+
+    #[test]
+    fn parse_program() {
+        let stmts = parse("1+2").unwrap();
+        assert_eq!(stmts.len(), 1);
+        match &stmts[0] {
+            (span, Statement::Expression(Expression::BinaryExpr { operator, .. })) => {
+                assert_eq!(*operator, BinaryOperator::Add);
+                assert_eq!(span.as_str(), "1+2");
+            }
+            s => panic!("Expected a binary expression, got {:?}", s),
+        }
+        assert_eq!(parse("a = 1\nf(b) = b; f(a)").unwrap().len(), 3);
+        assert!(parse("1 +").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        for src in &["1+2", "f(a) = a^2\nb = f(3)", "xs = [1...5]\ntotal(xs)"] {
+            let (out, _) = compile_program(&mut Context::new(), parse(src).unwrap()).unwrap();
+            let out: Vec<_> = out.iter().map(|l| l.to_string()).collect();
+            assert_eq!(out.join("\n"), compile_source(src).unwrap());
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use super::parser::Rule;
+use pest::error::{Error, InputLocation, LineColLocation};
+use std::{fmt, ops::Range};
+
+// Source that doesn't match the grammar. Problems with source that does parse, like type
+//  errors, are CompileErrors instead.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError(Error<Rule>);
+
+impl ParseError {
+    // The byte offsets of the source the error is about. Most syntax errors are at a
+    //  single position, where the range is empty.
+    pub fn span(&self) -> Range<usize> {
+        match self.0.location {
+            InputLocation::Pos(pos) => pos..pos,
+            InputLocation::Span((start, end)) => start..end,
+        }
+    }
+
+    // The line and column where the error starts, both counting from 1
+    pub fn line_col(&self) -> (usize, usize) {
+        match self.0.line_col {
+            LineColLocation::Pos(pos) | LineColLocation::Span(pos, _) => pos,
+        }
+    }
+
+    // The error from pest, which has the rules that were expected
+    pub fn pest_error(&self) -> &Error<Rule> {
+        &self.0
+    }
+}
+
+impl From<Error<Rule>> for ParseError {
+    fn from(err: Error<Rule>) -> Self {
+        Self(err)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parser::parse;
+
+    #[test]
+    fn unbalanced_paren() {
+        let err = parse("(1 + 2").unwrap_err();
+        assert_eq!(err.line_col(), (1, 7));
+        assert_eq!(err.span(), 6..6);
+        let msg = err.to_string();
+        assert!(msg.starts_with(" --> 1:7"), "{}", msg);
+        assert!(msg.contains("(1 + 2"), "{}", msg);
+        assert!(msg.contains("expected"), "{}", msg);
+    }
+
+    #[test]
+    fn later_line() {
+        let err = crate::parse("a = 1\nb = )").unwrap_err();
+        assert_eq!(err.line_col(), (2, 5));
+        assert_eq!(err.span(), 10..10);
+    }
+}
@@ -1 +1,4 @@
+#[allow(clippy::module_inception, clippy::result_large_err)]
 pub mod parser;
+
+pub mod error;
@@ -1,7 +1,8 @@
+use super::error::ParseError;
 use crate::core::{
     ast::{
         BinaryOperator, Branch, CallModifier, Expression, FunctionDefinition, LocatedExpression,
-        LocatedStatement, Statement, UnaryOperator,
+        LocatedStatement, LogicalOperator, Statement, UnaryOperator,
     },
     latex::CompareOperator,
     runtime::ValType,
@@ -10,22 +11,41 @@ use pest::Span;
 use pest_consume::{match_nodes, Error, Node as PestNode, Parser as PestConsumeParser};
 
 // pest + result = pesult ;)
-pub type ParseError = Error<Rule>;
-pub type Pesult<T> = std::result::Result<T, ParseError>;
+pub type Pesult<T> = std::result::Result<T, Error<Rule>>;
 pub type Node<'i> = PestNode<'i, Rule, ()>;
+// A parameter's name, type and default
+type FuncDefParam<'i> = (&'i str, ValType, Option<LocatedExpression<'i>>);
+// The params of a definition and the defaults of the trailing ones
+type FuncDefParams<'i> = (Vec<(&'i str, ValType)>, Vec<LocatedExpression<'i>>);
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(PestConsumeParser)]
 #[grammar = "parser/grammar.pest"] // relative to src
 pub struct DesmosParser;
 
+// Joins two conditions, spanning from the start of the left to the end of the right
+fn logical<'a>(
+    left: LocatedExpression<'a>,
+    op: LogicalOperator,
+    right: LocatedExpression<'a>,
+) -> LocatedExpression<'a> {
+    (
+        left.0.start_pos().span(&right.0.end_pos()),
+        Expression::Logical {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        },
+    )
+}
+
 impl DesmosParser {
     // Shared rules
     fn arguments(input: Node) -> Pesult<Vec<LocatedExpression>> {
         Ok(match_nodes!(
             input.into_children();
-            [Expression(e)..] => e,
-            [ExpressionNoList(e)..] => e,
+            [Argument(e)..] => e,
+            [ExpressionNoPipe(e)..] => e,
         )
         .collect())
     }
@@ -33,11 +53,12 @@ impl DesmosParser {
     fn expression(input: Node) -> Pesult<LocatedExpression> {
         Ok(match_nodes!(
             input.into_children();
-            [List(n)] => n,
-            [UnaryExpression(n)] => n,
-            [BinaryExpression(n)] => n,
-            [Term(n)] => n,
+            [Restricted(n)] => n,
             [Piecewise(n)] => n,
+            [If(n)] => n,
+            [Lambda(n)] => n,
+            [Let(n)] => n,
+            [Pipe(n)] => n,
         ))
     }
 }
@@ -48,14 +69,30 @@ impl DesmosParser {
         Ok(())
     }
 
-    fn ExpressionNoList(input: Node) -> Pesult<LocatedExpression> {
+    fn Expression(input: Node) -> Pesult<LocatedExpression> {
         Self::expression(input)
     }
 
-    fn Expression(input: Node) -> Pesult<LocatedExpression> {
+    fn ExpressionNoPipe(input: Node) -> Pesult<LocatedExpression> {
         Self::expression(input)
     }
 
+    fn Pipe(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [ExpressionNoPipe(e)] => e,
+            [ExpressionNoPipe(e), PipeFunction(funcs)..] => (s, Expression::Pipe {
+                value: Box::new(e),
+                funcs: funcs.collect(),
+            }),
+        ))
+    }
+
+    fn PipeFunction(input: Node<'_>) -> Pesult<(Span<'_>, &str)> {
+        Ok((input.as_span(), input.as_str()))
+    }
+
     fn MapExpression(input: Node) -> Pesult<LocatedExpression> {
         let spn = input.as_span();
         Ok(match_nodes!(
@@ -67,11 +104,108 @@ impl DesmosParser {
     fn Term(input: Node) -> Pesult<LocatedExpression> {
         Ok(match_nodes!(
             input.into_children();
-            [Expression(e)] => e,
+            [Power(e)] => e,
+            [Negation(e)] => e,
+            [UnaryPlus(e)] => e,
+        ))
+    }
+
+    fn Atom(input: Node) -> Pesult<LocatedExpression> {
+        Ok(match_nodes!(
+            input.into_children();
+            [Paren(e)] => e,
             [Number(n)] => n,
             [Variable(n)] => n,
             [Call(c)] => c,
             [MapExpression(e)] => e,
+            [Abs(e)] => e,
+            [Indicator(e)] => e,
+            [Comprehension(e)] => e,
+            [Range(e)] => e,
+            [List(e)] => e,
+        ))
+    }
+
+    fn Abs(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [ExpressionNoPipe(e)] => (s, Expression::Abs(Box::new(e))),
+        ))
+    }
+
+    fn Indicator(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Condition(c)] => (s, Expression::Indicator(Box::new(c))),
+        ))
+    }
+
+    fn Paren(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Expression(e)] => (s, Expression::Paren(Box::new(e))),
+            [Expression(x), Expression(y)] => (s, Expression::Point(Box::new(x), Box::new(y))),
+        ))
+    }
+
+    fn Power(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Postfix(a)] => a,
+            [Postfix(base), Term(exp)] => (s, Expression::BinaryExpr {
+                left: Box::new(base),
+                operator: BinaryOperator::Exponent,
+                right: Box::new(exp),
+            }),
+        ))
+    }
+
+    fn Indexed(input: Node) -> Pesult<LocatedExpression> {
+        Ok(match_nodes!(
+            input.into_children();
+            // In a[1][2], a[1] is indexed again, so each index extends the span
+            [Atom(a), Index(indices)..] => indices.fold(a, |list, (s, index)| (
+                list.0.start_pos().span(&s.end_pos()),
+                Expression::Index {
+                    list: Box::new(list),
+                    index: Box::new(index),
+                },
+            )),
+        ))
+    }
+
+    // The index with the span of its brackets
+    fn Index(input: Node) -> Pesult<(Span, LocatedExpression)> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Expression(e)] => (s, e),
+        ))
+    }
+
+    fn Negation(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Term(t)] => (s, Expression::UnaryExpr {
+                val: Box::new(t),
+                operator: UnaryOperator::Negate,
+            }),
+        ))
+    }
+
+    fn UnaryPlus(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Term(t)] => (s, Expression::UnaryExpr {
+                val: Box::new(t),
+                operator: UnaryOperator::Plus,
+            }),
         ))
     }
 
@@ -79,18 +213,36 @@ impl DesmosParser {
         Ok(UnaryOperator::Factorial)
     }
 
-    fn UnaryOperator(input: Node) -> Pesult<UnaryOperator> {
+    fn DoubleFactorial(input: Node) -> Pesult<UnaryOperator> {
+        Ok(UnaryOperator::DoubleFactorial)
+    }
+
+    fn Percent(input: Node) -> Pesult<UnaryOperator> {
+        Ok(UnaryOperator::Percent)
+    }
+
+    // The operator with its span
+    fn UnaryOperator(input: Node) -> Pesult<(Span, UnaryOperator)> {
+        let s = input.as_span();
         Ok(match_nodes!(
             input.into_children();
-            [Factorial(o)] => o,
+            [Factorial(o)] => (s, o),
+            [DoubleFactorial(o)] => (s, o),
+            [Percent(o)] => (s, o),
         ))
     }
 
-    fn UnaryExpression(input: Node) -> Pesult<LocatedExpression> {
-        let s = input.as_span();
+    fn Postfix(input: Node) -> Pesult<LocatedExpression> {
         Ok(match_nodes!(
             input.into_children();
-            [Term(t), UnaryOperator(op)] => (s, Expression::UnaryExpr { val: Box::new(t), operator: op })
+            // Each operator applies to everything before it, so it extends the span
+            [Indexed(t), UnaryOperator(ops)..] => ops.fold(t, |val, (s, operator)| (
+                val.0.start_pos().span(&s.end_pos()),
+                Expression::UnaryExpr {
+                    val: Box::new(val),
+                    operator,
+                },
+            )),
         ))
     }
 
@@ -136,6 +288,7 @@ impl DesmosParser {
     fn BinaryExpression(input: Node) -> Pesult<LocatedExpression> {
         Ok(match_nodes!(
             input.into_children();
+            [Term(l)] => l,
             [Term(l), BinPair(p), BinPair(rest)..] => rest
                 .collect::<Vec<_>>()
                 .into_iter()
@@ -158,6 +311,28 @@ impl DesmosParser {
         ))
     }
 
+    fn Restricted(input: Node) -> Pesult<LocatedExpression> {
+        Ok(match_nodes!(
+            input.into_children();
+            [BinaryExpression(e), Restriction(conds)..] => conds.fold(e, |value, cond| (
+                value.0.start_pos().span(&cond.0.end_pos()),
+                Expression::Restriction {
+                    value: Box::new(value),
+                    cond: Box::new(cond.1),
+                },
+            )),
+        ))
+    }
+
+    // The condition, with the span of the braces around it
+    fn Restriction(input: Node) -> Pesult<(Span, LocatedExpression)> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Condition(c)] => (s, c),
+        ))
+    }
+
     fn Piecewise(input: Node) -> Pesult<LocatedExpression> {
         let spn = input.as_span();
         Ok(match_nodes!(
@@ -176,7 +351,12 @@ impl DesmosParser {
             ] => Expression::Piecewise {
                 first: Box::new(first),
                 rest: rest,
-                default: Box::new(default),
+                default: Some(Box::new(default)),
+            },
+            [PiecewiseBranch(first), PiecewiseBranches(rest)] => Expression::Piecewise {
+                first: Box::new(first),
+                rest: rest,
+                default: None,
             },
         ))
     }
@@ -191,17 +371,34 @@ impl DesmosParser {
     fn PiecewiseBranch(input: Node) -> Pesult<Branch> {
         Ok(match_nodes!(
             input.into_children();
-            [Condition(cond), Expression(val)] => {
-                let (cond_left, cond, cond_right) = cond;
-                Branch { cond_left, cond, cond_right, val }
+            [Condition(cond), Expression(val)] => Branch { cond, val },
+        ))
+    }
+
+    fn Condition(input: Node) -> Pesult<LocatedExpression> {
+        Ok(match_nodes!(
+            input.into_children();
+            [Conjunction(first), Conjunction(rest)..] => {
+                rest.fold(first, |l, r| logical(l, LogicalOperator::Or, r))
             },
         ))
     }
 
-    fn Condition(input: Node) -> Pesult<(LocatedExpression, CompareOperator, LocatedExpression)> {
+    fn Conjunction(input: Node) -> Pesult<LocatedExpression> {
         Ok(match_nodes!(
             input.into_children();
-            [Expression(left), CompareOp(cond), Expression(right)] => (left, cond, right),
+            [CondTerm(first), CondTerm(rest)..] => {
+                rest.fold(first, |l, r| logical(l, LogicalOperator::And, r))
+            },
+        ))
+    }
+
+    fn CondTerm(input: Node) -> Pesult<LocatedExpression> {
+        Ok(match_nodes!(
+            input.into_children();
+            // The nesting already groups the condition, so the parentheses aren't kept
+            [Condition(c)] => c,
+            [Comparison(c)] => c,
         ))
     }
 
@@ -240,12 +437,78 @@ impl DesmosParser {
         Ok(match_nodes!(input.into_children(); [Expression(e)] => e))
     }
 
+    fn Comparison(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Expression(left), CompareOp(op), Expression(right)] => (s, Expression::Comparison {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            }),
+        ))
+    }
+
+    fn Argument(input: Node) -> Pesult<LocatedExpression> {
+        Ok(match_nodes!(
+            input.into_children();
+            [Binding(b)] => b,
+            [Expression(e)] => e,
+        ))
+    }
+
+    fn Binding(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Identifier(var), Expression(list)] => (s, Expression::Binding {
+                var,
+                list: Box::new(list),
+            }),
+        ))
+    }
+
+    fn Lambda(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Identifier(param), Expression(body)] => (s, Expression::Lambda {
+                param,
+                body: Box::new(body),
+            }),
+        ))
+    }
+
+    fn Let(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Identifier(name), Expression(value), Expression(body)] => (s, Expression::Let {
+                name,
+                value: Box::new(value),
+                body: Box::new(body),
+            }),
+        ))
+    }
+
+    fn If(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Condition(cond), Expression(then), Expression(else_)] => (s, Expression::If {
+                cond: Box::new(cond),
+                then: Box::new(then),
+                else_: Box::new(else_),
+            }),
+        ))
+    }
+
     fn Number(input: Node) -> Pesult<LocatedExpression> {
         let s = input.as_span();
         Ok((s, Expression::Num(input.as_str())))
     }
 
-    fn Identifier(input: Node) -> Pesult<&str> {
+    fn Identifier(input: Node<'_>) -> Pesult<&str> {
         Ok(input.as_str())
     }
 
@@ -259,7 +522,44 @@ impl DesmosParser {
         Ok(match_nodes!(
             input.into_children();
             [] => (s, Expression::List(vec![])),
-            [ArgumentsNoList(items)] => (s, Expression::List(items)),
+            [ListItems(items)] => (s, Expression::List(items)),
+        ))
+    }
+
+    fn Range(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [ExpressionNoPipe(start), ExpressionNoPipe(end)] => (s, Expression::Range {
+                start: Box::new(start),
+                end: Box::new(end),
+            }),
+        ))
+    }
+
+    fn Comprehension(input: Node) -> Pesult<LocatedExpression> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [ExpressionNoPipe(body), ComprehensionVar(v)] => (s, Expression::Comprehension {
+                body: Box::new(body),
+                var: v.0,
+                list: Box::new(v.1),
+                second: None,
+            }),
+            [ExpressionNoPipe(body), ComprehensionVar(v), ComprehensionVar(v2)] => (s, Expression::Comprehension {
+                body: Box::new(body),
+                var: v.0,
+                list: Box::new(v.1),
+                second: Some((v2.0, Box::new(v2.1))),
+            }),
+        ))
+    }
+
+    fn ComprehensionVar(input: Node<'_>) -> Pesult<(&str, LocatedExpression<'_>)> {
+        Ok(match_nodes!(
+            input.into_children();
+            [Identifier(var), Expression(list)] => (var, list),
         ))
     }
 
@@ -267,7 +567,7 @@ impl DesmosParser {
         Self::arguments(input)
     }
 
-    fn ArgumentsNoList(input: Node) -> Pesult<Vec<LocatedExpression>> {
+    fn ListItems(input: Node) -> Pesult<Vec<LocatedExpression>> {
         Self::arguments(input)
     }
 
@@ -299,11 +599,16 @@ impl DesmosParser {
         Ok(CallModifier::NormalCall)
     }
 
-    fn CallStart(input: Node) -> Pesult<(&str, CallModifier)> {
+    fn MacroCall(input: Node) -> Pesult<CallModifier> {
+        Ok(CallModifier::MacroCall)
+    }
+
+    fn CallStart(input: Node<'_>) -> Pesult<(&str, CallModifier)> {
         Ok(match_nodes!(
             input.into_children();
             [Identifier(i), MapCall(c)] => (i, c),
             [Identifier(i), NormalCall(c)] => (i, c),
+            [Identifier(i), MacroCall(c)] => (i, c),
         ))
     }
 
@@ -322,19 +627,38 @@ impl DesmosParser {
         ))
     }
 
-    fn FuncDefParam(input: Node) -> Pesult<(&str, ValType)> {
+    fn FuncDefParam(input: Node<'_>) -> Pesult<FuncDefParam<'_>> {
         Ok(match_nodes!(
             input.into_children();
-            [Identifier(name)] => (name, ValType::Number),
-            [Identifier(name), TypeAnnotation(t)] => (name, t)
+            [Identifier(name)] => (name, ValType::Number, None),
+            [Identifier(name), TypeAnnotation(t)] => (name, t, None),
+            [Identifier(name), Expression(d)] => (name, ValType::Number, Some(d)),
+            [Identifier(name), TypeAnnotation(t), Expression(d)] => (name, t, Some(d))
         ))
     }
 
-    fn FuncDefParams(input: Node) -> Pesult<Vec<(&str, ValType)>> {
-        Ok(match_nodes!(
-            input.into_children();
+    fn FuncDefParams(input: Node<'_>) -> Pesult<FuncDefParams<'_>> {
+        let params: Vec<FuncDefParam> = match_nodes!(
+            input.children();
             [FuncDefParam(params)..] => params.collect()
-        ))
+        );
+        let mut args = Vec::with_capacity(params.len());
+        let mut defaults = Vec::new();
+        for (name, t, default) in params {
+            match default {
+                Some(d) => defaults.push(d),
+                // Calls can only leave out args from the end
+                None if !defaults.is_empty() => {
+                    return Err(input.error(format!(
+                        "Parameter '{}' needs a default since it follows one that has a default",
+                        name
+                    )))
+                }
+                None => {}
+            }
+            args.push((name, t));
+        }
+        Ok((args, defaults))
     }
 
     fn FuncDef(input: Node) -> Pesult<FunctionDefinition> {
@@ -343,17 +667,23 @@ impl DesmosParser {
             [Identifier(n)] => FunctionDefinition {
                 name: n,
                 args: Vec::new(),
-                ret_annotation: None
+                ret_annotation: None,
+                defaults: vec![],
+                inline: false,
             },
-            [Identifier(n), FuncDefParams(args)] => FunctionDefinition {
+            [Identifier(n), FuncDefParams((args, defaults))] => FunctionDefinition {
                 name: n,
                 args: args,
-                ret_annotation: None
+                ret_annotation: None,
+                defaults: defaults,
+                inline: false,
             },
-            [Identifier(n), FuncDefParams(args), TypeAnnotation(ret)] => FunctionDefinition {
+            [Identifier(n), FuncDefParams((args, defaults)), TypeAnnotation(ret)] => FunctionDefinition {
                 name: n,
                 args: args,
-                ret_annotation: Some(ret)
+                ret_annotation: Some(ret),
+                defaults: defaults,
+                inline: false,
             },
         ))
     }
@@ -362,7 +692,23 @@ impl DesmosParser {
         let s = input.as_span();
         Ok(match_nodes!(
             input.into_children();
-            [FuncDef(d), Expression(e)] => (s, Statement::FuncDef(d, e))
+            [FuncDef(d), Expression(e)] => (s, Statement::FuncDef(d, e)),
+            [Inline(_), FuncDef(d), Expression(e)] => (
+                s,
+                Statement::FuncDef(FunctionDefinition { inline: true, ..d }, e)
+            )
+        ))
+    }
+
+    fn Inline(input: Node) -> Pesult<()> {
+        Ok(())
+    }
+
+    fn VarDef(input: Node) -> Pesult<LocatedStatement> {
+        let s = input.as_span();
+        Ok(match_nodes!(
+            input.into_children();
+            [Identifier(name), Expression(e)] => (s, Statement::VarDef(name, e))
         ))
     }
 
@@ -370,6 +716,8 @@ impl DesmosParser {
         Ok(match_nodes!(
             input.into_children();
             [FuncDefStmt(e)] => e,
+            [VarDef(e)] => e,
+            [Comparison(e)] => (e.0, Statement::Expression(e.1)),
             [Expression(e)] => (e.0, Statement::Expression(e.1)),
         ))
     }
@@ -380,15 +728,29 @@ impl DesmosParser {
             [Stmt(s), EOI(_)] => s,
         ))
     }
+
+    fn Statements(input: Node) -> Pesult<Vec<LocatedStatement>> {
+        Ok(match_nodes!(
+            input.into_children();
+            [Stmt(s).., EOI(_)] => s.collect(),
+        ))
+    }
 }
 
-pub fn parse(i: &str) -> Pesult<LocatedStatement> {
+pub fn parse(i: &str) -> Result<LocatedStatement<'_>, ParseError> {
     let inputs = DesmosParser::parse(Rule::Program, i)?;
     let input = inputs.single()?;
-    DesmosParser::Program(input)
+    Ok(DesmosParser::Program(input)?)
+}
+
+pub fn parse_statements(i: &str) -> Result<Vec<LocatedStatement<'_>>, ParseError> {
+    let inputs = DesmosParser::parse(Rule::Statements, i)?;
+    let input = inputs.single()?;
+    Ok(DesmosParser::Statements(input)?)
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     use pest::Span;
@@ -401,7 +763,7 @@ mod tests {
 
     macro_rules! stmt_ptest {
         ($i:expr, $r:expr) => {
-            assert_eq!(parse($i).unwrap(), (spn($i, 0, $i.len()), $r));
+            assert_eq!(parse($i).unwrap(), (spn($i, 0, $i.len()), $r))
         };
     }
 
@@ -421,6 +783,8 @@ mod tests {
         num_test!("1");
         num_test!("-2");
         num_test!("+3");
+        num_test!("1e5");
+        num_test!("2.5E-3");
     }
 
     #[test]
@@ -442,6 +806,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn mod_keyword() {
+        let i = "a mod b";
+        parse_test!(
+            i,
+            Expression::BinaryExpr {
+                left: Box::new((spn(i, 0, 1), Expression::Variable("a"))),
+                operator: BinaryOperator::Mod,
+                right: Box::new((spn(i, 6, 7), Expression::Variable("b")))
+            }
+        );
+        // Only a whole word is the keyword
+        assert!(parse("a model").is_err());
+    }
+
     #[test]
     fn long_binary_expression() {
         let i = "1 + 2 + 3";
@@ -475,6 +854,206 @@ mod tests {
         );
     }
 
+    #[test]
+    fn repeated_postfix() {
+        let i = "x!!!";
+        parse_test!(
+            i,
+            Expression::UnaryExpr {
+                val: Box::new((
+                    spn(i, 0, 3),
+                    Expression::UnaryExpr {
+                        val: Box::new((spn(i, 0, 1), Expression::Variable("x"))),
+                        operator: UnaryOperator::DoubleFactorial,
+                    }
+                )),
+                operator: UnaryOperator::Factorial,
+            }
+        );
+        let j = "x! !";
+        parse_test!(
+            j,
+            Expression::UnaryExpr {
+                val: Box::new((
+                    spn(j, 0, 2),
+                    Expression::UnaryExpr {
+                        val: Box::new((spn(j, 0, 1), Expression::Variable("x"))),
+                        operator: UnaryOperator::Factorial,
+                    }
+                )),
+                operator: UnaryOperator::Factorial,
+            }
+        );
+    }
+
+    #[test]
+    fn percent() {
+        let i = "(a + b)%";
+        parse_test!(
+            i,
+            Expression::UnaryExpr {
+                val: Box::new((
+                    spn(i, 0, 7),
+                    Expression::Paren(Box::new((
+                        spn(i, 1, 6),
+                        Expression::BinaryExpr {
+                            left: Box::new((spn(i, 1, 2), Expression::Variable("a"))),
+                            operator: BinaryOperator::Add,
+                            right: Box::new((spn(i, 5, 6), Expression::Variable("b"))),
+                        }
+                    )))
+                )),
+                operator: UnaryOperator::Percent,
+            }
+        );
+        let percent = |i, start, end| {
+            (
+                spn(i, start, end),
+                Expression::UnaryExpr {
+                    val: Box::new((spn(i, start, end - 1), Expression::Num(&i[start..end - 1]))),
+                    operator: UnaryOperator::Percent,
+                },
+            )
+        };
+        let j = "50% - 10%";
+        parse_test!(
+            j,
+            Expression::BinaryExpr {
+                left: Box::new(percent(j, 0, 3)),
+                operator: BinaryOperator::Subtract,
+                right: Box::new(percent(j, 6, 9)),
+            }
+        );
+        // With an operand after it, it is still a mod
+        for k in &["a % b", "a %b", "a % (b)", "a % -b", "a % - b", "a %\n  b"] {
+            match parse(k).unwrap().1 {
+                Statement::Expression(Expression::BinaryExpr { operator, .. }) => {
+                    assert_eq!(operator, BinaryOperator::Mod, "{}", k)
+                }
+                e => panic!("Expected a mod for {}, got {:?}", k, e),
+            }
+        }
+    }
+
+    #[test]
+    fn exponent_precedence() {
+        let i = "-x^2";
+        parse_test!(
+            i,
+            Expression::UnaryExpr {
+                val: Box::new((
+                    spn(i, 1, 4),
+                    Expression::BinaryExpr {
+                        left: Box::new((spn(i, 1, 2), Expression::Variable("x"))),
+                        operator: BinaryOperator::Exponent,
+                        right: Box::new((spn(i, 3, 4), Expression::Num("2"))),
+                    }
+                )),
+                operator: UnaryOperator::Negate,
+            }
+        );
+        let j = "(-x)^2";
+        parse_test!(
+            j,
+            Expression::BinaryExpr {
+                left: Box::new((
+                    spn(j, 0, 4),
+                    Expression::Paren(Box::new((
+                        spn(j, 1, 3),
+                        Expression::UnaryExpr {
+                            val: Box::new((spn(j, 2, 3), Expression::Variable("x"))),
+                            operator: UnaryOperator::Negate,
+                        }
+                    )))
+                )),
+                operator: BinaryOperator::Exponent,
+                right: Box::new((spn(j, 5, 6), Expression::Num("2"))),
+            }
+        );
+        let k = "-2^2";
+        parse_test!(
+            k,
+            Expression::UnaryExpr {
+                val: Box::new((
+                    spn(k, 1, 4),
+                    Expression::BinaryExpr {
+                        left: Box::new((spn(k, 1, 2), Expression::Num("2"))),
+                        operator: BinaryOperator::Exponent,
+                        right: Box::new((spn(k, 3, 4), Expression::Num("2"))),
+                    }
+                )),
+                operator: UnaryOperator::Negate,
+            }
+        );
+    }
+
+    #[test]
+    fn index() {
+        let i = "a[1][b]^2";
+        parse_test!(
+            i,
+            Expression::BinaryExpr {
+                left: Box::new((
+                    spn(i, 0, 7),
+                    Expression::Index {
+                        list: Box::new((
+                            spn(i, 0, 4),
+                            Expression::Index {
+                                list: Box::new((spn(i, 0, 1), Expression::Variable("a"))),
+                                index: Box::new((spn(i, 2, 3), Expression::Num("1"))),
+                            }
+                        )),
+                        index: Box::new((spn(i, 5, 6), Expression::Variable("b"))),
+                    }
+                )),
+                operator: BinaryOperator::Exponent,
+                right: Box::new((spn(i, 8, 9), Expression::Num("2"))),
+            }
+        );
+    }
+
+    #[test]
+    fn var_def() {
+        let i = "a = [1]";
+        stmt_ptest!(
+            i,
+            Statement::VarDef(
+                "a",
+                (
+                    spn(i, 4, 7),
+                    Expression::List(vec![(spn(i, 5, 6), Expression::Num("1"))])
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn unary_plus() {
+        let i = "+x";
+        parse_test!(
+            i,
+            Expression::UnaryExpr {
+                val: Box::new((spn(i, 1, 2), Expression::Variable("x"))),
+                operator: UnaryOperator::Plus,
+            }
+        );
+        let j = "1 + +x";
+        parse_test!(
+            j,
+            Expression::BinaryExpr {
+                left: Box::new((spn(j, 0, 1), Expression::Num("1"))),
+                operator: BinaryOperator::Add,
+                right: Box::new((
+                    spn(j, 4, 6),
+                    Expression::UnaryExpr {
+                        val: Box::new((spn(j, 5, 6), Expression::Variable("x"))),
+                        operator: UnaryOperator::Plus,
+                    }
+                )),
+            }
+        );
+    }
+
     #[test]
     fn call() {
         parse_test!(
@@ -516,6 +1095,59 @@ mod tests {
         )
     }
 
+    #[test]
+    fn macro_lambda() {
+        let i = "map!(x => x*2, [1])";
+        parse_test!(
+            i,
+            Expression::Call {
+                modifier: CallModifier::MacroCall,
+                func: "map",
+                args: vec![
+                    (
+                        spn(i, 5, 13),
+                        Expression::Lambda {
+                            param: "x",
+                            body: Box::new((
+                                spn(i, 10, 13),
+                                Expression::BinaryExpr {
+                                    left: Box::new((spn(i, 10, 11), Expression::Variable("x"))),
+                                    operator: BinaryOperator::Multiply,
+                                    right: Box::new((spn(i, 12, 13), Expression::Num("2"))),
+                                }
+                            )),
+                        }
+                    ),
+                    (
+                        spn(i, 15, 18),
+                        Expression::List(vec![(spn(i, 16, 17), Expression::Num("1"))])
+                    ),
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn mapcall_precedence() {
+        let i = "f@(a) + 1";
+        parse_test!(
+            i,
+            Expression::BinaryExpr {
+                left: Box::new((
+                    spn(i, 0, 5),
+                    Expression::Call {
+                        modifier: CallModifier::MapCall,
+                        func: "f",
+                        args: vec![(spn(i, 3, 4), Expression::Variable("a"))]
+                    }
+                )),
+                operator: BinaryOperator::Add,
+                right: Box::new((spn(i, 8, 9), Expression::Num("1"))),
+            }
+        );
+        assert!(parse("f @(a)").is_err());
+    }
+
     #[test]
     fn map_expression() {
         let i = "@({a=1:2,otherwise:3})";
@@ -525,13 +1157,18 @@ mod tests {
                 spn(i, 2, 21),
                 Expression::Piecewise {
                     first: Box::new(Branch {
-                        cond_left: (spn(i, 3, 4), Expression::Variable("a")),
-                        cond: CompareOperator::Equal,
-                        cond_right: (spn(i, 5, 6), Expression::Num("1")),
+                        cond: (
+                            spn(i, 3, 6),
+                            Expression::Comparison {
+                                left: Box::new((spn(i, 3, 4), Expression::Variable("a"))),
+                                op: CompareOperator::Equal,
+                                right: Box::new((spn(i, 5, 6), Expression::Num("1"))),
+                            },
+                        ),
                         val: (spn(i, 7, 8), Expression::Num("2")),
                     }),
                     rest: vec![],
-                    default: Box::new((spn(i, 19, 20), Expression::Num("3"))),
+                    default: Some(Box::new((spn(i, 19, 20), Expression::Num("3")))),
                 },
             )))
         );
@@ -550,6 +1187,53 @@ mod tests {
         );
     }
 
+    #[test]
+    fn comprehension() {
+        let i = "[i^2 for i=[1...5]]";
+        parse_test!(
+            i,
+            Expression::Comprehension {
+                body: Box::new((
+                    spn(i, 1, 4),
+                    Expression::BinaryExpr {
+                        left: Box::new((spn(i, 1, 2), Expression::Variable("i"))),
+                        operator: BinaryOperator::Exponent,
+                        right: Box::new((spn(i, 3, 4), Expression::Num("2"))),
+                    }
+                )),
+                var: "i",
+                list: Box::new((
+                    spn(i, 11, 18),
+                    Expression::Range {
+                        start: Box::new((spn(i, 12, 13), Expression::Num("1"))),
+                        end: Box::new((spn(i, 16, 17), Expression::Num("5"))),
+                    }
+                )),
+                second: None,
+            }
+        );
+    }
+
+    #[test]
+    fn two_variable_comprehension() {
+        let i = "[(i,j) for i=a, j=b]";
+        parse_test!(
+            i,
+            Expression::Comprehension {
+                body: Box::new((
+                    spn(i, 1, 6),
+                    Expression::Point(
+                        Box::new((spn(i, 2, 3), Expression::Variable("i"))),
+                        Box::new((spn(i, 4, 5), Expression::Variable("j"))),
+                    )
+                )),
+                var: "i",
+                list: Box::new((spn(i, 13, 14), Expression::Variable("a"))),
+                second: Some(("j", Box::new((spn(i, 18, 19), Expression::Variable("b"))))),
+            }
+        );
+    }
+
     #[test]
     fn func_def() {
         let i = "f(a, b) = 1";
@@ -559,13 +1243,52 @@ mod tests {
                 FunctionDefinition {
                     name: "f",
                     args: vec![("a", ValType::Number), ("b", ValType::Number)],
-                    ret_annotation: None
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: false,
                 },
                 (spn(i, 10, 11), Expression::Num("1"))
             )
         )
     }
 
+    #[test]
+    fn func_def_defaults() {
+        let i = "f(a, b: Number = 1) = a";
+        stmt_ptest!(
+            i,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "f",
+                    args: vec![("a", ValType::Number), ("b", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![(spn(i, 17, 18), Expression::Num("1"))],
+                    inline: false,
+                },
+                (spn(i, 22, 23), Expression::Variable("a"))
+            )
+        );
+        assert!(parse("f(a = 1, b) = a").is_err());
+    }
+
+    #[test]
+    fn inline_func_def() {
+        let i = "inline f(a) = a";
+        stmt_ptest!(
+            i,
+            Statement::FuncDef(
+                FunctionDefinition {
+                    name: "f",
+                    args: vec![("a", ValType::Number)],
+                    ret_annotation: None,
+                    defaults: vec![],
+                    inline: true,
+                },
+                (spn(i, 14, 15), Expression::Variable("a"))
+            )
+        );
+    }
+
     #[test]
     fn func_def_annotations() {
         let i = "f(a: Number, b:List): Number = 1";
@@ -575,13 +1298,288 @@ mod tests {
                 FunctionDefinition {
                     name: "f",
                     args: vec![("a", ValType::Number), ("b", ValType::List)],
-                    ret_annotation: Some(ValType::Number)
+                    ret_annotation: Some(ValType::Number),
+                    defaults: vec![],
+                    inline: false,
                 },
                 (spn(i, 31, 32), Expression::Num("1"))
             )
         )
     }
 
+    #[test]
+    fn deep_nesting() {
+        // Each level used to be parsed several times, so this would never finish
+        let depth = 20;
+        let i = format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        let stmt = parse(&i).unwrap().1;
+        let mut e = match &stmt {
+            Statement::Expression(e) => e,
+            s => panic!("Expected an expression, got {:?}", s),
+        };
+        for _ in 0..depth {
+            e = match e {
+                Expression::Paren(inner) => &inner.1,
+                _ => panic!("Expected parentheses, got {:?}", e),
+            };
+        }
+        assert_eq!(e, &Expression::Num("1"));
+    }
+
+    #[test]
+    fn absolute_value() {
+        let i = "|x|";
+        parse_test!(
+            i,
+            Expression::Abs(Box::new((spn(i, 1, 2), Expression::Variable("x"))))
+        );
+        let j = "||x| - 1|";
+        parse_test!(
+            j,
+            Expression::Abs(Box::new((
+                spn(j, 1, 8),
+                Expression::BinaryExpr {
+                    left: Box::new((
+                        spn(j, 1, 4),
+                        Expression::Abs(Box::new((spn(j, 2, 3), Expression::Variable("x"))))
+                    )),
+                    operator: BinaryOperator::Subtract,
+                    right: Box::new((spn(j, 7, 8), Expression::Num("1"))),
+                }
+            )))
+        );
+        let k = "|a|*|b|";
+        parse_test!(
+            k,
+            Expression::BinaryExpr {
+                left: Box::new((
+                    spn(k, 0, 3),
+                    Expression::Abs(Box::new((spn(k, 1, 2), Expression::Variable("a"))))
+                )),
+                operator: BinaryOperator::Multiply,
+                right: Box::new((
+                    spn(k, 4, 7),
+                    Expression::Abs(Box::new((spn(k, 5, 6), Expression::Variable("b"))))
+                )),
+            }
+        );
+    }
+
+    #[test]
+    fn pipe() {
+        let i = "x |> f |> g";
+        parse_test!(
+            i,
+            Expression::Pipe {
+                value: Box::new((spn(i, 0, 1), Expression::Variable("x"))),
+                funcs: vec![(spn(i, 5, 6), "f"), (spn(i, 10, 11), "g")],
+            }
+        );
+        // The pipe binds looser than everything else
+        let j = "1 + x |> f";
+        parse_test!(
+            j,
+            Expression::Pipe {
+                value: Box::new((
+                    spn(j, 0, 5),
+                    Expression::BinaryExpr {
+                        left: Box::new((spn(j, 0, 1), Expression::Num("1"))),
+                        operator: BinaryOperator::Add,
+                        right: Box::new((spn(j, 4, 5), Expression::Variable("x"))),
+                    }
+                )),
+                funcs: vec![(spn(j, 9, 10), "f")],
+            }
+        );
+    }
+
+    #[test]
+    fn binding_argument() {
+        let i = "sum!(i in xs, i)";
+        parse_test!(
+            i,
+            Expression::Call {
+                modifier: CallModifier::MacroCall,
+                func: "sum",
+                args: vec![
+                    (
+                        spn(i, 5, 12),
+                        Expression::Binding {
+                            var: "i",
+                            list: Box::new((spn(i, 10, 12), Expression::Variable("xs"))),
+                        }
+                    ),
+                    (spn(i, 14, 15), Expression::Variable("i")),
+                ],
+            }
+        );
+        // Names starting with "in" are still arguments
+        let j = "f(index)";
+        parse_test!(
+            j,
+            Expression::Call {
+                modifier: CallModifier::NormalCall,
+                func: "f",
+                args: vec![(spn(j, 2, 7), Expression::Variable("index"))],
+            }
+        );
+    }
+
+    #[test]
+    fn let_expression() {
+        let i = "let a = 1 in a";
+        parse_test!(
+            i,
+            Expression::Let {
+                name: "a",
+                value: Box::new((spn(i, 8, 9), Expression::Num("1"))),
+                body: Box::new((spn(i, 13, 14), Expression::Variable("a"))),
+            }
+        );
+    }
+
+    #[test]
+    fn if_expression() {
+        let i = "if a > 0 then 1 else -1";
+        parse_test!(
+            i,
+            Expression::If {
+                cond: Box::new((
+                    spn(i, 3, 8),
+                    Expression::Comparison {
+                        left: Box::new((spn(i, 3, 4), Expression::Variable("a"))),
+                        op: CompareOperator::GreaterThan,
+                        right: Box::new((spn(i, 7, 8), Expression::Num("0"))),
+                    }
+                )),
+                then: Box::new((spn(i, 14, 15), Expression::Num("1"))),
+                else_: Box::new((spn(i, 21, 23), Expression::Num("-1"))),
+            }
+        );
+    }
+
+    #[test]
+    fn statements() {
+        let i = "f(a) = a\n\nf(1); 2;";
+        assert_eq!(
+            parse_statements(i).unwrap(),
+            vec![
+                (
+                    spn(i, 0, 8),
+                    Statement::FuncDef(
+                        FunctionDefinition {
+                            name: "f",
+                            args: vec![("a", ValType::Number)],
+                            ret_annotation: None,
+                            defaults: vec![],
+                            inline: false,
+                        },
+                        (spn(i, 7, 8), Expression::Variable("a"))
+                    )
+                ),
+                (
+                    spn(i, 10, 14),
+                    Statement::Expression(Expression::Call {
+                        modifier: CallModifier::NormalCall,
+                        func: "f",
+                        args: vec![(spn(i, 12, 13), Expression::Num("1"))]
+                    })
+                ),
+                (spn(i, 16, 17), Statement::Expression(Expression::Num("2"))),
+            ]
+        );
+        assert!(parse_statements("1 2").is_err());
+    }
+
+    #[test]
+    fn empty_statements() {
+        for i in &["", "  \t", "\n\n", " ;\n; "] {
+            assert_eq!(parse_statements(i).unwrap(), vec![]);
+        }
+        // A single statement is still required where one is expected
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn multi_line_expressions() {
+        let i = "1 +\n  2\nf(\n  1,\n  [2,\n  3]\n)\n1\n+2";
+        assert_eq!(
+            parse_statements(i).unwrap(),
+            vec![
+                (
+                    spn(i, 0, 7),
+                    Statement::Expression(Expression::BinaryExpr {
+                        left: Box::new((spn(i, 0, 1), Expression::Num("1"))),
+                        operator: BinaryOperator::Add,
+                        right: Box::new((spn(i, 6, 7), Expression::Num("2"))),
+                    })
+                ),
+                (
+                    spn(i, 8, 28),
+                    Statement::Expression(Expression::Call {
+                        modifier: CallModifier::NormalCall,
+                        func: "f",
+                        args: vec![
+                            (spn(i, 13, 14), Expression::Num("1")),
+                            (
+                                spn(i, 18, 26),
+                                Expression::List(vec![
+                                    (spn(i, 19, 20), Expression::Num("2")),
+                                    (spn(i, 24, 25), Expression::Num("3")),
+                                ])
+                            ),
+                        ]
+                    })
+                ),
+                // A newline before an operator still ends the statement
+                (spn(i, 29, 30), Statement::Expression(Expression::Num("1"))),
+                (spn(i, 31, 33), Statement::Expression(Expression::Num("+2"))),
+            ]
+        );
+        let j = "(1,\n 2)";
+        parse_test!(
+            j,
+            Expression::Point(
+                Box::new((spn(j, 1, 2), Expression::Num("1"))),
+                Box::new((spn(j, 5, 6), Expression::Num("2")))
+            )
+        );
+        // Inside brackets an operator can also start a new line
+        let k = "(1\n+ 2)";
+        parse_test!(
+            k,
+            Expression::Paren(Box::new((
+                spn(k, 1, 6),
+                Expression::BinaryExpr {
+                    left: Box::new((spn(k, 1, 2), Expression::Num("1"))),
+                    operator: BinaryOperator::Add,
+                    right: Box::new((spn(k, 5, 6), Expression::Num("2"))),
+                }
+            )))
+        );
+        let r = "[1...\n5]";
+        parse_test!(
+            r,
+            Expression::Range {
+                start: Box::new((spn(r, 1, 2), Expression::Num("1"))),
+                end: Box::new((spn(r, 6, 7), Expression::Num("5"))),
+            }
+        );
+        let c = "[i\n  for i=a\n]";
+        parse_test!(
+            c,
+            Expression::Comprehension {
+                body: Box::new((spn(c, 1, 2), Expression::Variable("i"))),
+                var: "i",
+                list: Box::new((spn(c, 11, 12), Expression::Variable("a"))),
+                second: None,
+            }
+        );
+        // but not once they are closed
+        let l = "(1)\n+2";
+        assert_eq!(parse_statements(l).unwrap().len(), 2);
+    }
+
     #[test]
     fn piecewise_single() {
         let i = "{ a = 1: 2, otherwise: 3 }";
@@ -589,13 +1587,41 @@ mod tests {
             i,
             Expression::Piecewise {
                 first: Box::new(Branch {
-                    cond_left: (spn(i, 2, 3), Expression::Variable("a")),
-                    cond: CompareOperator::Equal,
-                    cond_right: (spn(i, 6, 7), Expression::Num("1")),
+                    cond: (
+                        spn(i, 2, 7),
+                        Expression::Comparison {
+                            left: Box::new((spn(i, 2, 3), Expression::Variable("a"))),
+                            op: CompareOperator::Equal,
+                            right: Box::new((spn(i, 6, 7), Expression::Num("1"))),
+                        },
+                    ),
                     val: (spn(i, 9, 10), Expression::Num("2"))
                 }),
                 rest: vec![],
-                default: Box::new((spn(i, 23, 24), Expression::Num("3")))
+                default: Some(Box::new((spn(i, 23, 24), Expression::Num("3"))))
+            }
+        )
+    }
+
+    #[test]
+    fn piecewise_without_default() {
+        let i = "{ a = 1: 2 }";
+        parse_test!(
+            i,
+            Expression::Piecewise {
+                first: Box::new(Branch {
+                    cond: (
+                        spn(i, 2, 7),
+                        Expression::Comparison {
+                            left: Box::new((spn(i, 2, 3), Expression::Variable("a"))),
+                            op: CompareOperator::Equal,
+                            right: Box::new((spn(i, 6, 7), Expression::Num("1"))),
+                        },
+                    ),
+                    val: (spn(i, 9, 10), Expression::Num("2"))
+                }),
+                rest: vec![],
+                default: None
             }
         )
     }
@@ -607,33 +1633,146 @@ mod tests {
             i,
             Expression::Piecewise {
                 first: Box::new(Branch {
-                    cond_left: (spn(i, 2, 3), Expression::Variable("a")),
-                    cond: CompareOperator::GreaterThanEqual,
-                    cond_right: (spn(i, 7, 8), Expression::Num("1")),
+                    cond: (
+                        spn(i, 2, 8),
+                        Expression::Comparison {
+                            left: Box::new((spn(i, 2, 3), Expression::Variable("a"))),
+                            op: CompareOperator::GreaterThanEqual,
+                            right: Box::new((spn(i, 7, 8), Expression::Num("1"))),
+                        },
+                    ),
                     val: (spn(i, 10, 11), Expression::Num("2"))
                 }),
                 rest: vec![
                     Branch {
-                        cond_left: (spn(i, 13, 14), Expression::Variable("a")),
-                        cond: CompareOperator::LessThanEqual,
-                        cond_right: (spn(i, 18, 19), Expression::Num("3")),
+                        cond: (
+                            spn(i, 13, 19),
+                            Expression::Comparison {
+                                left: Box::new((spn(i, 13, 14), Expression::Variable("a"))),
+                                op: CompareOperator::LessThanEqual,
+                                right: Box::new((spn(i, 18, 19), Expression::Num("3"))),
+                            },
+                        ),
                         val: (spn(i, 21, 22), Expression::Num("4"))
                     },
                     Branch {
-                        cond_left: (spn(i, 24, 25), Expression::Variable("a")),
-                        cond: CompareOperator::LessThan,
-                        cond_right: (spn(i, 28, 29), Expression::Num("5")),
+                        cond: (
+                            spn(i, 24, 29),
+                            Expression::Comparison {
+                                left: Box::new((spn(i, 24, 25), Expression::Variable("a"))),
+                                op: CompareOperator::LessThan,
+                                right: Box::new((spn(i, 28, 29), Expression::Num("5"))),
+                            },
+                        ),
                         val: (spn(i, 31, 32), Expression::Num("6"))
                     },
                     Branch {
-                        cond_left: (spn(i, 34, 35), Expression::Variable("a")),
-                        cond: CompareOperator::GreaterThan,
-                        cond_right: (spn(i, 38, 39), Expression::Num("7")),
+                        cond: (
+                            spn(i, 34, 39),
+                            Expression::Comparison {
+                                left: Box::new((spn(i, 34, 35), Expression::Variable("a"))),
+                                op: CompareOperator::GreaterThan,
+                                right: Box::new((spn(i, 38, 39), Expression::Num("7"))),
+                            },
+                        ),
                         val: (spn(i, 41, 42), Expression::Num("8"))
                     }
                 ],
-                default: Box::new((spn(i, 55, 56), Expression::Num("9")))
+                default: Some(Box::new((spn(i, 55, 56), Expression::Num("9"))))
             }
         )
     }
+
+    #[test]
+    fn indicator() {
+        let i = "2 * {a > 0}";
+        parse_test!(
+            i,
+            Expression::BinaryExpr {
+                left: Box::new((spn(i, 0, 1), Expression::Num("2"))),
+                operator: BinaryOperator::Multiply,
+                right: Box::new((
+                    spn(i, 4, 11),
+                    Expression::Indicator(Box::new((
+                        spn(i, 5, 10),
+                        Expression::Comparison {
+                            left: Box::new((spn(i, 5, 6), Expression::Variable("a"))),
+                            op: CompareOperator::GreaterThan,
+                            right: Box::new((spn(i, 9, 10), Expression::Num("0"))),
+                        }
+                    )))
+                ))
+            }
+        );
+    }
+
+    #[test]
+    fn restriction() {
+        let i = "a {a > 0} {a < 1}";
+        let cmp = |start, op, num| {
+            (
+                spn(i, start, start + 5),
+                Expression::Comparison {
+                    left: Box::new((spn(i, start, start + 1), Expression::Variable("a"))),
+                    op,
+                    right: Box::new((spn(i, start + 4, start + 5), Expression::Num(num))),
+                },
+            )
+        };
+        // Each restriction spans from the value to its closing brace
+        parse_test!(
+            i,
+            Expression::Restriction {
+                value: Box::new((
+                    spn(i, 0, 9),
+                    Expression::Restriction {
+                        value: Box::new((spn(i, 0, 1), Expression::Variable("a"))),
+                        cond: Box::new(cmp(3, CompareOperator::GreaterThan, "0")),
+                    }
+                )),
+                cond: Box::new(cmp(11, CompareOperator::LessThan, "1")),
+            }
+        );
+    }
+
+    #[test]
+    fn logical_conditions() {
+        let i = "{ a < 1 or a > 2 and (a < 3): 4 }";
+        let cmp = |start, op, num, end| {
+            (
+                spn(i, start, end),
+                Expression::Comparison {
+                    left: Box::new((spn(i, start, start + 1), Expression::Variable("a"))),
+                    op,
+                    right: Box::new((spn(i, end - 1, end), Expression::Num(num))),
+                },
+            )
+        };
+        parse_test!(
+            i,
+            Expression::Piecewise {
+                first: Box::new(Branch {
+                    cond: (
+                        spn(i, 2, 27),
+                        Expression::Logical {
+                            left: Box::new(cmp(2, CompareOperator::LessThan, "1", 7)),
+                            op: LogicalOperator::Or,
+                            right: Box::new((
+                                spn(i, 11, 27),
+                                Expression::Logical {
+                                    left: Box::new(cmp(11, CompareOperator::GreaterThan, "2", 16)),
+                                    op: LogicalOperator::And,
+                                    right: Box::new(cmp(22, CompareOperator::LessThan, "3", 27)),
+                                }
+                            )),
+                        }
+                    ),
+                    val: (spn(i, 30, 31), Expression::Num("4"))
+                }),
+                rest: vec![],
+                default: None
+            }
+        );
+        assert!(parse("{ a < 1 and: 2 }").is_err());
+    }
 }
@@ -0,0 +1,1484 @@
+use crate::{
+    compiler::{
+        compiler::{
+            check_type, compile_expect, compile_program, compile_stmt, compile_stmt_typed,
+            hidden_definition, Context,
+        },
+        error::{CompileError, CompileErrorKind},
+        source_map::{self, SourceMap},
+        transform::strip_parens,
+    },
+    core::{
+        ast::{LocatedStatement, Statement},
+        latex::{Latex, LatexFormat},
+        runtime::ValType,
+    },
+    parser::{
+        error::ParseError,
+        parser::{parse, parse_statements},
+    },
+};
+use pest::Span;
+
+#[derive(Debug)]
+pub enum EvalError<'a> {
+    ParseError(ParseError),
+    CompileError(CompileError<'a>),
+}
+
+impl From<ParseError> for EvalError<'_> {
+    fn from(err: ParseError) -> Self {
+        Self::ParseError(err)
+    }
+}
+
+impl<'a> From<CompileError<'a>> for EvalError<'a> {
+    fn from(err: CompileError<'a>) -> Self {
+        Self::CompileError(err)
+    }
+}
+
+fn join_latex(items: &[Latex]) -> String {
+    items
+        .iter()
+        .map(|l| l.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Parses and compiles a whole program, producing one line of LaTeX per statement.
+//  An empty program compiles to an empty string.
+#[allow(clippy::result_large_err)]
+pub fn compile_source(src: &str) -> Result<String, EvalError<'_>> {
+    Ok(compile_statements_typed(parse_statements(src)?)?.map_or_else(String::new, |(s, _)| s))
+}
+
+// Like compile_source, but also returns the type of the final statement. If the program
+//  ends with a function definition, its return type is used. An empty program has no
+//  final statement, so it is an error.
+#[allow(clippy::result_large_err)]
+pub fn compile_source_typed(src: &str) -> Result<(String, ValType), EvalError<'_>> {
+    compile_statements_typed(parse_statements(src)?)?.ok_or_else(|| {
+        CompileError {
+            kind: CompileErrorKind::EmptyProgram,
+            span: Span::new(src, 0, src.len()).unwrap(),
+        }
+        .into()
+    })
+}
+
+// None when there are no statements
+fn compile_statements_typed(
+    mut stmts: Vec<LocatedStatement<'_>>,
+) -> Result<Option<(String, ValType)>, CompileError<'_>> {
+    let (last_span, last) = match stmts.pop() {
+        Some(last) => last,
+        None => return Ok(None),
+    };
+
+    let mut ctx = Context::new();
+    let (mut out, _) = compile_program(&mut ctx, stmts)?;
+
+    let (latex, ret) = compile_stmt_typed(&mut ctx, (last_span, last))?;
+    if !hidden_definition(&ctx, &latex) {
+        out.push(latex);
+    }
+
+    Ok(Some((join_latex(&out), ret)))
+}
+
+// Like compile_source, but writes the shortest latex it can, for embedding in URLs.
+//  Constants are folded, parentheses from the source are left out since precedence puts
+//  back the ones that are needed, and calls without arguments are just the name.
+#[allow(clippy::result_large_err)]
+pub fn compile_source_minified(src: &str) -> Result<String, EvalError<'_>> {
+    let mut ctx = Context::new();
+    ctx.fold_constants = true;
+    let (out, _) = compile_program(&mut ctx, parse_statements(src)?)?;
+    let format = LatexFormat {
+        bare_zero_arg_calls: true,
+        ..Default::default()
+    };
+    Ok(out
+        .into_iter()
+        .map(|l| strip_parens(l).with_format(&format).to_string())
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+// Like compile_source, but also maps the output back to the source it came from
+#[allow(clippy::result_large_err)]
+pub fn compile_source_mapped(src: &str) -> Result<(String, SourceMap<'_>), EvalError<'_>> {
+    let mut ctx = Context::new();
+    let mut out = String::new();
+    let mut map = SourceMap { entries: vec![] };
+    let mut first = true;
+    for (span, stmt) in parse_statements(src)? {
+        ctx.source_spans = Some(vec![]);
+        let latex = compile_stmt(&mut ctx, (span.clone(), stmt))?;
+        let mut spans = ctx.source_spans.take().unwrap();
+        if hidden_definition(&ctx, &latex) {
+            continue;
+        }
+        if !first {
+            out.push('\n');
+        }
+        first = false;
+        // The statement itself isn't an expression, so it isn't recorded otherwise
+        spans.push((span, latex.clone()));
+        let (line, line_map) = source_map::build(&latex, spans, &LatexFormat::default());
+        map.append(line_map, out.len());
+        out.push_str(&line);
+    }
+    Ok((out, map))
+}
+
+// Compiles a single statement, erroring if its type isn't expect. For a function
+//  definition its return type is checked.
+#[allow(clippy::result_large_err)]
+pub fn compile_source_expect(src: &str, expect: ValType) -> Result<String, EvalError<'_>> {
+    let (span, stmt) = parse(src)?;
+    let mut ctx = Context::new();
+    let latex = match stmt {
+        Statement::Expression(e) => compile_expect(&mut ctx, span.clone(), (span, e), expect)?,
+        stmt => {
+            let (latex, t) = compile_stmt_typed(&mut ctx, (span.clone(), stmt))?;
+            check_type(span, t, expect)?;
+            latex
+        }
+    };
+    Ok(latex.to_string())
+}
+
+// Compiles a program one statement at a time while keeping what earlier statements
+//  defined, for embedders like a REPL that get their input piece by piece
+pub struct Compiler<'a> {
+    pub ctx: Context<'a>,
+}
+
+impl Default for Compiler<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new() -> Self {
+        Self::with_context(Context::new())
+    }
+
+    // Compiles with ctx, along with any options that have been set on it
+    pub fn with_context(ctx: Context<'a>) -> Self {
+        Self { ctx }
+    }
+
+    // Parses and compiles a single statement. When it fails the context is left as it
+    //  was, so a half-compiled definition can't affect later statements. An inline
+    //  function definition has no output of its own, so it gives None.
+    #[allow(clippy::result_large_err)]
+    pub fn compile_statement(&mut self, src: &'a str) -> Result<Option<String>, EvalError<'a>> {
+        let stmt = parse(src)?;
+        let snapshot = self.ctx.snapshot();
+        match compile_stmt(&mut self.ctx, stmt) {
+            Ok(latex) if hidden_definition(&self.ctx, &latex) => Ok(None),
+            Ok(latex) => Ok(Some(latex.to_string())),
+            Err(e) => {
+                self.ctx.restore(snapshot);
+                Err(e.into())
+            }
+        }
+    }
+
+    // Forgets everything that was defined, keeping the options of the context
+    pub fn reset(&mut self) {
+        self.ctx.reset(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Debug;
+
+    // The error that r failed to compile with
+    fn expect_compile_error<T: Debug>(r: Result<T, EvalError<'_>>) -> CompileError<'_> {
+        match r {
+            Err(EvalError::CompileError(e)) => e,
+            r => panic!("Expected a compile error, got {:?}", r),
+        }
+    }
+
+    // The kind of error that compiling src fails with
+    fn compile_err(src: &str) -> CompileErrorKind<'_> {
+        expect_compile_error(compile_source(src)).kind
+    }
+
+    #[test]
+    fn single_expression() {
+        assert_eq!(
+            compile_source("sin(1)").unwrap(),
+            "\\sin\\left(1\\right)".to_string()
+        );
+    }
+
+    #[test]
+    fn empty_program() {
+        for src in &["", "   ", "\n\t\n", ";;"] {
+            assert_eq!(compile_source(src).unwrap(), "");
+            assert_eq!(compile_source_minified(src).unwrap(), "");
+            assert_eq!(
+                expect_compile_error(compile_source_typed(src)).kind,
+                CompileErrorKind::EmptyProgram
+            );
+        }
+        assert_eq!(
+            compile_program(&mut Context::new(), vec![]).unwrap(),
+            (vec![], vec![])
+        );
+    }
+
+    #[test]
+    fn multiple_statements() {
+        assert_eq!(
+            compile_source("f(a) = a\nf(2)").unwrap(),
+            "f\\left(a\\right)=a\nf\\left(2\\right)".to_string()
+        );
+    }
+
+    #[test]
+    fn typed() {
+        assert_eq!(
+            compile_source_typed("[1,2,3]").unwrap(),
+            ("\\left[1,2,3\\right]".to_string(), ValType::List)
+        );
+        assert_eq!(compile_source_typed("sin(1)").unwrap().1, ValType::Number);
+        assert_eq!(
+            compile_source_typed("f(a: List) = a; f([1])").unwrap().1,
+            ValType::List
+        );
+        assert_eq!(
+            compile_source_typed("f(a: List) = a").unwrap().1,
+            ValType::List
+        );
+    }
+
+    #[test]
+    fn inequality_statement() {
+        assert_eq!(
+            compile_source_typed("y < x^2").unwrap(),
+            ("y<x^{2}".to_string(), ValType::Bool)
+        );
+        assert_eq!(
+            compile_source("f(a) = a*2\ny >= f(x)\nx <= 1").unwrap(),
+            "f\\left(a\\right)=a2\ny\\ge f\\left(x\\right)\nx\\le 1"
+        );
+        // x and y are only defined for the comparison itself
+        assert!(compile_source("y < x; y").is_err());
+    }
+
+    #[test]
+    fn list_concatenation() {
+        assert_eq!(
+            compile_source("[1,2] + [3,4]").unwrap(),
+            "\\operatorname{join}\\left(\\left[1,2\\right],\\left[3,4\\right]\\right)"
+        );
+        assert_eq!(
+            compile_source_typed("xs = [1]\nxs + [2] + [1...3]").unwrap(),
+            (
+                "x_{s}=\\left[1\\right]\n\
+                 \\operatorname{join}\\left(x_{s},\\left[2\\right],\\left[1...3\\right]\\right)"
+                    .to_string(),
+                ValType::List
+            )
+        );
+        // Only + joins lists
+        assert!(compile_source("[1,2] - [3,4]").is_err());
+        assert!(compile_source("[1,2] + 3").is_err());
+    }
+
+    #[test]
+    fn comparison_operand_types() {
+        assert_eq!(
+            compile_source("a = 1\nb = 2\na > b").unwrap(),
+            "a=1\nb=2\na>b"
+        );
+        let point_mismatch = CompileErrorKind::TypeMismatch {
+            got: ValType::Point,
+            expected: ValType::Number,
+        };
+        let e = expect_compile_error(compile_source("p = (1, 2)\np > 0"));
+        assert_eq!(e.kind, point_mismatch);
+        assert_eq!(e.span.as_str(), "p");
+        // Desmos can't compare points with each other either
+        let e = expect_compile_error(compile_source("p = (1, 2)\nq = (3, 4)\nq > p"));
+        assert_eq!(e.kind, point_mismatch);
+        assert_eq!(e.span.as_str(), "q");
+    }
+
+    #[test]
+    fn expected_type() {
+        assert_eq!(
+            compile_source_expect("[1,2]", ValType::List).unwrap(),
+            "\\left[1,2\\right]"
+        );
+        assert_eq!(
+            compile_source_expect("f(a) = [a]", ValType::List).unwrap(),
+            "f\\left(a\\right)=\\left[a\\right]"
+        );
+        assert_eq!(
+            expect_compile_error(compile_source_expect("sin(1)", ValType::List)).kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::Number,
+                expected: ValType::List
+            }
+        );
+    }
+
+    #[test]
+    fn if_expression() {
+        assert_eq!(
+            compile_source("f(a) = if a > 0 then 1 else -1").unwrap(),
+            "f\\left(a\\right)=\\left\\{a>0:1,-1\\right\\}".to_string()
+        );
+    }
+
+    #[test]
+    fn piecewise_default() {
+        assert_eq!(
+            compile_source("f(a) = {a > 0: 1, _: 2}").unwrap(),
+            "f\\left(a\\right)=\\left\\{a>0:1,2\\right\\}"
+        );
+        assert_eq!(
+            compile_source("f(a) = {a > 0: 1}").unwrap(),
+            "f\\left(a\\right)=\\left\\{a>0:1\\right\\}"
+        );
+    }
+
+    #[test]
+    fn list_piecewise() {
+        assert_eq!(
+            compile_source_typed("f(a) = {a > 0: [1, 2], a < 0: [3], _: [4, 5]}\nf(1)").unwrap(),
+            (
+                "f\\left(a\\right)=\\left\\{a>0:\\left[1,2\\right],a<0:\\left[3\\right],\\left[4,5\\right]\\right\\}\nf\\left(1\\right)"
+                    .to_string(),
+                ValType::List
+            )
+        );
+        for src in &[
+            "f(a) = {a > 0: [1], _: 2}",
+            "f(a) = {a > 0: 1, a < 0: [2], _: 3}",
+        ] {
+            let e = expect_compile_error(compile_source(src));
+            assert!(
+                matches!(e.kind, CompileErrorKind::TypeMismatch { .. }),
+                "{:?}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn map_lambda() {
+        assert_eq!(
+            compile_source_typed("map!(x => x*2, [1,2,3])").unwrap(),
+            (
+                "\\left[x2\\operatorname{for}x=\\left[1,2,3\\right]\\right]".to_string(),
+                ValType::List
+            )
+        );
+    }
+
+    #[test]
+    fn list_returning_function() {
+        assert_eq!(
+            compile_source_typed("pair(x) = [x, x]; pair(1)").unwrap(),
+            (
+                "pair\\left(x\\right)=\\left[x,x\\right]\npair\\left(1\\right)".to_string(),
+                ValType::List
+            )
+        );
+        assert_eq!(
+            compile_source_typed("pair(x) = [x, x]; total(pair(1))")
+                .unwrap()
+                .1,
+            ValType::Number
+        );
+        assert_eq!(
+            compile_source_typed("pair(x) = [x, x]; f(a: List): List = a; f(pair(1))")
+                .unwrap()
+                .1,
+            ValType::List
+        );
+        assert_eq!(
+            compile_err("pair(x) = [x, x]; sin(pair(1))"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+    }
+
+    #[test]
+    fn absolute_value() {
+        assert_eq!(
+            compile_source("f(x) = ||x| - 1|").unwrap(),
+            "f\\left(x\\right)=\\left|\\left|x\\right|-1\\right|"
+        );
+        assert_eq!(compile_source("abs(-2)").unwrap(), "\\left|-2\\right|");
+        assert_eq!(
+            compile_err("|[1, 2]|"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+    }
+
+    #[test]
+    fn negation_precedence() {
+        assert_eq!(
+            compile_source("f(x) = -x^2").unwrap(),
+            "f\\left(x\\right)=-x^{2}"
+        );
+        assert_eq!(
+            compile_source("f(x) = -(x^2)").unwrap(),
+            "f\\left(x\\right)=-\\left(x^{2}\\right)"
+        );
+        assert_eq!(
+            compile_source("f(x) = (-x)^2").unwrap(),
+            "f\\left(x\\right)=\\left(-x\\right)^{2}"
+        );
+        // Factorials bind tighter than negation and exponents
+        assert_eq!(compile_source("-3!").unwrap(), "-3!");
+        assert_eq!(
+            compile_source("f(x) = -x!").unwrap(),
+            "f\\left(x\\right)=-x!"
+        );
+        assert_eq!(
+            compile_source("f(x) = 2^x!").unwrap(),
+            "f\\left(x\\right)=2^{x!}"
+        );
+        assert_eq!(
+            compile_source("f(x) = x!^2").unwrap(),
+            "f\\left(x\\right)=x!^{2}"
+        );
+        assert_eq!(
+            compile_source("f(x) = (-x)!").unwrap(),
+            "f\\left(x\\right)=\\left(-x\\right)!"
+        );
+    }
+
+    #[test]
+    fn inline_functions() {
+        let program = "inline sq(a) = a^2\nc = 2\nb = sq(c) + sq(3)";
+        assert_eq!(compile_source(program).unwrap(), "c=2\nb=c^{2}+3^{2}");
+        let (out, map) = compile_source_mapped(program).unwrap();
+        assert_eq!(out, "c=2\nb=c^{2}+3^{2}");
+        assert_eq!(map.span_at(0).unwrap().as_str(), "c = 2");
+        // Other definitions are still emitted, even ones that end up being inlined
+        assert_eq!(
+            compile_source("inline f(a) = a + 1\ng(a) = f(a) * 2\ng(1)").unwrap(),
+            "g\\left(a\\right)=\\left(a+1\\right)2\ng\\left(1\\right)"
+        );
+        // A global used by the body can't be pasted where a param shadows it
+        assert_eq!(
+            compile_err("c = 2\ninline f(a) = a + c\ng(c) = f(1)"),
+            CompileErrorKind::CapturedVariable("c")
+        );
+        assert_eq!(
+            compile_err("c = 2\ninline f(a) = a + c\ny = [f(1) for c=[1...3]]"),
+            CompileErrorKind::CapturedVariable("c")
+        );
+        // A let binding is substituted away, so it doesn't shadow anything
+        assert_eq!(
+            compile_source("c = 2\ninline f(a) = a + c\ng(b) = let c = b in f(c)").unwrap(),
+            "c=2\ng\\left(b\\right)=b+c"
+        );
+        // Only a whole word is the keyword
+        assert_eq!(
+            compile_source("inlined(a) = a").unwrap(),
+            "inlined\\left(a\\right)=a"
+        );
+    }
+
+    #[test]
+    fn inline_sum_variables() {
+        // The sum variable isn't replaced by the argument
+        assert_eq!(
+            compile_source("inline p(j) = sum(1, 3, j => j)\nq(a) = p(7)").unwrap(),
+            "q\\left(a\\right)=\\sum_{j=1}^{3}j"
+        );
+        // And doesn't capture a variable of the same name in the argument
+        assert_eq!(
+            compile_source("inline g(k) = sum(1, 3, j => j*k)\nj = 2\nh(a) = g(j)").unwrap(),
+            "j=2\nh\\left(a\\right)=\\sum_{j_{1}=1}^{3}j_{1}j"
+        );
+    }
+
+    #[test]
+    fn default_args() {
+        assert_eq!(
+            compile_source("f(a, b = 1) = a + b\nf(2)\nf(2, 3)").unwrap(),
+            "f\\left(a,b\\right)=a+b\n\
+             f\\left(2,1\\right)\n\
+             f\\left(2,3\\right)"
+        );
+        assert_eq!(
+            compile_err("f(a, b = 1) = a + b\nf()"),
+            CompileErrorKind::WrongArgCount {
+                got: 0,
+                expected: 2
+            }
+        );
+        assert_eq!(
+            compile_err("f(a, b: List = 1) = a"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::Number,
+                expected: ValType::List
+            }
+        );
+        // A default that uses a global can't be filled in where the global is shadowed
+        assert_eq!(
+            compile_source("c = 5\nf(a, b = c) = a + b\ng(d) = f(d)").unwrap(),
+            "c=5\n\
+             f\\left(a,b\\right)=a+b\n\
+             g\\left(d\\right)=f\\left(d,c\\right)"
+        );
+        for src in &[
+            "c = 5\nf(a, b = c) = a + b\ng(c) = f(c)",
+            "c = 5\nf(a, b = c) = a + b\n[f(1) for c=[1...3]]",
+        ] {
+            assert_eq!(compile_err(src), CompileErrorKind::CapturedVariable("c"));
+        }
+    }
+
+    #[test]
+    fn call_results_in_variables() {
+        let program = "f(n) = [1...n]\na = f(3)\nsort(a)";
+        assert_eq!(
+            compile_source_typed(program).unwrap(),
+            (
+                "f\\left(n\\right)=\\left[1...n\\right]\n\
+                 a=f\\left(3\\right)\n\
+                 \\operatorname{sort}\\left(a\\right)"
+                    .to_string(),
+                ValType::List
+            )
+        );
+        assert_eq!(
+            compile_source_typed("f(n) = [1...n]\na = f(3)\na[2]")
+                .unwrap()
+                .1,
+            ValType::Number
+        );
+        assert_eq!(
+            compile_err("f(n) = n + 1\na = f(3)\na[2]"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::Number,
+                expected: ValType::List
+            }
+        );
+    }
+
+    #[test]
+    fn list_variables() {
+        assert_eq!(
+            compile_source_typed("a = [1, 2, 3]\na[2]").unwrap(),
+            (
+                "a=\\left[1,2,3\\right]\na\\left[2\\right]".to_string(),
+                ValType::Number
+            )
+        );
+        assert_eq!(
+            compile_source_typed("a = [1, 2, 3]\na[[1, 3]]").unwrap().1,
+            ValType::List
+        );
+        assert_eq!(
+            compile_source_typed("p = [(1, 2)]\np[1]").unwrap().1,
+            ValType::Point
+        );
+        assert_eq!(
+            compile_source_typed("a = [1, 2]").unwrap(),
+            ("a=\\left[1,2\\right]".to_string(), ValType::List)
+        );
+        let e = expect_compile_error(compile_source("a = 1\na[2]"));
+        assert_eq!(
+            e.kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::Number,
+                expected: ValType::List
+            }
+        );
+        assert_eq!(e.span.as_str(), "a");
+        // x and y are still coordinates
+        assert_eq!(compile_source("y = x^2").unwrap(), "y=x^{2}");
+    }
+
+    #[test]
+    fn factorials() {
+        assert_eq!(
+            compile_source("f(x) = x!!").unwrap(),
+            "f\\left(x\\right)=\\prod_{k=0}^{\\operatorname{ceil}\\left(\\frac{x}{2}\\right)-1}\\left(x-2k\\right)"
+        );
+        // The product variable doesn't capture the operand
+        assert_eq!(
+            compile_source("f(k) = k!!").unwrap(),
+            "f\\left(k\\right)=\\prod_{k_{1}=0}^{\\operatorname{ceil}\\left(\\frac{k}{2}\\right)-1}\\left(k-2k_{1}\\right)"
+        );
+        assert_eq!(
+            compile_source("f(a, b) = (a+b)!").unwrap(),
+            "f\\left(a,b\\right)=\\left(a+b\\right)!"
+        );
+        // Two factorials in a row are grouped so they can't be read as a double factorial
+        assert_eq!(
+            compile_source("f(x) = x! !").unwrap(),
+            "f\\left(x\\right)=\\left(x!\\right)!"
+        );
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(compile_source("50%").unwrap(), "\\frac{50}{100}");
+        // Set apart, it is a mod wherever its operand starts
+        for src in &["f(a, b) = a %\n  b", "f(a, b) = a % - b"] {
+            assert!(
+                compile_source(src)
+                    .unwrap()
+                    .contains("\\operatorname{mod}\\left(a,"),
+                "{}",
+                src
+            );
+        }
+        // A statement can end with a percentage
+        assert_eq!(
+            compile_source("a = 50%\nb = 2").unwrap(),
+            "a=\\frac{50}{100}\nb=2"
+        );
+        assert_eq!(
+            compile_source("f(a, b) = (a+b)% * 2").unwrap(),
+            "f\\left(a,b\\right)=\\frac{a+b}{100}2"
+        );
+        assert_eq!(
+            compile_source("[i% for i=[1...3]]").unwrap(),
+            "\\left[\\frac{i}{100}\\operatorname{for}i=\\left[1...3\\right]\\right]"
+        );
+        assert_eq!(
+            compile_err("[1, 2]%"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+    }
+
+    #[test]
+    fn calling_variables() {
+        for src in &["a = 2\na(1)", "f(a) = a(2)", "let a = 1 in a(2)"] {
+            let e = expect_compile_error(compile_source(src));
+            assert_eq!(e.kind, CompileErrorKind::NotAFunction("a"));
+            assert_eq!(
+                e.to_string().lines().last().unwrap().trim(),
+                "= 'a' is a variable, not a function"
+            );
+        }
+        assert_eq!(compile_err("b(1)"), CompileErrorKind::UnknownFunction("b"));
+    }
+
+    #[test]
+    fn and_or_conditions() {
+        // Each alternative of an or gets its own branch
+        assert_eq!(
+            compile_source("f(a) = {a < 0 or a > 10: 1, otherwise: 2}").unwrap(),
+            "f\\left(a\\right)=\\left\\{a<0:1,a>10:1,2\\right\\}"
+        );
+        // An and is the condition that the indicators of its comparisons multiply to 1
+        assert_eq!(
+            compile_source("f(a) = {a > 0 and a < 10: 1, otherwise: 2}").unwrap(),
+            "f\\left(a\\right)=\\left\\{\\left\\{a>0\\right\\}\\left\\{a<10\\right\\}=1:1,2\\right\\}"
+        );
+        // And the later branches are still tried when it fails
+        assert_eq!(
+            compile_source("f(a) = {a > 0 and a < 10: 1, a = 20: 3}").unwrap(),
+            "f\\left(a\\right)=\\left\\{\\left\\{a>0\\right\\}\\left\\{a<10\\right\\}=1:1,\
+             a=20:3\\right\\}"
+        );
+        assert_eq!(
+            compile_source("f(a) = if a > 0 or a < -5 and a > -10 then 1 else 2").unwrap(),
+            "f\\left(a\\right)=\\left\\{a>0:1,\\left\\{a<-5\\right\\}\\left\\{a>-10\\right\\}=1:1,\
+             2\\right\\}"
+        );
+        // Each and only adds its own comparisons, so the output grows linearly with the
+        //  number of branches
+        let branches: Vec<String> = (0..18)
+            .map(|i| format!("a > {} and a < {}: {}", i, i + 1, i))
+            .collect();
+        let src = format!("f(a) = {{{}, otherwise: 0}}", branches.join(", "));
+        assert!(compile_source(&src).unwrap().len() < 18 * 100);
+    }
+
+    #[test]
+    fn indicators() {
+        assert_eq!(
+            compile_source_typed("f(a) = 2 * {a > 0}").unwrap(),
+            (
+                "f\\left(a\\right)=2\\left\\{a>0\\right\\}".to_string(),
+                ValType::Number
+            )
+        );
+        assert_eq!(
+            compile_source("f(a) = {a > 0 and a < 1} * a").unwrap(),
+            "f\\left(a\\right)=\\left\\{a>0\\right\\}\\left\\{a<1\\right\\}a"
+        );
+        assert_eq!(
+            compile_source("f(a) = {a < 0 or a > 1}").unwrap(),
+            "f\\left(a\\right)=\\left\\{a<0:1,a>1:1\\right\\}"
+        );
+        // With a result it is still a piecewise
+        assert_eq!(
+            compile_source("f(a) = {a > 0: 2}").unwrap(),
+            "f\\left(a\\right)=\\left\\{a>0:2\\right\\}"
+        );
+    }
+
+    #[test]
+    fn exponent_towers() {
+        // ^ is right associative, so only a parenthesized base keeps its parentheses
+        assert_eq!(compile_source("y = 2^3^2").unwrap(), "y=2^{3^{2}}");
+        assert_eq!(compile_source("y = 2^2^2^2").unwrap(), "y=2^{2^{2^{2}}}");
+        assert_eq!(
+            compile_source("y = (2^3)^2").unwrap(),
+            "y=\\left(2^{3}\\right)^{2}"
+        );
+    }
+
+    #[test]
+    fn restrictions() {
+        assert_eq!(
+            compile_source("y = x^2 {x > 0}").unwrap(),
+            "y=x^{2}\\left\\{x>0\\right\\}"
+        );
+        assert_eq!(
+            compile_source("f(a) = a + 1 {a > 0} {a < 3}").unwrap(),
+            "f\\left(a\\right)=\\left(a+1\\right)\\left\\{a>0\\right\\}\\left\\{a<3\\right\\}"
+        );
+        assert_eq!(
+            compile_source("f(a) = a {a < 0 or a > 1}").unwrap(),
+            "f\\left(a\\right)=a\\left\\{a<0:1,a>1:1\\right\\}"
+        );
+        // Only numbers can be restricted
+        assert_eq!(
+            compile_err("f(a) = [1, 2] {a > 0}"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+    }
+
+    #[test]
+    fn unary_plus() {
+        assert_eq!(compile_source("f(x) = +x").unwrap(), "f\\left(x\\right)=x");
+        assert_eq!(
+            compile_source("f(a, b) = +(a+b)").unwrap(),
+            "f\\left(a,b\\right)=a+b"
+        );
+        assert_eq!(
+            compile_source("f(a, b) = 2*+(a+b)").unwrap(),
+            "f\\left(a,b\\right)=2\\left(a+b\\right)"
+        );
+        assert_eq!(compile_source("+5").unwrap(), "5");
+        assert_eq!(
+            compile_err("+[1, 2]"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+    }
+
+    #[test]
+    fn parentheses_kept() {
+        assert_eq!(compile_source("(1+2)*3").unwrap(), "\\left(1+2\\right)3");
+        assert_eq!(
+            compile_source_typed("([1, 2])").unwrap(),
+            (
+                "\\left(\\left[1,2\\right]\\right)".to_string(),
+                ValType::List
+            )
+        );
+    }
+
+    #[test]
+    fn repeat_builtin() {
+        assert_eq!(
+            compile_source_typed("repeat(0, 5)").unwrap(),
+            (
+                "0\\cdot \\left[1...5\\right]^{0}".to_string(),
+                ValType::List
+            )
+        );
+    }
+
+    #[test]
+    fn let_bindings() {
+        assert_eq!(
+            compile_source("f(x) = let a = x*2 in let b = a+1 in a*b").unwrap(),
+            "f\\left(x\\right)=\\left(x2\\right)\\left(\\left(x2\\right)+1\\right)"
+        );
+        // A variable bound in the body is renamed when a value it would capture is
+        //  pasted into its scope
+        assert_eq!(
+            compile_source("f(n) = let a = n in sum!(n in [1...3], a)").unwrap(),
+            "f\\left(n\\right)=\\sum_{n_{1}=1}^{3}n"
+        );
+        assert_eq!(
+            compile_source("f(n) = let a = n in [a + n for n=[1,2]]").unwrap(),
+            "f\\left(n\\right)=\\left[n+n_{1}\\operatorname{for}n_{1}=\\left[1,2\\right]\\right]"
+        );
+        assert_eq!(
+            compile_source("f(n) = let a = n in sum(1, 3, n => a*n)").unwrap(),
+            "f\\left(n\\right)=\\sum_{n_{1}=1}^{3}nn_{1}"
+        );
+        // Including by the name another variable was renamed to
+        assert_eq!(
+            compile_source("f(n) = let a = n in sum!(n in [1...3], sum!(n1 in [1...2], a+n+n1))")
+                .unwrap(),
+            "f\\left(n\\right)=\\sum_{n_{1}=1}^{3}\\left(\\sum_{n_{11}=1}^{2}\\left(n+n_{1}+n_{11}\\right)\\right)"
+        );
+    }
+
+    #[test]
+    fn scientific_notation() {
+        assert_eq!(compile_source("1e5").unwrap(), "1\\cdot10^{5}");
+        assert_eq!(compile_source("2.5e-3").unwrap(), "2.5\\cdot10^{-3}");
+        assert_eq!(compile_source("100").unwrap(), "100");
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let src = "f(a) = a / 0";
+        let e = expect_compile_error(compile_source(src));
+        assert_eq!(e.kind, CompileErrorKind::DivisionByZero);
+        assert_eq!(e.span.as_str(), "0");
+    }
+
+    #[test]
+    fn comprehension() {
+        assert_eq!(
+            compile_source_typed("[i^2 for i=[1...5]]").unwrap(),
+            (
+                "\\left[i^{2}\\operatorname{for}i=\\left[1...5\\right]\\right]".to_string(),
+                ValType::List
+            )
+        );
+    }
+
+    #[test]
+    fn point_comprehension() {
+        assert_eq!(
+            compile_source_typed("[(i,j) for i=[1...3], j=[1...3]]").unwrap(),
+            (
+                "\\left[\\left(i,j\\right)\\operatorname{for}i=\\left[1...3\\right],j=\\left[1...3\\right]\\right]".to_string(),
+                ValType::PointList
+            )
+        );
+    }
+
+    #[test]
+    fn parametric_points() {
+        assert_eq!(
+            compile_source_typed("p = [(t, t^2) for t=[0...10]]\npolygon(p)").unwrap(),
+            (
+                "p=\\left[\\left(t,t^{2}\\right)\\operatorname{for}t=\\left[0...10\\right]\\right]\n\
+                 \\operatorname{polygon}\\left(p\\right)"
+                    .to_string(),
+                ValType::Polygon
+            )
+        );
+        // Every item has to be a point, so the body can't be a point in only some cases
+        for src in &[
+            "[if t > 5 then (t, t) else t for t=[0...10]]",
+            "[{t > 5: t, _: (t, t)} for t=[0...10]]",
+        ] {
+            let e = expect_compile_error(compile_source(src));
+            assert!(matches!(e.kind, CompileErrorKind::TypeMismatch { .. }));
+        }
+    }
+
+    #[test]
+    fn point_arithmetic() {
+        for (src, out) in &[
+            ("(1, 2) + (3, 4)", "\\left(1,2\\right)+\\left(3,4\\right)"),
+            ("(1, 2) - (3, 4)", "\\left(1,2\\right)-\\left(3,4\\right)"),
+            ("2 * (1, 2)", "2\\cdot \\left(1,2\\right)"),
+            ("(1, 2) * 2", "\\left(1,2\\right)\\cdot 2"),
+            ("(1, 2) / 2", "\\frac{\\left(1,2\\right)}{2}"),
+        ] {
+            assert_eq!(
+                compile_source_typed(src).unwrap(),
+                (out.to_string(), ValType::Point)
+            );
+        }
+        assert_eq!(
+            compile_source_typed("p = (1, 2)\nf(a) = a * p + p").unwrap(),
+            (
+                "p=\\left(1,2\\right)\nf\\left(a\\right)=ap+p".to_string(),
+                ValType::Point
+            )
+        );
+
+        for (src, got, expected) in &[
+            ("(1, 2) * (3, 4)", ValType::Point, ValType::Number),
+            ("(1, 2) + 1", ValType::Number, ValType::Point),
+            ("1 - (1, 2)", ValType::Point, ValType::Number),
+            ("2 / (1, 2)", ValType::Point, ValType::Number),
+            ("(1, 2)^2", ValType::Point, ValType::Number),
+            ("(1, 2) * [1, 2]", ValType::List, ValType::Number),
+        ] {
+            let e = expect_compile_error(compile_source(src));
+            assert_eq!(
+                e.kind,
+                CompileErrorKind::TypeMismatch {
+                    got: *got,
+                    expected: *expected
+                }
+            );
+            assert_eq!(e.span.as_str(), *src);
+        }
+    }
+
+    #[test]
+    fn total_of_map() {
+        assert_eq!(
+            compile_source_typed("square(x) = x*x; total(map!(square, [1,2,3]))")
+                .unwrap()
+                .1,
+            ValType::Number
+        );
+        assert_eq!(
+            compile_source_typed("sin@([1,2,3])").unwrap().1,
+            ValType::List
+        );
+    }
+
+    #[test]
+    fn multi_arg_map_call() {
+        let def = "add(a, b) = a + b\n";
+        assert_eq!(
+            compile_source_typed(&format!("{}add@([1, 2], [3, 4])", def)).unwrap(),
+            (
+                "add\\left(a,b\\right)=a+b\nadd\\left(\\left[1,2\\right],\\left[3,4\\right]\\right)"
+                    .to_string(),
+                ValType::List
+            )
+        );
+        // A scalar is passed to every call
+        assert_eq!(
+            compile_source_typed(&format!("{}add@([1, 2], 1)", def))
+                .unwrap()
+                .1,
+            ValType::List
+        );
+        // Nothing to map over
+        assert_eq!(
+            compile_source_typed(&format!("{}add@(1, 2)", def))
+                .unwrap()
+                .1,
+            ValType::Number
+        );
+    }
+
+    #[test]
+    fn pipe() {
+        assert_eq!(
+            compile_source_typed("[1,2,3] |> sort |> total").unwrap(),
+            (
+                "\\operatorname{total}\\left(\\operatorname{sort}\\left(\\left[1,2,3\\right]\\right)\\right)"
+                    .to_string(),
+                ValType::Number
+            )
+        );
+        let e = expect_compile_error(compile_source("[1,2] |> sort |> join"));
+        assert_eq!(
+            e.kind,
+            CompileErrorKind::WrongArgCount {
+                got: 1,
+                expected: 2
+            }
+        );
+        assert_eq!(e.span.as_str(), "join");
+        // Bars around a pipe need parentheses, which keeps this a comparison
+        assert_eq!(
+            compile_source("f(x) = if |x|>0 then |(x |> sin)| else 0").unwrap(),
+            "f\\left(x\\right)=\\left\\{\\left|x\\right|>0:\\left|\\left(\\sin\\left(x\\right)\\right)\\right|,0\\right\\}"
+        );
+    }
+
+    #[test]
+    fn list_arithmetic_in_map() {
+        // @(...) maps over the lists in an expression without a function
+        assert_eq!(
+            compile_source_typed("a = @([1, 2] * 2)").unwrap(),
+            ("a=\\left[1,2\\right]2".to_string(), ValType::List)
+        );
+        assert_eq!(compile_err("@(sin@([1]))"), CompileErrorKind::NestedMap);
+        assert_eq!(
+            compile_source_typed("sin@([1, 2] + [3, 4])").unwrap(),
+            (
+                "\\sin\\left(\\left[1,2\\right]+\\left[3,4\\right]\\right)".to_string(),
+                ValType::List
+            )
+        );
+        assert_eq!(
+            compile_source_typed("map!(sin, 2 * [1, 2])").unwrap().1,
+            ValType::List
+        );
+        // Unary operators are broadcast over too
+        assert_eq!(
+            compile_source_typed("sin@(-[1, 2])").unwrap(),
+            (
+                "\\sin\\left(-\\left[1,2\\right]\\right)".to_string(),
+                ValType::List
+            )
+        );
+        assert_eq!(
+            compile_source("sin@([1, 2]!)").unwrap(),
+            "\\sin\\left(\\left[1,2\\right]!\\right)"
+        );
+        assert_eq!(
+            compile_err("-[1, 2]"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+        assert_eq!(
+            compile_err("sin@([1, 2]!!)"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+        assert_eq!(
+            compile_source_typed("f(a) = a^2\nmap!(f, [1, 2, 3])").unwrap(),
+            (
+                "f\\left(a\\right)=a^{2}\nf\\left(\\left[1,2,3\\right]\\right)".to_string(),
+                ValType::List
+            )
+        );
+        assert_eq!(
+            compile_source_typed("map!(sin, [1, 2]^2)").unwrap(),
+            (
+                "\\sin\\left(\\left[1,2\\right]^{2}\\right)".to_string(),
+                ValType::List
+            )
+        );
+        // The exponent is never broadcast over
+        let e = expect_compile_error(compile_source("map!(sin, 2^[1, 2])"));
+        assert_eq!(
+            e.kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+        assert_eq!(e.span.as_str(), "[1, 2]");
+        // Outside of a map only + works on lists, where it joins them
+        assert_eq!(
+            compile_err("[1, 2] - [3, 4]"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::Number
+            }
+        );
+        // Lists can be parsed anywhere a term can, but still can't be nested
+        assert_eq!(compile_err("[[1], 2]"), CompileErrorKind::NoNestedList);
+    }
+
+    #[test]
+    fn nested_map() {
+        for (src, inner) in &[
+            ("map!(sin, map!(cos, [1, 2]))", "map!(cos, [1, 2])"),
+            ("sin@(cos@([1, 2]))", "cos@([1, 2])"),
+            (
+                "map!(sin, map!(x => x + 1, [1, 2]))",
+                "map!(x => x + 1, [1, 2])",
+            ),
+        ] {
+            let e = expect_compile_error(compile_source(src));
+            assert_eq!(e.kind, CompileErrorKind::NestedMap);
+            assert_eq!(e.span.as_str(), *inner);
+        }
+        // The list a lambda maps over isn't inside the map
+        assert_eq!(
+            compile_source("map!(x => x + 1, map!(sin, [1, 2]))").unwrap(),
+            "\\left[x+1\\operatorname{for}x=\\sin\\left(\\left[1,2\\right]\\right)\\right]"
+        );
+    }
+
+    #[test]
+    fn map_needs_list() {
+        assert_eq!(
+            compile_source("xs = [1, 2]\nmap!(sin, xs)").unwrap(),
+            "x_{s}=\\left[1,2\\right]\n\\sin\\left(x_{s}\\right)"
+        );
+        for src in &["map!(sin, 1)", "add(a, b) = a + b\nmap!(add, 1, 2)"] {
+            let e = expect_compile_error(compile_source(src));
+            assert_eq!(e.kind, CompileErrorKind::BadMapMacro);
+            assert!(e.span.as_str().starts_with("map!"));
+        }
+    }
+
+    #[test]
+    fn range_directions() {
+        // Desmos counts down when the end is below the start, so the bounds are kept in order
+        assert_eq!(compile_source("[1...5]").unwrap(), "\\left[1...5\\right]");
+        assert_eq!(compile_source("[5...1]").unwrap(), "\\left[5...1\\right]");
+        assert_eq!(
+            compile_source("[-3 ... -1]").unwrap(),
+            "\\left[-3...-1\\right]"
+        );
+        assert_eq!(
+            compile_source("[2...-(2)]").unwrap(),
+            "\\left[2...-\\left(2\\right)\\right]"
+        );
+    }
+
+    #[test]
+    fn total_of_comprehension() {
+        assert_eq!(
+            compile_source_typed("f(n) = total([i^2 for i=[1...n]])").unwrap(),
+            (
+                "f\\left(n\\right)=\\operatorname{total}\\left(\\left[i^{2}\\operatorname{for}\
+                 i=\\left[1...n\\right]\\right]\\right)"
+                    .to_string(),
+                ValType::Number
+            )
+        );
+        // A comprehension of points is a list of points, which total doesn't take
+        let e = expect_compile_error(compile_source("total([(i, i) for i=[1...3]])"));
+        assert_eq!(
+            e.kind,
+            CompileErrorKind::TypeMismatch {
+                got: ValType::PointList,
+                expected: ValType::List
+            }
+        );
+        assert_eq!(e.span.as_str(), "[(i, i) for i=[1...3]]");
+    }
+
+    #[test]
+    fn empty_lists() {
+        assert_eq!(
+            compile_source_typed("[]").unwrap(),
+            ("\\left[\\right]".to_string(), ValType::List)
+        );
+        assert_eq!(
+            compile_source("total([])").unwrap(),
+            "\\operatorname{total}\\left(\\left[\\right]\\right)"
+        );
+        // An empty list can be a list of points too
+        assert_eq!(
+            compile_source_typed("polygon([])").unwrap(),
+            (
+                "\\operatorname{polygon}\\left(\\left[\\right]\\right)".to_string(),
+                ValType::Polygon
+            )
+        );
+        // But a list with items has their type
+        assert_eq!(
+            compile_err("polygon([1])"),
+            CompileErrorKind::TypeMismatch {
+                got: ValType::List,
+                expected: ValType::PointList
+            }
+        );
+    }
+
+    #[test]
+    fn sum_macro() {
+        assert_eq!(
+            compile_source_typed("sum!(x in [1,2,3], x^2)").unwrap(),
+            (
+                "\\operatorname{total}\\left(\\left[x^{2}\\operatorname{for}x=\\left[1,2,3\\right]\\right]\\right)"
+                    .to_string(),
+                ValType::Number
+            )
+        );
+        assert_eq!(
+            compile_source("sum!(i in [1...10], i^2 + 1)").unwrap(),
+            "\\sum_{i=1}^{10}\\left(i^{2}+1\\right)"
+        );
+        assert_eq!(
+            compile_source("sum!(i in [10...1], i)").unwrap(),
+            "\\sum_{i=1}^{10}i"
+        );
+        // The variable has the type of the items
+        assert_eq!(
+            compile_source_typed("sum!(p in [(1,2),(3,4)], p)")
+                .unwrap()
+                .1,
+            ValType::Point
+        );
+        for (src, kind) in &[
+            ("sum!([1], 2)", CompileErrorKind::BadSumMacro),
+            ("sum!(i in [1], i, 2)", CompileErrorKind::BadSumMacro),
+            ("sin(i in [1])", CompileErrorKind::UnexpectedBinding),
+            (
+                "sum!(i in 3, i)",
+                CompileErrorKind::TypeMismatch {
+                    got: ValType::Number,
+                    expected: ValType::List,
+                },
+            ),
+        ] {
+            assert_eq!(compile_err(src), *kind);
+        }
+    }
+
+    #[test]
+    fn sum_function() {
+        assert_eq!(
+            compile_source("sum(1, 10, i => i^2)").unwrap(),
+            compile_source("sum!(i in [1...10], i^2)").unwrap()
+        );
+        // Bounds that count down are summed upwards, like a descending range
+        assert_eq!(
+            compile_source("sum(5, 1, i => i)").unwrap(),
+            "\\sum_{i=1}^{5}i"
+        );
+        assert_eq!(
+            compile_source("sum(5, 1, i => i)").unwrap(),
+            compile_source("sum!(i in [5...1], i)").unwrap()
+        );
+        assert_eq!(
+            compile_source("f(n) = sum(1, n, i => i + n)").unwrap(),
+            "f\\left(n\\right)=\\sum_{i=1}^{n}\\left(i+n\\right)"
+        );
+        // A function named sum is called like any other
+        assert_eq!(
+            compile_source("sum(a) = a\nsum(1)").unwrap(),
+            "sum\\left(a\\right)=a\nsum\\left(1\\right)"
+        );
+        for (src, kind) in &[
+            (
+                "sum(1, i => i)",
+                CompileErrorKind::WrongArgCount {
+                    got: 2,
+                    expected: 3,
+                },
+            ),
+            ("sum(1, 10, 2)", CompileErrorKind::ExpectedFunction),
+            (
+                "sum(1, 10, i => [i])",
+                CompileErrorKind::TypeMismatch {
+                    got: ValType::List,
+                    expected: ValType::Number,
+                },
+            ),
+        ] {
+            assert_eq!(compile_err(src), *kind);
+        }
+    }
+
+    #[test]
+    fn operator_names() {
+        assert_eq!(
+            compile_source("f(x) = sign(x)").unwrap(),
+            "f\\left(x\\right)=\\operatorname{sign}\\left(x\\right)"
+        );
+        assert_eq!(
+            compile_source("f(x) = sin(x)").unwrap(),
+            "f\\left(x\\right)=\\sin\\left(x\\right)"
+        );
+        assert_eq!(
+            compile_source("max([1, 2]) + round(1.5)").unwrap(),
+            "\\max\\left(\\left[1,2\\right]\\right)+\\operatorname{round}\\left(1.5\\right)"
+        );
+        // mod has no command in desmos, so neither does the operator
+        for src in &[
+            "f(a, b) = a mod b",
+            "f(a, b) = a % b",
+            "f(a, b) = mod(a, b)",
+        ] {
+            assert_eq!(
+                compile_source(src).unwrap(),
+                "f\\left(a,b\\right)=\\operatorname{mod}\\left(a,b\\right)"
+            );
+        }
+    }
+
+    #[test]
+    fn builtin_aliases() {
+        assert_eq!(
+            compile_source("asin(1)").unwrap(),
+            compile_source("arcsin(1)").unwrap()
+        );
+        assert_eq!(
+            compile_source("asin(1)").unwrap(),
+            "\\arcsin\\left(1\\right)"
+        );
+        assert_eq!(
+            compile_source("absoluteValue(-1)").unwrap(),
+            "\\left|-1\\right|"
+        );
+        // User functions take priority over aliases
+        assert_eq!(
+            compile_source("asin(a) = a\nasin(1)").unwrap(),
+            "asin\\left(a\\right)=a\nasin\\left(1\\right)"
+        );
+    }
+
+    #[test]
+    fn polygon() {
+        assert_eq!(
+            compile_source_typed("polygon([(0,0),(1,0),(0,1)])").unwrap(),
+            (
+                "\\operatorname{polygon}\\left(\\left[\\left(0,0\\right),\\left(1,0\\right),\\left(0,1\\right)\\right]\\right)"
+                    .to_string(),
+                ValType::Polygon
+            )
+        );
+        let mismatch = |src, got, expected| {
+            assert_eq!(
+                compile_err(src),
+                CompileErrorKind::TypeMismatch { got, expected }
+            )
+        };
+        mismatch("polygon([0, 1])", ValType::List, ValType::PointList);
+        // Every item has the type of the first one
+        mismatch("[(0,0), 1]", ValType::Number, ValType::Point);
+        mismatch("[1, (0,0)]", ValType::Point, ValType::Number);
+    }
+
+    #[test]
+    fn div_builtin() {
+        assert_eq!(
+            compile_source("div(7,2)").unwrap(),
+            "\\left\\lfloor\\frac{7}{2}\\right\\rfloor"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            compile_source("1 +"),
+            Err(EvalError::ParseError(_))
+        ));
+        assert!(matches!(
+            compile_source("sin([1])"),
+            Err(EvalError::CompileError(_))
+        ));
+    }
+
+    #[test]
+    fn compiler_keeps_definitions() {
+        let mut compiler = Compiler::new();
+        assert_eq!(
+            compiler.compile_statement("f(a) = a + 1").unwrap(),
+            Some("f\\left(a\\right)=a+1".to_string())
+        );
+        assert_eq!(
+            compiler.compile_statement("f(2)").unwrap(),
+            Some("f\\left(2\\right)".to_string())
+        );
+        // Inline definitions aren't output, but can still be called
+        assert_eq!(
+            compiler.compile_statement("inline g(a) = a * 2").unwrap(),
+            None
+        );
+        assert_eq!(
+            compiler.compile_statement("g(3)").unwrap(),
+            Some("3\\cdot 2".to_string())
+        );
+        // A statement that fails doesn't define anything
+        assert!(compiler.compile_statement("h(a) = a + b").is_err());
+        assert_eq!(
+            expect_compile_error(compiler.compile_statement("h(1)")).kind,
+            CompileErrorKind::UnknownFunction("h")
+        );
+
+        compiler.reset();
+        assert_eq!(
+            expect_compile_error(compiler.compile_statement("f(2)")).kind,
+            CompileErrorKind::UnknownFunction("f")
+        );
+    }
+
+    #[test]
+    fn compiler_keeps_options() {
+        let mut ctx = Context::new();
+        ctx.allow_unknown_functions = true;
+        let mut compiler = Compiler::with_context(ctx);
+        compiler.reset();
+        assert_eq!(
+            compiler.compile_statement("custom(1)").unwrap(),
+            Some("\\operatorname{custom}\\left(1\\right)".to_string())
+        );
+    }
+
+    #[test]
+    fn minified() {
+        let src = "f(a) = ((a + (2 * 3))) * (4)\ng() = 1\nb = g()";
+        let normal = compile_source(src).unwrap();
+        let minified = compile_source_minified(src).unwrap();
+        assert_eq!(
+            normal,
+            "f\\left(a\\right)=\\left(\\left(a+\\left(2\\cdot 3\\right)\\right)\\right)\\left(4\\right)\n\
+             g\\left(\\right)=1\n\
+             b=g\\left(\\right)"
+        );
+        assert_eq!(minified, "f\\left(a\\right)=\\left(a+6\\right)4\ng=1\nb=g");
+        assert!(minified.len() < normal.len());
+        // Desmos would read (a+1)-(b) without the parentheses the same way, but not a-(b+1)
+        assert_eq!(
+            compile_source_minified("f(a, b) = (a + 1) - (b + 1)").unwrap(),
+            "f\\left(a,b\\right)=a+1-\\left(b+1\\right)"
+        );
+        // Numeric factors that lose their parentheses still don't run together
+        assert_eq!(
+            compile_source("f(a) = (a*2)*3").unwrap(),
+            "f\\left(a\\right)=\\left(a2\\right)3"
+        );
+        assert_eq!(
+            compile_source_minified("f(a) = (a*2)*3").unwrap(),
+            "f\\left(a\\right)=a2\\cdot 3"
+        );
+        assert_eq!(
+            compile_source_minified("f(a) = (a^2)*3").unwrap(),
+            "f\\left(a\\right)=a^{2}\\cdot 3"
+        );
+        assert_eq!(
+            compile_source_minified("f(a) = a*(1.5)").unwrap(),
+            "f\\left(a\\right)=a\\cdot 1.5"
+        );
+    }
+
+    #[test]
+    fn source_map() {
+        let src = "f(a) = a + 1\nb = 2 * f(3)";
+        let (out, map) = compile_source_mapped(src).unwrap();
+        assert_eq!(out, compile_source(src).unwrap());
+        let call = "f\\left(3\\right)";
+        let start = out.find(call).unwrap();
+        for offset in start..start + call.len() {
+            let span = map.span_at(offset).unwrap();
+            // The 3 inside the call maps to itself
+            if &out[offset..offset + 1] == "3" {
+                assert_eq!(span.as_str(), "3");
+            } else {
+                assert_eq!(span.as_str(), "f(3)");
+            }
+        }
+        assert_eq!(
+            map.span_at(out.find("+").unwrap()).unwrap().as_str(),
+            "a + 1"
+        );
+        // The product is juxtaposed, so all of its output is either factor
+        assert_eq!(map.span_at(start - 1).unwrap().as_str(), "2");
+        assert_eq!(
+            map.span_at(out.find("b").unwrap()).unwrap().as_str(),
+            "b = 2 * f(3)"
+        );
+    }
+}